@@ -1,32 +1,84 @@
 use std::fmt;
 use std::str::FromStr;
 
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
+use encryption::{self, AeadEncryption, Encryption};
 use error::{ConnectionError, ConnectionResult};
 use key_exchange::{self, KeyExchange};
+use mac::{self, MacAlgorithm as Mac};
+use public_key::{self, CryptoSystem};
 
 /// Slice of implemented key exchange algorithms, ordered by preference
 pub static KEY_EXCHANGE: &[KeyExchangeAlgorithm] =
     &[
     KeyExchangeAlgorithm::CURVE25519_SHA256,
+    KeyExchangeAlgorithm::CURVE25519_SHA256_LIBSSH,
 //  KeyExchangeAlgorithm::DH_GROUP_EXCHANGE_SHA1,
 ];
 
 /// Slice of implemented host key algorithms, ordered by preference
 pub static HOST_KEY: &[PublicKeyAlgorithm] = &[
     PublicKeyAlgorithm::SSH_ED25519,
-//  PublicKeyAlgorithm::SSH_RSA,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP256,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP384,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP521,
+    PublicKeyAlgorithm::RSA_SHA2_512,
+    PublicKeyAlgorithm::RSA_SHA2_256,
+    PublicKeyAlgorithm::SSH_RSA,
+];
+
+/// Slice of host certificate algorithms, which are only offered when the
+/// server has a host certificate, ordered by preference
+pub static HOST_CERTIFICATE: &[PublicKeyAlgorithm] = &[
+    PublicKeyAlgorithm::SSH_ED25519_CERT,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP256_CERT,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP384_CERT,
+    PublicKeyAlgorithm::ECDSA_SHA2_NISTP521_CERT,
+    PublicKeyAlgorithm::RSA_SHA2_512_CERT,
+    PublicKeyAlgorithm::RSA_SHA2_256_CERT,
+    PublicKeyAlgorithm::SSH_RSA_CERT,
 ];
 
 /// Slice of implemented encryption algorithms, ordered by preference
-pub static ENCRYPTION: &[EncryptionAlgorithm] =
-    &[EncryptionAlgorithm::AES256_CTR];
+pub static ENCRYPTION: &[EncryptionAlgorithm] = &[
+    EncryptionAlgorithm::CHACHA20_POLY1305,
+    EncryptionAlgorithm::AES256_GCM,
+    EncryptionAlgorithm::AES128_GCM,
+    EncryptionAlgorithm::AES256_CTR,
+    EncryptionAlgorithm::AES192_CTR,
+    EncryptionAlgorithm::AES128_CTR,
+];
+
+/// Slice of legacy CBC mode encryption algorithms, which are only offered
+/// when enabled in the server config, ordered by preference
+pub static LEGACY_ENCRYPTION: &[EncryptionAlgorithm] = &[
+    EncryptionAlgorithm::AES256_CBC,
+    EncryptionAlgorithm::AES192_CBC,
+    EncryptionAlgorithm::AES128_CBC,
+    EncryptionAlgorithm::TRIPLE_DES_CBC,
+];
 
 /// Slice of implemented MAC algorithms, ordered by preference
-pub static MAC: &[MacAlgorithm] = &[MacAlgorithm::HMAC_SHA2_256];
+pub static MAC: &[MacAlgorithm] = &[
+    MacAlgorithm::UMAC_64_ETM,
+    MacAlgorithm::UMAC_128_ETM,
+    MacAlgorithm::HMAC_SHA2_256_ETM,
+    MacAlgorithm::HMAC_SHA2_512_ETM,
+    MacAlgorithm::HMAC_SHA1_ETM,
+    MacAlgorithm::UMAC_64,
+    MacAlgorithm::UMAC_128,
+    MacAlgorithm::HMAC_SHA2_256,
+    MacAlgorithm::HMAC_SHA2_512,
+    MacAlgorithm::HMAC_SHA1,
+];
 
 /// Slice of implemented compression algorithms, ordered by preference
-pub static COMPRESSION: &[CompressionAlgorithm] =
-    &[CompressionAlgorithm::None, CompressionAlgorithm::Zlib];
+pub static COMPRESSION: &[CompressionAlgorithm] = &[
+    CompressionAlgorithm::None,
+    CompressionAlgorithm::ZlibOpenSsh,
+    CompressionAlgorithm::Zlib,
+];
 
 /// Find the best matching algorithm
 pub fn negotiate<A: PartialEq + Copy>(server: &[A], client: &[A])
@@ -44,6 +96,7 @@ pub fn negotiate<A: PartialEq + Copy>(server: &[A], client: &[A])
 #[allow(non_camel_case_types)]
 pub enum KeyExchangeAlgorithm {
     CURVE25519_SHA256,
+    CURVE25519_SHA256_LIBSSH,
     ECDH_SHA2_NISTP256,
     ECDH_SHA2_NISTP384,
     ECDH_SHA2_NISTP521,
@@ -54,6 +107,8 @@ pub enum KeyExchangeAlgorithm {
     DH_GROUP14_SHA256,
     DH_GROUP14_SHA1,
     EXT_INFO_C,
+    KEX_STRICT_C,
+    KEX_STRICT_S,
 }
 
 impl KeyExchangeAlgorithm {
@@ -61,7 +116,7 @@ impl KeyExchangeAlgorithm {
         use self::KeyExchangeAlgorithm::*;
         match self
         {
-            &CURVE25519_SHA256 => Some(
+            &CURVE25519_SHA256 | &CURVE25519_SHA256_LIBSSH => Some(
                 Box::new(key_exchange::Curve25519::new()),
             ),
             _ => None,
@@ -76,6 +131,7 @@ impl FromStr for KeyExchangeAlgorithm {
         match s
         {
             "curve25519-sha256" => Ok(CURVE25519_SHA256),
+            "curve25519-sha256@libssh.org" => Ok(CURVE25519_SHA256_LIBSSH),
             "ecdh-sha2-nistp256" => Ok(ECDH_SHA2_NISTP256),
             "ecdh-sha2-nistp384" => Ok(ECDH_SHA2_NISTP384),
             "ecdh-sha2-nistp521" => Ok(ECDH_SHA2_NISTP521),
@@ -88,6 +144,8 @@ impl FromStr for KeyExchangeAlgorithm {
             "diffie-hellman-group14-sha256" => Ok(DH_GROUP14_SHA256),
             "diffie-hellman-group14-sha1" => Ok(DH_GROUP14_SHA1),
             "ext-info-c" => Ok(EXT_INFO_C),
+            "kex-strict-c-v00@openssh.com" => Ok(KEX_STRICT_C),
+            "kex-strict-s-v00@openssh.com" => Ok(KEX_STRICT_S),
             _ => {
                 debug!("Unknown kex algorithm: {}", s);
                 Err(())
@@ -102,6 +160,7 @@ impl fmt::Display for KeyExchangeAlgorithm {
         f.write_str(match self
         {
             &CURVE25519_SHA256 => "curve25519-sha256",
+            &CURVE25519_SHA256_LIBSSH => "curve25519-sha256@libssh.org",
             &ECDH_SHA2_NISTP256 => "ecdh-sha2-nistp256",
             &ECDH_SHA2_NISTP384 => "ecdh-sha2-nistp384",
             &ECDH_SHA2_NISTP521 => "ecdh-sha2-nistp521",
@@ -112,6 +171,8 @@ impl fmt::Display for KeyExchangeAlgorithm {
             &DH_GROUP14_SHA256 => "diffie-hellman-group14-sha256",
             &DH_GROUP14_SHA1 => "diffie-hellman-group14-sha1",
             &EXT_INFO_C => "ext-info-c",
+            &KEX_STRICT_C => "kex-strict-c-v00@openssh.com",
+            &KEX_STRICT_S => "kex-strict-s-v00@openssh.com",
         })
     }
 }
@@ -126,6 +187,53 @@ pub enum PublicKeyAlgorithm {
     ECDSA_SHA2_NISTP384,
     ECDSA_SHA2_NISTP521,
     SSH_ED25519,
+    SSH_RSA_CERT,
+    RSA_SHA2_256_CERT,
+    RSA_SHA2_512_CERT,
+    ECDSA_SHA2_NISTP256_CERT,
+    ECDSA_SHA2_NISTP384_CERT,
+    ECDSA_SHA2_NISTP521_CERT,
+    SSH_ED25519_CERT,
+}
+
+impl PublicKeyAlgorithm {
+    /// The algorithm of the certified key for certificate algorithms
+    pub fn certified(&self) -> Option<PublicKeyAlgorithm> {
+        use self::PublicKeyAlgorithm::*;
+        match self
+        {
+            &SSH_RSA_CERT => Some(SSH_RSA),
+            &RSA_SHA2_256_CERT => Some(RSA_SHA2_256),
+            &RSA_SHA2_512_CERT => Some(RSA_SHA2_512),
+            &ECDSA_SHA2_NISTP256_CERT => Some(ECDSA_SHA2_NISTP256),
+            &ECDSA_SHA2_NISTP384_CERT => Some(ECDSA_SHA2_NISTP384),
+            &ECDSA_SHA2_NISTP521_CERT => Some(ECDSA_SHA2_NISTP521),
+            &SSH_ED25519_CERT => Some(SSH_ED25519),
+            _ => None,
+        }
+    }
+
+    /// The crypto system of the keys used with this algorithm
+    pub fn system(&self) -> &'static CryptoSystem {
+        use self::PublicKeyAlgorithm::*;
+        match self
+        {
+            &SSH_RSA | &RSA_SHA2_256 | &RSA_SHA2_512 => &public_key::RSA,
+            &SSH_RSA_CERT | &RSA_SHA2_256_CERT | &RSA_SHA2_512_CERT => {
+                &public_key::RSA
+            }
+            &ECDSA_SHA2_NISTP256 | &ECDSA_SHA2_NISTP256_CERT => {
+                &public_key::ECDSA_NISTP256
+            }
+            &ECDSA_SHA2_NISTP384 | &ECDSA_SHA2_NISTP384_CERT => {
+                &public_key::ECDSA_NISTP384
+            }
+            &ECDSA_SHA2_NISTP521 | &ECDSA_SHA2_NISTP521_CERT => {
+                &public_key::ECDSA_NISTP521
+            }
+            &SSH_ED25519 | &SSH_ED25519_CERT => &public_key::ED25519,
+        }
+    }
 }
 
 impl FromStr for PublicKeyAlgorithm {
@@ -141,6 +249,19 @@ impl FromStr for PublicKeyAlgorithm {
             "ecdsa-sha2-nistp384" => Ok(ECDSA_SHA2_NISTP384),
             "ecdsa-sha2-nistp521" => Ok(ECDSA_SHA2_NISTP521),
             "ssh-ed25519" => Ok(SSH_ED25519),
+            "ssh-rsa-cert-v01@openssh.com" => Ok(SSH_RSA_CERT),
+            "rsa-sha2-256-cert-v01@openssh.com" => Ok(RSA_SHA2_256_CERT),
+            "rsa-sha2-512-cert-v01@openssh.com" => Ok(RSA_SHA2_512_CERT),
+            "ecdsa-sha2-nistp256-cert-v01@openssh.com" => {
+                Ok(ECDSA_SHA2_NISTP256_CERT)
+            }
+            "ecdsa-sha2-nistp384-cert-v01@openssh.com" => {
+                Ok(ECDSA_SHA2_NISTP384_CERT)
+            }
+            "ecdsa-sha2-nistp521-cert-v01@openssh.com" => {
+                Ok(ECDSA_SHA2_NISTP521_CERT)
+            }
+            "ssh-ed25519-cert-v01@openssh.com" => Ok(SSH_ED25519_CERT),
             _ => {
                 debug!("Unknown host key algorithm: {}", s);
                 Err(())
@@ -161,6 +282,19 @@ impl fmt::Display for PublicKeyAlgorithm {
             &ECDSA_SHA2_NISTP384 => "ecdsa-sha2-nistp384",
             &ECDSA_SHA2_NISTP521 => "ecdsa-sha2-nistp521",
             &SSH_ED25519 => "ssh-ed25519",
+            &SSH_RSA_CERT => "ssh-rsa-cert-v01@openssh.com",
+            &RSA_SHA2_256_CERT => "rsa-sha2-256-cert-v01@openssh.com",
+            &RSA_SHA2_512_CERT => "rsa-sha2-512-cert-v01@openssh.com",
+            &ECDSA_SHA2_NISTP256_CERT => {
+                "ecdsa-sha2-nistp256-cert-v01@openssh.com"
+            }
+            &ECDSA_SHA2_NISTP384_CERT => {
+                "ecdsa-sha2-nistp384-cert-v01@openssh.com"
+            }
+            &ECDSA_SHA2_NISTP521_CERT => {
+                "ecdsa-sha2-nistp521-cert-v01@openssh.com"
+            }
+            &SSH_ED25519_CERT => "ssh-ed25519-cert-v01@openssh.com",
         })
     }
 }
@@ -168,27 +302,102 @@ impl fmt::Display for PublicKeyAlgorithm {
 #[derive(Clone, Copy, PartialEq, Debug)]
 #[allow(non_camel_case_types)]
 pub enum EncryptionAlgorithm {
+    CHACHA20_POLY1305,
+    AES128_GCM,
+    AES256_GCM,
     AES128_CTR,
     AES128_CBC,
     AES192_CTR,
     AES192_CBC,
     AES256_CTR,
     AES256_CBC,
+    TRIPLE_DES_CBC,
     None,
 }
 
+impl EncryptionAlgorithm {
+    pub fn key_len(&self) -> usize {
+        use self::EncryptionAlgorithm::*;
+        match self
+        {
+            &AES128_CTR | &AES128_CBC | &AES128_GCM => 16,
+            &AES192_CTR | &AES192_CBC | &TRIPLE_DES_CBC => 24,
+            &AES256_CTR | &AES256_CBC | &AES256_GCM => 32,
+            &CHACHA20_POLY1305 => 64,
+            &EncryptionAlgorithm::None => 0,
+        }
+    }
+
+    pub fn iv_len(&self) -> usize {
+        use self::EncryptionAlgorithm::*;
+        match self
+        {
+            &CHACHA20_POLY1305 | &EncryptionAlgorithm::None => 0,
+            &AES128_GCM | &AES256_GCM => 12,
+            &TRIPLE_DES_CBC => 8,
+            _ => 16,
+        }
+    }
+
+    /// AEAD ciphers protect the integrity themselves, so no MAC is used
+    pub fn is_aead(&self) -> bool {
+        use self::EncryptionAlgorithm::*;
+        match self
+        {
+            &CHACHA20_POLY1305 | &AES128_GCM | &AES256_GCM => true,
+            _ => false,
+        }
+    }
+
+    pub fn aead_instance(&self, key: &[u8], iv: &[u8])
+        -> Option<Box<AeadEncryption>> {
+        use self::EncryptionAlgorithm::*;
+        match self
+        {
+            &CHACHA20_POLY1305 => {
+                Some(Box::new(encryption::ChaCha20Poly1305::new(key)))
+            }
+            &AES128_GCM | &AES256_GCM => {
+                Some(Box::new(encryption::AesGcm::new(key, iv)))
+            }
+            _ => Option::None,
+        }
+    }
+
+    pub fn instance(&self, key: &[u8], iv: &[u8]) -> Option<Box<Encryption>> {
+        use self::EncryptionAlgorithm::*;
+        match self
+        {
+            &AES128_CTR | &AES192_CTR | &AES256_CTR => {
+                Some(Box::new(encryption::AesCtr::new(key, iv)))
+            }
+            &AES128_CBC | &AES192_CBC | &AES256_CBC => {
+                Some(Box::new(encryption::Cbc::aes(key, iv)))
+            }
+            &TRIPLE_DES_CBC => {
+                Some(Box::new(encryption::Cbc::triple_des(key, iv)))
+            }
+            _ => Option::None,
+        }
+    }
+}
+
 impl FromStr for EncryptionAlgorithm {
     type Err = ();
     fn from_str(s: &str) -> Result<EncryptionAlgorithm, ()> {
         use self::EncryptionAlgorithm::*;
         match s
         {
+            "chacha20-poly1305@openssh.com" => Ok(CHACHA20_POLY1305),
+            "aes128-gcm@openssh.com" => Ok(AES128_GCM),
+            "aes256-gcm@openssh.com" => Ok(AES256_GCM),
             "aes128-ctr" => Ok(AES128_CTR),
             "aes128-cbc" => Ok(AES128_CBC),
             "aes192-ctr" => Ok(AES192_CTR),
             "aes192-cbc" => Ok(AES192_CBC),
             "aes256-ctr" => Ok(AES256_CTR),
             "aes256-cbc" => Ok(AES256_CBC),
+            "3des-cbc" => Ok(TRIPLE_DES_CBC),
             "none" => Ok(EncryptionAlgorithm::None),
             _ => {
                 debug!("Unknown encryption algorithm: `{}`", s);
@@ -203,12 +412,16 @@ impl fmt::Display for EncryptionAlgorithm {
         use self::EncryptionAlgorithm::*;
         f.write_str(match self
         {
+            &CHACHA20_POLY1305 => "chacha20-poly1305@openssh.com",
+            &AES128_GCM => "aes128-gcm@openssh.com",
+            &AES256_GCM => "aes256-gcm@openssh.com",
             &AES128_CTR => "aes128-ctr",
             &AES128_CBC => "aes128-cbc",
             &AES192_CTR => "aes192-ctr",
             &AES192_CBC => "aes192-cbc",
             &AES256_CTR => "aes256-ctr",
             &AES256_CBC => "aes256-cbc",
+            &TRIPLE_DES_CBC => "3des-cbc",
             &EncryptionAlgorithm::None => "none",
         })
     }
@@ -220,9 +433,63 @@ pub enum MacAlgorithm {
     HMAC_SHA1,
     HMAC_SHA2_256,
     HMAC_SHA2_512,
+    HMAC_SHA1_ETM,
+    HMAC_SHA2_256_ETM,
+    HMAC_SHA2_512_ETM,
+    UMAC_64,
+    UMAC_128,
+    UMAC_64_ETM,
+    UMAC_128_ETM,
     None,
 }
 
+impl MacAlgorithm {
+    pub fn key_len(&self) -> usize {
+        use self::MacAlgorithm::*;
+        match self
+        {
+            &HMAC_SHA1 | &HMAC_SHA1_ETM => 20,
+            &HMAC_SHA2_256 | &HMAC_SHA2_256_ETM => 32,
+            &HMAC_SHA2_512 | &HMAC_SHA2_512_ETM => 64,
+            &UMAC_64 | &UMAC_128 | &UMAC_64_ETM | &UMAC_128_ETM => 16,
+            &MacAlgorithm::None => 0,
+        }
+    }
+
+    /// Encrypt-then-MAC algorithms send the packet length unencrypted and
+    /// authenticate the ciphertext instead of the plaintext
+    pub fn is_etm(&self) -> bool {
+        use self::MacAlgorithm::*;
+        match self
+        {
+            &HMAC_SHA1_ETM | &HMAC_SHA2_256_ETM | &HMAC_SHA2_512_ETM |
+            &UMAC_64_ETM | &UMAC_128_ETM => true,
+            _ => false,
+        }
+    }
+
+    pub fn instance(&self, key: &[u8]) -> Option<Box<Mac>> {
+        use self::MacAlgorithm::*;
+        match self
+        {
+            &HMAC_SHA1 | &HMAC_SHA1_ETM => {
+                Some(Box::new(mac::Hmac::new(Sha1::new(), key)))
+            }
+            &HMAC_SHA2_256 | &HMAC_SHA2_256_ETM => {
+                Some(Box::new(mac::Hmac::new(Sha256::new(), key)))
+            }
+            &HMAC_SHA2_512 | &HMAC_SHA2_512_ETM => {
+                Some(Box::new(mac::Hmac::new(Sha512::new(), key)))
+            }
+            &UMAC_64 | &UMAC_64_ETM => Some(Box::new(mac::Umac::new(key, 8))),
+            &UMAC_128 | &UMAC_128_ETM => {
+                Some(Box::new(mac::Umac::new(key, 16)))
+            }
+            _ => Option::None,
+        }
+    }
+}
+
 impl FromStr for MacAlgorithm {
     type Err = ();
     fn from_str(s: &str) -> Result<MacAlgorithm, ()> {
@@ -232,6 +499,13 @@ impl FromStr for MacAlgorithm {
             "hmac-sha1" => Ok(HMAC_SHA1),
             "hmac-sha2-256" => Ok(HMAC_SHA2_256),
             "hmac-sha2-512" => Ok(HMAC_SHA2_512),
+            "hmac-sha1-etm@openssh.com" => Ok(HMAC_SHA1_ETM),
+            "hmac-sha2-256-etm@openssh.com" => Ok(HMAC_SHA2_256_ETM),
+            "hmac-sha2-512-etm@openssh.com" => Ok(HMAC_SHA2_512_ETM),
+            "umac-64@openssh.com" => Ok(UMAC_64),
+            "umac-128@openssh.com" => Ok(UMAC_128),
+            "umac-64-etm@openssh.com" => Ok(UMAC_64_ETM),
+            "umac-128-etm@openssh.com" => Ok(UMAC_128_ETM),
             "none" => Ok(MacAlgorithm::None),
             _ => {
                 debug!("Unknown mac algorithm: {}", s);
@@ -249,6 +523,13 @@ impl fmt::Display for MacAlgorithm {
             &HMAC_SHA1 => "hmac-sha1",
             &HMAC_SHA2_256 => "hmac-sha2-256",
             &HMAC_SHA2_512 => "hmac-sha2-512",
+            &HMAC_SHA1_ETM => "hmac-sha1-etm@openssh.com",
+            &HMAC_SHA2_256_ETM => "hmac-sha2-256-etm@openssh.com",
+            &HMAC_SHA2_512_ETM => "hmac-sha2-512-etm@openssh.com",
+            &UMAC_64 => "umac-64@openssh.com",
+            &UMAC_128 => "umac-128@openssh.com",
+            &UMAC_64_ETM => "umac-64-etm@openssh.com",
+            &UMAC_128_ETM => "umac-128-etm@openssh.com",
             &MacAlgorithm::None => "none",
         })
     }
@@ -257,15 +538,25 @@ impl fmt::Display for MacAlgorithm {
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum CompressionAlgorithm {
     Zlib,
+    ZlibOpenSsh,
     None,
 }
 
+impl CompressionAlgorithm {
+    /// Delayed compression only starts after user authentication, so that
+    /// unauthenticated clients can't attack the decompressor
+    pub fn is_delayed(&self) -> bool {
+        *self == CompressionAlgorithm::ZlibOpenSsh
+    }
+}
+
 impl FromStr for CompressionAlgorithm {
     type Err = ();
     fn from_str(s: &str) -> Result<CompressionAlgorithm, ()> {
         match s
         {
             "zlib" => Ok(CompressionAlgorithm::Zlib),
+            "zlib@openssh.com" => Ok(CompressionAlgorithm::ZlibOpenSsh),
             "none" => Ok(CompressionAlgorithm::None),
             _ => {
                 debug!("Unknown compression algorithm: {}", s);
@@ -280,6 +571,7 @@ impl fmt::Display for CompressionAlgorithm {
         f.write_str(match self
         {
             &CompressionAlgorithm::Zlib => "zlib",
+            &CompressionAlgorithm::ZlibOpenSsh => "zlib@openssh.com",
             &CompressionAlgorithm::None => "none",
         })
     }
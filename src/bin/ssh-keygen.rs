@@ -1,10 +1,440 @@
 extern crate ssh;
-use std::fs::File;
 
-use ssh::public_key;
+use std::env;
+use std::fs::{DirBuilder, File, OpenOptions, Permissions};
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use ssh::config::ssh::expand_home;
+use ssh::public_key::{self, CryptoSystem, FingerprintHash, KeyPair};
+
+const HOST_KEY_DIR: &str = "/etc/ssh";
+/// Options that take a value
+const VALUE_OPTIONS: &str = "bCEfNPt";
+
+fn usage() -> ! {
+    writeln!(
+        io::stderr(),
+        "usage: ssh-keygen [-t type] [-b bits] [-f file] [-C comment] \
+         [-N passphrase]
+       ssh-keygen -p [-f file] [-P old_passphrase] [-N new_passphrase]
+       ssh-keygen -y [-f file] [-P passphrase]
+       ssh-keygen -l [-v] [-E sha256|md5] [-f file]
+       ssh-keygen -A [-f prefix]"
+    ).unwrap();
+    process::exit(1);
+}
+
+fn exit(message: &str) -> ! {
+    writeln!(io::stderr(), "ssh-keygen: {}", message).unwrap();
+    process::exit(1);
+}
+
+/// Reads a passphrase from the terminal without echoing it
+fn read_passphrase(prompt: &str) -> String {
+    write!(io::stderr(), "{}", prompt).unwrap();
+
+    let echo_off = ssh::sys::set_echo(0, false).is_ok();
+    let mut passphrase = String::new();
+    let result = io::stdin().lock().read_line(&mut passphrase);
+
+    if echo_off {
+        let _ = ssh::sys::set_echo(0, true);
+        writeln!(io::stderr(), "").unwrap();
+    }
+
+    if result.is_err() {
+        exit("failed to read passphrase");
+    }
+
+    passphrase.trim_right_matches(|c| c == '\r' || c == '\n').to_string()
+}
+
+/// Asks for a new passphrase twice
+fn read_new_passphrase() -> String {
+    let passphrase =
+        read_passphrase("Enter passphrase (empty for no passphrase): ");
+    if read_passphrase("Enter same passphrase again: ") != passphrase {
+        exit("passphrases do not match");
+    }
+    passphrase
+}
+
+/// Creates a file that only the owner can read, for private keys. The mode
+/// of an existing file is changed as well.
+fn create_private(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// The key file used without -f, e.g. ~/.ssh/id_ed25519 like in OpenSSH
+fn default_path(system: &CryptoSystem) -> String {
+    let name = system.name.replace('-', "_");
+    expand_home(&format!("~/.ssh/id_{}", name))
+}
+
+/// Finds the crypto system for -t, by key type name or wire name. Names
+/// with several systems, like ECDSA, are told apart by the key size.
+fn find_system(key_type: &str, bits: Option<u32>) -> &'static CryptoSystem {
+    let system = public_key::system_by_name(key_type, bits)
+        .or_else(|| public_key::system_by_id(key_type));
+
+    let can_generate = |system: &CryptoSystem| {
+        public_key::CRYPTO_SYSTEMS.iter().any(|s| s.id == system.id)
+    };
+
+    match (system, bits)
+    {
+        (Some(system), _) if can_generate(system) => {
+            check_bits(system, bits);
+            system
+        }
+        (Some(_), _) => exit(&format!("cannot generate {} keys", key_type)),
+        // The key type is known, but not with that size
+        (None, Some(bits))
+            if public_key::system_by_name(key_type, None).is_some() =>
+        {
+            exit(&format!("invalid {} key length {}", key_type, bits))
+        }
+        (None, _) => exit(&format!("unknown key type {}", key_type)),
+    }
+}
+
+/// Rejects RSA key sizes that OpenSSH would not generate either, instead of
+/// generating another size than asked for
+fn check_bits(system: &CryptoSystem, bits: Option<u32>) {
+    let bits = match bits
+    {
+        Some(bits) if system.id == public_key::RSA.id => bits,
+        _ => return,
+    };
+
+    if bits < public_key::RSA_MIN_BITS {
+        exit(&format!(
+            "invalid RSA key length: minimum is {} bits",
+            public_key::RSA_MIN_BITS
+        ));
+    }
+    if bits > public_key::RSA_MAX_BITS {
+        exit(&format!(
+            "invalid RSA key length: maximum is {} bits",
+            public_key::RSA_MAX_BITS
+        ));
+    }
+}
+
+/// Reads a private key file, or a public key file with its comment
+fn read_key_file(path: &str, passphrase: Option<&str>)
+    -> (Box<KeyPair>, String) {
+    let mut data = Vec::new();
+    let result = File::open(path).and_then(|mut f| f.read_to_end(&mut data));
+    if let Err(err) = result {
+        exit(&format!("{}: {}", path, err));
+    }
+
+    let encrypted = match public_key::is_encrypted_private_key(&mut &data[..])
+    {
+        Ok(encrypted) => encrypted,
+        Err(_) => {
+            // Not a private key, try the public key line format
+            let line = String::from_utf8_lossy(&data);
+            let key = public_key::parse_public_key_line(&line)
+                .unwrap_or_else(|err| exit(&format!("{}: {}", path, err)));
+            let comment = line.split_whitespace()
+                .skip(2)
+                .collect::<Vec<_>>()
+                .join(" ");
+            return (key, comment);
+        }
+    };
+
+    let passphrase = match passphrase
+    {
+        Some(passphrase) => Some(passphrase.to_string()),
+        None if encrypted => {
+            Some(read_passphrase(&format!("Enter passphrase for {}: ", path)))
+        }
+        None => None,
+    };
+
+    public_key::read_private_key(
+        &mut &data[..],
+        passphrase.as_ref().map(|p| &**p),
+    ).unwrap_or_else(|err| exit(&format!("{}: {}", path, err)))
+}
+
+/// Writes a private key and the matching .pub file
+fn write_key_files(
+    keypair: &KeyPair,
+    path: &str,
+    comment: &str,
+    passphrase: &str,
+) -> io::Result<()> {
+    let mut file = create_private(path)?;
+    public_key::export_private_key(
+        keypair,
+        comment,
+        Some(passphrase),
+        &mut file,
+    )?;
+
+    let mut file = File::create(format!("{}.pub", path))?;
+    let line = public_key::format_public_key_line(keypair, comment)?;
+    writeln!(file, "{}", line)
+}
+
+fn generate(
+    system: &CryptoSystem,
+    bits: Option<u32>,
+    path: &str,
+    comment: &str,
+    passphrase: Option<String>,
+) {
+    // Like OpenSSH, create a missing ~/.ssh only readable by the owner
+    if let Some(dir) = Path::new(path).parent() {
+        if !dir.as_os_str().is_empty() && !dir.exists() {
+            if let Err(err) = DirBuilder::new().mode(0o700).create(dir) {
+                exit(&format!("{}: {}", dir.display(), err));
+            }
+            println!("Created directory '{}'.", dir.display());
+        }
+    }
+
+    if Path::new(path).exists() {
+        print!("{} already exists.\nOverwrite (y/n)? ", path);
+        io::stdout().flush().unwrap();
+
+        let mut answer = String::new();
+        let _ = io::stdin().lock().read_line(&mut answer);
+        if !answer.trim().starts_with('y') {
+            process::exit(1);
+        }
+    }
+
+    println!("Generating public/private {} key pair.", system.name);
+    let keypair = (system.generate_key_pair)(bits);
+    let passphrase = passphrase.unwrap_or_else(read_new_passphrase);
+
+    if let Err(err) = write_key_files(&*keypair, path, comment, &passphrase) {
+        exit(&format!("{}: {}", path, err));
+    }
+
+    println!("Your identification has been saved in {}", path);
+    println!("Your public key has been saved in {}.pub", path);
+    println!("The key fingerprint is:");
+    println!("{} {}", keypair.fingerprint(FingerprintHash::Sha256), comment);
+    println!("The key's randomart image is:");
+    println!("{}", keypair.randomart(FingerprintHash::Sha256));
+}
+
+/// Changes the passphrase of a private key file in place
+fn change_passphrase(
+    path: &str,
+    old_passphrase: Option<String>,
+    new_passphrase: Option<String>,
+) {
+    let (keypair, comment) =
+        read_key_file(path, old_passphrase.as_ref().map(|p| &**p));
+
+    if !keypair.has_private() {
+        exit(&format!("{} is not a private key", path));
+    }
+
+    let new_passphrase = new_passphrase.unwrap_or_else(read_new_passphrase);
+
+    let result = create_private(path).and_then(|mut f| {
+        public_key::export_private_key(
+            &*keypair,
+            &comment,
+            Some(&new_passphrase),
+            &mut f,
+        )
+    });
+
+    if let Err(err) = result {
+        exit(&format!("{}: {}", path, err));
+    }
+
+    println!("Your identification has been saved with the new passphrase.");
+}
+
+/// Prints the public key of a private key file
+fn print_public_key(path: &str, passphrase: Option<String>) {
+    let (keypair, comment) =
+        read_key_file(path, passphrase.as_ref().map(|p| &**p));
+
+    match public_key::format_public_key_line(&*keypair, &comment)
+    {
+        Ok(line) => println!("{}", line),
+        Err(err) => exit(&format!("{}: {}", path, err)),
+    }
+}
+
+/// Prints the fingerprint of a key file. For private keys, the .pub file is
+/// used if it exists, so that no passphrase is needed.
+fn print_fingerprint(path: &str, hash: FingerprintHash, visual: bool) {
+    let public_path = format!("{}.pub", path);
+    let path = if Path::new(&public_path).exists() {
+        &public_path
+    }
+    else {
+        path
+    };
+
+    let (keypair, comment) = read_key_file(path, None);
+    let comment = if comment.is_empty() {
+        "no comment"
+    }
+    else {
+        &comment
+    };
+
+    println!(
+        "{} {} {} ({})",
+        keypair.bits(),
+        keypair.fingerprint(hash),
+        comment,
+        keypair.system().name.to_uppercase()
+    );
+
+    if visual {
+        println!("{}", keypair.randomart(hash));
+    }
+}
+
+/// Generates an unencrypted host key of each type that does not exist yet.
+/// Like in OpenSSH, -f gives a prefix for the host key directory.
+fn generate_host_keys(prefix: &str) {
+    let mut generated = Vec::new();
+
+    for system in public_key::CRYPTO_SYSTEMS.iter() {
+        let name = system.name;
+        let path = format!("{}{}/ssh_host_{}_key", prefix, HOST_KEY_DIR, name);
+
+        if generated.contains(&name) || Path::new(&path).exists() {
+            continue;
+        }
+
+        let keypair = (system.generate_key_pair)(None);
+        if let Err(err) = write_key_files(&*keypair, &path, "", "") {
+            exit(&format!("{}: {}", path, err));
+        }
+
+        generated.push(name);
+    }
+
+    if !generated.is_empty() {
+        let names: Vec<String> =
+            generated.iter().map(|name| name.to_uppercase()).collect();
+        println!("ssh-keygen: generated new host keys: {}", names.join(" "));
+    }
+}
+
+/// Splits combined options like "-lv" or "-fkey" into single options and
+/// their values, like getopt
+fn split_options<I: Iterator<Item = String>>(args: I) -> Vec<String> {
+    let mut options = Vec::new();
+
+    for arg in args {
+        if !arg.starts_with('-') || arg.len() <= 2 {
+            options.push(arg);
+            continue;
+        }
+
+        for (i, c) in arg.char_indices().skip(1) {
+            options.push(format!("-{}", c));
+            if VALUE_OPTIONS.contains(c) {
+                if i + 1 < arg.len() {
+                    options.push(arg[i + 1..].to_string());
+                }
+                break;
+            }
+        }
+    }
+
+    options
+}
+
+enum Mode {
+    Generate,
+    ChangePassphrase,
+    PrintPublicKey,
+    PrintFingerprint,
+    GenerateHostKeys,
+}
 
 pub fn main() {
-    let keypair = (public_key::ED25519.generate_key_pair)(None);
-    let mut buffer = File::create("server.key").unwrap();
-    let _ = keypair.export(&mut buffer);
+    let mut mode = Mode::Generate;
+    let mut key_type = String::from("ed25519");
+    let mut bits = None;
+    let mut path = None;
+    let mut comment = String::new();
+    let mut old_passphrase = None;
+    let mut new_passphrase = None;
+    let mut hash = String::from("sha256");
+    let mut visual = false;
+
+    let mut args = split_options(env::args().skip(1)).into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+
+        match arg.as_ref()
+        {
+            "-A" => mode = Mode::GenerateHostKeys,
+            "-b" => {
+                let value = value();
+                bits = Some(u32::from_str(&value).unwrap_or_else(|_| {
+                    exit(&format!("invalid number of bits {}", value))
+                }));
+            }
+            "-C" => comment = value(),
+            "-E" => hash = value().to_lowercase(),
+            "-f" => path = Some(value()),
+            "-l" => mode = Mode::PrintFingerprint,
+            "-N" => new_passphrase = Some(value()),
+            "-P" => old_passphrase = Some(value()),
+            "-p" => mode = Mode::ChangePassphrase,
+            "-t" => key_type = value().to_lowercase(),
+            "-v" => visual = true,
+            "-y" => mode = Mode::PrintPublicKey,
+            _ => usage(),
+        }
+    }
+
+    let hash = match hash.as_ref()
+    {
+        "sha256" => FingerprintHash::Sha256,
+        "md5" => FingerprintHash::Md5,
+        _ => exit(&format!("invalid hash algorithm {}", hash)),
+    };
+
+    if let Mode::GenerateHostKeys = mode {
+        generate_host_keys(path.as_ref().map_or("", |p| &**p));
+        return;
+    }
+
+    let system = find_system(&key_type, bits);
+    let path = path.unwrap_or_else(|| default_path(system));
+
+    match mode
+    {
+        Mode::Generate => {
+            generate(system, bits, &path, &comment, new_passphrase)
+        }
+        Mode::ChangePassphrase => {
+            change_passphrase(&path, old_passphrase, new_passphrase)
+        }
+        Mode::PrintPublicKey => print_public_key(&path, old_passphrase),
+        Mode::PrintFingerprint => print_fingerprint(&path, hash, visual),
+        Mode::GenerateHostKeys => (),
+    }
 }
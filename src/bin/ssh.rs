@@ -1,5 +1,271 @@
 extern crate ssh;
+extern crate log;
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log::{LogLevelFilter, LogMetadata, LogRecord};
+
+use ssh::{Client, ClientConfig};
+use ssh::config::ssh::{self as ssh_config, HostConfig, SshConfig,
+                       expand_home};
+use ssh::known_hosts::{self, KnownHosts};
+
+struct StdErrLogger;
+
+impl log::Log for StdErrLogger {
+    fn enabled(&self, _: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            writeln!(io::stderr(), "{} - {}", record.level(), record.args())
+                .unwrap();
+        }
+    }
+}
+
+fn usage() -> ! {
+    writeln!(
+        io::stderr(),
+        "usage: ssh [-vG] [-F configfile] [-i identity_file] [-l login_name]\n\
+         \x20          [-o option] [-p port] [user@]hostname"
+    ).unwrap();
+    process::exit(255);
+}
+
+fn fail(err: &fmt::Display) -> ! {
+    writeln!(io::stderr(), "ssh: {}", err).unwrap();
+    process::exit(255);
+}
+
+/// Asks a yes/no question on the terminal
+fn prompt(question: &str) -> bool {
+    let tty = File::open("/dev/tty");
+    let mut tty = match tty
+    {
+        Ok(tty) => BufReader::new(tty),
+        Err(_) => return false,
+    };
+
+    let mut stderr = io::stderr();
+    let _ = write!(stderr, "{} ", question);
+    let _ = stderr.flush();
+
+    loop {
+        let mut answer = String::new();
+        if tty.read_line(&mut answer).unwrap_or(0) == 0 {
+            return false;
+        }
+
+        match answer.trim()
+        {
+            "yes" => return true,
+            "no" => return false,
+            _ => {
+                let _ = write!(stderr, "Please type 'yes' or 'no': ");
+                let _ = stderr.flush();
+            }
+        }
+    }
+}
+
+/// Prints the resolved options for a host, like `ssh -G`
+fn print_config(host: &str, config: &HostConfig) {
+    println!("hostname {}", config.host_name(host));
+    if let Some(ref user) = config.user {
+        println!("user {}", user);
+    }
+    println!("port {}", config.port.unwrap_or(22));
+    for file in config.identity_files.iter() {
+        println!("identityfile {}", file);
+    }
+    if let Some(ref jump) = config.proxy_jump {
+        println!("proxyjump {}", jump);
+    }
+    let forwards = config
+        .local_forwards
+        .iter()
+        .map(|forward| ("localforward", forward))
+        .chain(config.remote_forwards.iter().map(
+            |forward| ("remoteforward", forward),
+        ));
+    for (keyword, forward) in forwards {
+        let listen = match forward.bind_address
+        {
+            Some(ref address) => {
+                format!("[{}]:{}", address, forward.listen_port)
+            }
+            None => forward.listen_port.to_string(),
+        };
+        println!("{} {} [{}]:{}", keyword, listen, forward.host, forward.port);
+    }
+    println!(
+        "stricthostkeychecking {:?}",
+        config.strict_host_key_checking.unwrap_or_default()
+    );
+    for file in config.user_known_hosts_files.iter() {
+        println!("userknownhostsfile {}", file);
+    }
+}
+
+/// Fails for options that change where or how we connect but are not
+/// implemented yet, instead of silently connecting without them
+fn check_unsupported(config: &HostConfig) {
+    if config.proxy_jump.as_ref().map_or(false, |jump| jump != "none") {
+        fail(&"ProxyJump is not supported");
+    }
+    if !config.local_forwards.is_empty() || !config.remote_forwards.is_empty()
+    {
+        fail(&"LocalForward and RemoteForward are not supported");
+    }
+
+    if config.user.is_some() || !config.identity_files.is_empty() {
+        writeln!(
+            io::stderr(),
+            "ssh: user authentication is not supported, ignoring the user \
+             and identity files"
+        ).unwrap();
+    }
+}
 
 pub fn main() {
-    println!("Hello from ssh!");
+    let mut verbosity = LogLevelFilter::Warn;
+    let mut print_only = false;
+    let mut config_file = None;
+    let mut options = Vec::new();
+    let mut destination = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref()
+        {
+            "-v" => verbosity = LogLevelFilter::Info,
+            "-vv" => verbosity = LogLevelFilter::Debug,
+            "-vvv" => verbosity = LogLevelFilter::Trace,
+            "-G" => print_only = true,
+            "-F" => {
+                config_file = Some(args.next().unwrap_or_else(|| usage()));
+            }
+            "-o" => {
+                options.push(args.next().unwrap_or_else(|| usage()));
+            }
+            // Options that are shorthands for config options
+            "-i" | "-l" | "-p" => {
+                let keyword = match arg.as_ref()
+                {
+                    "-i" => "IdentityFile",
+                    "-l" => "User",
+                    _ => "Port",
+                };
+                let value = args.next().unwrap_or_else(|| usage());
+                options.push(format!("{} {}", keyword, value));
+            }
+            _ if arg.starts_with('-') || destination.is_some() => usage(),
+            _ => destination = Some(arg.clone()),
+        }
+    }
+
+    log::set_logger(|max_log_level| {
+        max_log_level.set(verbosity);
+        Box::new(StdErrLogger)
+    }).unwrap();
+
+    let destination = destination.unwrap_or_else(|| usage());
+    let (user, host) = match destination.rfind('@')
+    {
+        Some(pos) => (Some(&destination[..pos]), &destination[pos + 1..]),
+        None => (None, &*destination),
+    };
+
+    // The first value of an option is used, so the command line comes
+    // first, then the user's config and then the system wide config
+    let mut host_config = HostConfig::default();
+    let command_line = SshConfig::parse(&options.join("\n"), "command line")
+        .unwrap_or_else(|err| fail(&err));
+    command_line.apply(host, &mut host_config);
+
+    if let Some(user) = user {
+        host_config.user = Some(user.to_string());
+    }
+
+    // Only an explicitly given config file has to exist
+    let ssh_dir = PathBuf::from(expand_home("~/.ssh"));
+    let config_files = match config_file
+    {
+        Some(path) => vec![PathBuf::from(path)],
+        None => {
+            vec![
+                PathBuf::from(expand_home(&format!(
+                    "~/{}",
+                    ssh_config::USER_PATH
+                ))),
+                PathBuf::from(ssh_config::SYSTEM_PATH),
+            ].into_iter()
+                .filter(|path| path.exists())
+                .collect()
+        }
+    };
+
+    for path in config_files.iter() {
+        let include_dir = if path.starts_with(&ssh_dir) {
+            ssh_dir.as_path()
+        }
+        else {
+            Path::new("/etc/ssh")
+        };
+
+        SshConfig::read(path, include_dir)
+            .unwrap_or_else(|err| fail(&err))
+            .apply(host, &mut host_config);
+    }
+
+    if print_only {
+        print_config(host, &host_config);
+        process::exit(0);
+    }
+
+    check_unsupported(&host_config);
+
+    let mut known_hosts_files: Vec<PathBuf> = host_config
+        .user_known_hosts_files
+        .iter()
+        .filter(|file| &***file != "none")
+        .map(|file| PathBuf::from(expand_home(file)))
+        .collect();
+    if host_config.user_known_hosts_files.is_empty() {
+        let path = format!("~/{}", known_hosts::USER_PATH);
+        known_hosts_files.push(PathBuf::from(expand_home(&path)));
+    }
+
+    let mut known_hosts = KnownHosts::new();
+    for path in known_hosts_files.iter() {
+        known_hosts.read(path).unwrap_or_else(|err| fail(&err));
+    }
+    known_hosts
+        .read(Path::new(known_hosts::SYSTEM_PATH))
+        .unwrap_or_else(|err| fail(&err));
+
+    let config = ClientConfig {
+        host: host_config.host_name(host),
+        port: host_config.port.unwrap_or(22),
+        known_hosts: known_hosts,
+        known_hosts_file: known_hosts_files.first().cloned(),
+        strict_host_key_checking: host_config
+            .strict_host_key_checking
+            .unwrap_or_default(),
+        prompt: Some(prompt),
+        ..ClientConfig::default()
+    };
+
+    let client = Client::with_config(config);
+
+    if let Err(err) = client.connect() {
+        fail(&err);
+    }
 }
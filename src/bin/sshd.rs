@@ -3,14 +3,16 @@ extern crate log;
 
 use std::env;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
 use std::process;
 use std::str::FromStr;
 
 use log::{LogLevelFilter, LogMetadata, LogRecord};
 
 use ssh::{Server, ServerConfig};
-use ssh::public_key::ED25519;
+use ssh::config::sshd::{self, SshdConfig};
+use ssh::public_key::{self, Certificate, CertificateType, KeyPair};
 
 struct StdErrLogger;
 
@@ -27,26 +29,85 @@ impl log::Log for StdErrLogger {
     }
 }
 
-pub fn main() {
-    let mut verbosity = LogLevelFilter::Warn;
-    let mut foreground = false;
+/// Reads the keys of an authorized_keys file, skipping invalid lines
+fn read_authorized_keys<R: BufRead>(reader: R) -> Vec<Box<KeyPair>> {
+    reader
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match public_key::parse_public_key_line(&line)
+        {
+            Ok(key) => Some(key),
+            Err(err) => {
+                writeln!(io::stderr(), "sshd: invalid authorized key: {}", err)
+                    .unwrap();
+                None
+            }
+        })
+        .collect()
+}
 
-    let key_pair = File::open("server.key").and_then(
-        |mut f| (ED25519.import)(&mut f),
-    );
+/// Reads a host key and its certificate from the file with the -cert.pub
+/// suffix, if there is one
+fn read_host_key(path: &str) -> (Box<KeyPair>, Option<Certificate>) {
+    let key = File::open(path).and_then(|mut f| {
+        public_key::read_private_key(&mut f, None).map(|(key, _)| key)
+    });
 
-    if let Some(ref err) = key_pair.as_ref().err() {
-        writeln!(io::stderr(), "sshd: failed to open server.key: {}", err)
-            .unwrap();
-        process::exit(1);
-    }
+    let key = match key
+    {
+        Ok(key) => key,
+        Err(err) => {
+            writeln!(io::stderr(), "sshd: failed to open {}: {}", path, err)
+                .unwrap();
+            process::exit(1);
+        }
+    };
 
-    let mut config = ServerConfig {
-        host: String::from("0.0.0.0"),
-        port: 22,
-        key: key_pair.unwrap(),
+    let cert_path = format!("{}-cert.pub", path);
+    let cert = match read_host_certificate(&cert_path, &*key)
+    {
+        Ok(cert) => Some(cert),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => {
+            writeln!(io::stderr(), "sshd: {}: {}", cert_path, err).unwrap();
+            None
+        }
     };
 
+    (key, cert)
+}
+
+/// Reads a host certificate and checks that it certifies the host key
+fn read_host_certificate(path: &str, key: &KeyPair)
+    -> io::Result<Certificate> {
+    let mut line = String::new();
+    File::open(path)?.read_to_string(&mut line)?;
+    let cert = public_key::parse_certificate_line(&line)?;
+
+    let (mut cert_key, mut host_key) = (Vec::new(), Vec::new());
+    cert.key.write_public(&mut cert_key)?;
+    key.write_public(&mut host_key)?;
+
+    if cert_key != host_key || cert.cert_type != CertificateType::Host {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a certificate for the host key",
+        ));
+    }
+
+    Ok(cert)
+}
+
+pub fn main() {
+    let mut verbosity = LogLevelFilter::Warn;
+    let mut foreground = false;
+    let mut test_only = false;
+    let mut config_file = None;
+    let mut options = Vec::new();
+    let mut ports = Vec::new();
+    let mut host_key_files = Vec::new();
+
     let mut args = env::args().skip(1);
     while let Some(arg) = args.next() {
         match arg.as_ref()
@@ -54,12 +115,26 @@ pub fn main() {
             "-v" => verbosity = LogLevelFilter::Info,
             "-vv" => verbosity = LogLevelFilter::Debug,
             "-vvv" => verbosity = LogLevelFilter::Trace,
-            "-f" => foreground = true,
+            "-D" => foreground = true,
+            "-t" => test_only = true,
+            "-f" => {
+                config_file =
+                    Some(args.next().expect("sshd: no argument to -f option"));
+            }
+            "-h" => {
+                host_key_files.push(
+                    args.next().expect("sshd: no argument to -h option"),
+                );
+            }
+            "-o" => {
+                options.push(
+                    args.next().expect("sshd: no argument to -o option"),
+                );
+            }
             "-p" => {
-                config.port =
-                    u16::from_str(
-                        &args.next().expect("sshd: no argument to -p option"),
-                    ).expect("sshd: invalid port number to -p option");
+                ports.push(u16::from_str(
+                    &args.next().expect("sshd: no argument to -p option"),
+                ).expect("sshd: invalid port number to -p option"));
             }
             _ => (),
         }
@@ -70,6 +145,97 @@ pub fn main() {
         Box::new(StdErrLogger)
     }).unwrap();
 
+    // A missing config file is only an error if it was given explicitly
+    let config_file = config_file.or_else(|| {
+        Some(sshd::DEFAULT_PATH.to_string())
+            .filter(|path| Path::new(path).exists())
+    });
+
+    let path = config_file.as_ref().map(|path| &**path);
+    let mut sshd_config = match SshdConfig::read(path, &options)
+    {
+        Ok(config) => config,
+        Err(err) => {
+            writeln!(io::stderr(), "sshd: {}", err).unwrap();
+            process::exit(1);
+        }
+    };
+
+    if host_key_files.is_empty() {
+        host_key_files = sshd_config.host_keys.clone();
+    }
+    if host_key_files.is_empty() {
+        host_key_files.push(String::from("server.key"));
+    }
+
+    let mut host_keys: Vec<Box<KeyPair>> = Vec::new();
+    let mut host_certificates = Vec::new();
+
+    for path in host_key_files.iter() {
+        let (key, cert) = read_host_key(path);
+
+        if host_keys.iter().any(|k| k.system().id == key.system().id) {
+            writeln!(
+                io::stderr(),
+                "sshd: ignoring {}: another {} host key is already loaded",
+                path,
+                key.system().name
+            ).unwrap();
+            continue;
+        }
+
+        host_keys.push(key);
+        host_certificates.extend(cert);
+    }
+
+    // Ports given with -p replace the Port options
+    if !ports.is_empty() {
+        sshd_config.ports = ports;
+    }
+
+    let listeners = match sshd_config.listeners()
+    {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            writeln!(io::stderr(), "sshd: {}", err).unwrap();
+            process::exit(1);
+        }
+    };
+
+    if test_only {
+        process::exit(0);
+    }
+
+    let authorized_keys = match File::open("authorized_keys")
+    {
+        Ok(f) => read_authorized_keys(BufReader::new(f)),
+        Err(_) => Vec::new(),
+    };
+
+    let trusted_user_ca_keys = match File::open("trusted_user_ca_keys")
+    {
+        Ok(f) => read_authorized_keys(BufReader::new(f)),
+        Err(_) => Vec::new(),
+    };
+
+    let config = ServerConfig {
+        listeners: listeners,
+        host_keys: host_keys,
+        host_certificates: host_certificates,
+        login_grace_time: sshd_config.login_grace_time,
+        max_startups: sshd_config.max_startups,
+        per_source_max_startups: sshd_config.per_source_max_startups,
+        kex_algorithms: sshd_config.kex_algorithms,
+        ciphers: sshd_config.ciphers,
+        macs: sshd_config.macs,
+        authorized_keys: authorized_keys,
+        trusted_user_ca_keys: trusted_user_ca_keys,
+        subsystems: sshd_config.subsystems,
+        settings: sshd_config.settings,
+        matches: sshd_config.matches,
+        ..ServerConfig::default()
+    };
+
     if !foreground {
         use ssh::sys::fork;
         if fork() != 0 {
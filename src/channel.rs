@@ -33,6 +33,7 @@ pub enum ChannelRequest {
         modes: Vec<u8>,
     },
     Shell,
+    Exec(String),
 }
 
 impl Channel {
@@ -117,41 +118,51 @@ impl Channel {
                 self.master = Some(unsafe { File::from_raw_fd(master_fd) });
             }
             ChannelRequest::Shell => {
-                if let Some(&(_, ref tty_path)) = self.pty.as_ref() {
-                    let stdin = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(&tty_path)
-                        .unwrap()
-                        .into_raw_fd();
-
-                    let stdout = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(&tty_path)
-                        .unwrap()
-                        .into_raw_fd();
-
-                    let stderr = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(&tty_path)
-                        .unwrap()
-                        .into_raw_fd();
-
-                    process::Command::new("login")
-                        .stdin(unsafe { Stdio::from_raw_fd(stdin) })
-                        .stdout(unsafe { Stdio::from_raw_fd(stdout) })
-                        .stderr(unsafe { Stdio::from_raw_fd(stderr) })
-                        .before_exec(|| sys::before_exec())
-                        .spawn()
-                        .unwrap();
-                }
+                self.spawn(process::Command::new("login"));
+            }
+            ChannelRequest::Exec(ref command) => {
+                let mut process = process::Command::new("sh");
+                process.arg("-c").arg(command);
+                self.spawn(process);
             }
         }
         debug!("Channel Request: {:?}", request);
     }
 
+    /// Runs a process on the PTY of the channel
+    fn spawn(&mut self, mut process: process::Command) {
+        if let Some(&(_, ref tty_path)) = self.pty.as_ref() {
+            let stdin = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&tty_path)
+                .unwrap()
+                .into_raw_fd();
+
+            let stdout = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&tty_path)
+                .unwrap()
+                .into_raw_fd();
+
+            let stderr = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&tty_path)
+                .unwrap()
+                .into_raw_fd();
+
+            process
+                .stdin(unsafe { Stdio::from_raw_fd(stdin) })
+                .stdout(unsafe { Stdio::from_raw_fd(stdout) })
+                .stderr(unsafe { Stdio::from_raw_fd(stderr) })
+                .before_exec(|| sys::before_exec())
+                .spawn()
+                .unwrap();
+        }
+    }
+
     pub fn data(&mut self, data: &[u8]) -> io::Result<()> {
         if let Some(ref mut master) = self.master {
             master.write_all(data)?;
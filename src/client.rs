@@ -0,0 +1,57 @@
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use config::ssh::StrictHostKeyChecking;
+use connection::{Connection, ConnectionType, RekeyLimit};
+use error::ConnectionResult;
+use known_hosts::KnownHosts;
+
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    pub rekey_limit: RekeyLimit,
+    /// Host keys that the server host key is checked against
+    pub known_hosts: KnownHosts,
+    /// File that accepted host keys are added to
+    pub known_hosts_file: Option<PathBuf>,
+    pub strict_host_key_checking: StrictHostKeyChecking,
+    /// Asks the user a yes/no question, unset if there is no user to ask
+    pub prompt: Option<fn(&str) -> bool>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            host: "localhost".to_string(),
+            port: 22,
+            rekey_limit: RekeyLimit::default(),
+            known_hosts: KnownHosts::new(),
+            known_hosts_file: None,
+            strict_host_key_checking: StrictHostKeyChecking::default(),
+            prompt: None,
+        }
+    }
+}
+
+pub struct Client {
+    config: Arc<ClientConfig>,
+}
+
+impl Client {
+    pub fn with_config(config: ClientConfig) -> Client {
+        Client { config: Arc::new(config) }
+    }
+
+    pub fn connect(&self) -> ConnectionResult<()> {
+        let mut stream =
+            TcpStream::connect((&*self.config.host, self.config.port))?;
+
+        debug!("Connected to {}", stream.peer_addr()?);
+
+        let mut connection =
+            Connection::new(ConnectionType::Client(self.config.clone()));
+
+        connection.run(&mut stream)
+    }
+}
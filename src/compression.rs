@@ -0,0 +1,123 @@
+use std::io::{self, ErrorKind};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress,
+             FlushDecompress};
+
+use packet::MAX_PACKET_LEN;
+
+/// Compresses payloads of one direction as a single zlib stream, which is
+/// flushed after every packet, see RFC 4253, section 6.2
+pub struct Compressor {
+    deflate: Compress,
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor { deflate: Compress::new(Compression::default(), true) }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        let total_in = self.deflate.total_in();
+
+        loop {
+            let consumed = (self.deflate.total_in() - total_in) as usize;
+            self.deflate.compress_vec(
+                &data[consumed..],
+                &mut out,
+                FlushCompress::Sync,
+            )?;
+
+            let consumed = (self.deflate.total_in() - total_in) as usize;
+
+            // The flush is complete if there is output space left
+            if consumed == data.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+
+            out.reserve(1024);
+        }
+    }
+}
+
+/// Decompresses payloads of one direction from a single zlib stream
+pub struct Decompressor {
+    inflate: Decompress,
+}
+
+impl Decompressor {
+    pub fn new() -> Decompressor {
+        Decompressor { inflate: Decompress::new(true) }
+    }
+
+    /// Decompresses one payload. Payloads larger than the maximum packet
+    /// size are rejected, to protect against decompression bombs.
+    pub fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() * 4);
+        let total_in = self.inflate.total_in();
+
+        loop {
+            let consumed = (self.inflate.total_in() - total_in) as usize;
+            let written = out.len();
+
+            self.inflate.decompress_vec(
+                &data[consumed..],
+                &mut out,
+                FlushDecompress::Sync,
+            )?;
+
+            if out.len() > MAX_PACKET_LEN {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "decompressed packet too large",
+                ));
+            }
+
+            let progress = (self.inflate.total_in() - total_in) as usize;
+
+            if progress == data.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+
+            if progress == consumed && out.len() == written &&
+                out.len() < out.capacity()
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "truncated compressed packet",
+                ));
+            }
+
+            let additional = out.len().max(1024).min(MAX_PACKET_LEN + 1);
+            out.reserve(additional);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compressor, Decompressor};
+    use packet::MAX_PACKET_LEN;
+
+    #[test]
+    fn test_zlib_stream() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+
+        // Every payload is flushed, so it decompresses on its own
+        for payload in [&b"redox"[..], &[0; 40000][..], &b"redox"[..]].iter() {
+            let compressed = compressor.compress(payload).unwrap();
+            assert_eq!(decompressor.decompress(&compressed).unwrap(), *payload);
+        }
+    }
+
+    #[test]
+    fn test_zlib_bomb() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+
+        let compressed = compressor.compress(&vec![0; MAX_PACKET_LEN + 1])
+            .unwrap();
+        assert!(decompressor.decompress(&compressed).is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::io::ErrorKind::InvalidData;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub mod ssh;
+pub mod sshd;
+
+/// Maximum nesting of Include directives, like in OpenSSH
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A directive of a configuration file, with the keyword in lower case
+#[derive(Clone, Debug)]
+struct Line {
+    location: String,
+    keyword: String,
+    args: Vec<String>,
+}
+
+impl Line {
+    fn error(&self, message: &str) -> io::Error {
+        io::Error::new(InvalidData, format!("{}: {}", self.location, message))
+    }
+}
+
+/// Reads the directives of a configuration file. Include directives are
+/// replaced by the directives of the included files, relative paths are
+/// looked up in `include_dir`.
+fn read_file(path: &Path, include_dir: &Path, depth: usize)
+    -> io::Result<Vec<Line>> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+        })?;
+
+    let mut lines = Vec::new();
+    for line in parse_text(&text, &path.display().to_string())? {
+        if line.keyword != "include" {
+            lines.push(line);
+            continue;
+        }
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("Include nested too deeply"));
+        }
+
+        if line.args.is_empty() {
+            return Err(line.error("Include missing argument"));
+        }
+
+        for pattern in line.args.iter() {
+            for path in expand_include(&include_dir.join(pattern))? {
+                lines.extend(read_file(&path, include_dir, depth + 1)?);
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Splits a configuration text into directives, named after `name` in
+/// error messages
+fn parse_text(text: &str, name: &str) -> io::Result<Vec<Line>> {
+    let mut lines = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let location = format!("{} line {}", name, number + 1);
+        let words = split_line(line).map_err(|err| {
+            io::Error::new(InvalidData, format!("{}: {}", location, err))
+        })?;
+
+        let mut words = words.into_iter();
+        if let Some(keyword) = words.next() {
+            lines.push(Line {
+                location: location,
+                keyword: keyword.to_lowercase(),
+                args: words.collect(),
+            });
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Splits a line into the keyword and its arguments. The keyword may be
+/// followed by an equals sign, arguments may be quoted.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, mut rest) = line.split_at(end);
+
+    rest = rest.trim_start();
+    if rest.starts_with('=') {
+        rest = rest[1..].trim_start();
+    }
+
+    let mut words = vec![keyword.to_string()];
+    let mut chars = rest.chars().peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let quoted = match chars.peek()
+        {
+            None => break,
+            Some(&'#') => break,
+            Some(&'"') => {
+                chars.next();
+                true
+            }
+            Some(_) => false,
+        };
+
+        let mut word = String::new();
+        loop {
+            match chars.next()
+            {
+                Some('"') if quoted => break,
+                Some(c) if !quoted && c.is_whitespace() => break,
+                Some(c) => word.push(c),
+                None if quoted => return Err("unterminated quote".to_string()),
+                None => break,
+            }
+        }
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// Expands a wildcard in the file name of an Include path. Files that do
+/// not exist are skipped, like in OpenSSH.
+fn expand_include(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if !name.contains(|c| c == '*' || c == '?') {
+        let exists = path.exists();
+        return Ok(if exists { vec![path.to_path_buf()] } else { vec![] });
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir)
+    {
+        Ok(entries) => {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file() &&
+                        path.file_name().map_or(false, |file_name| {
+                            match_pattern(&name, &file_name.to_string_lossy())
+                        })
+                })
+                .collect()
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Parses a yes/no argument
+fn parse_flag(arg: &str) -> Result<bool, String> {
+    match &*arg.to_lowercase()
+    {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("Bad yes/no argument: {}", arg)),
+    }
+}
+
+fn parse_number<T: FromStr>(arg: &str) -> Result<T, String> {
+    T::from_str(arg).or(Err(format!("Bad number: {}", arg)))
+}
+
+fn parse_port(arg: &str) -> Result<u16, String> {
+    match parse_number(arg)
+    {
+        Ok(0) | Err(_) => Err(format!("Bad port number: {}", arg)),
+        Ok(port) => Ok(port),
+    }
+}
+
+/// Parses `host`, `host:port` or `[host]:port`, where the host may be an
+/// IPv6 address without brackets if there is no port
+fn parse_host_port(arg: &str) -> Result<(String, Option<u16>), String> {
+    if arg.starts_with('[') {
+        let end = arg.find(']').ok_or_else(
+            || format!("Bad address: {}", arg),
+        )?;
+        let port = match &arg[end + 1..]
+        {
+            "" => None,
+            rest if rest.starts_with(':') => Some(parse_port(&rest[1..])?),
+            _ => return Err(format!("Bad address: {}", arg)),
+        };
+        return Ok((arg[1..end].to_string(), port));
+    }
+
+    let mut parts = arg.split(':');
+    match (parts.next(), parts.next(), parts.next())
+    {
+        (Some(host), Some(port), None) => {
+            Ok((host.to_string(), Some(parse_port(port)?)))
+        }
+        _ => Ok((arg.to_string(), None)),
+    }
+}
+
+/// Parses a time interval like "90", "2m" or "1h30m", see TIME FORMATS in
+/// sshd_config(5). Plain numbers are seconds.
+fn parse_time(arg: &str) -> Result<Duration, String> {
+    let error = || format!("Bad time value: {}", arg);
+    let mut seconds = 0u64;
+    let mut number = String::new();
+
+    for c in arg.chars() {
+        if c.is_digit(10) {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase()
+        {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(error()),
+        };
+
+        let value = u64::from_str(&number).or(Err(error()))?;
+        let value = value.checked_mul(unit).ok_or_else(error)?;
+        seconds = seconds.checked_add(value).ok_or_else(error)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        let value = u64::from_str(&number).or(Err(error()))?;
+        seconds = seconds.checked_add(value).ok_or_else(error)?;
+    }
+    else if arg.is_empty() {
+        return Err(error());
+    }
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses a time interval where 0 disables the feature
+fn parse_timeout(arg: &str) -> Result<Option<Duration>, String> {
+    let time = parse_time(arg)?;
+    Ok(if time.as_secs() == 0 { None } else { Some(time) })
+}
+
+/// Matches a string against a pattern with the wildcards `*` and `?`
+pub fn match_pattern(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // Position after the last `*` in the pattern and the string, to
+    // backtrack to when the rest does not match
+    let (mut p, mut i) = (0, 0);
+    let mut star = None;
+
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, i));
+        }
+        else if let Some((star_p, star_i)) = star {
+            p = star_p;
+            i = star_i + 1;
+            star = Some((star_p, i));
+        }
+        else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches a string against a comma separated list of patterns. A match
+/// of a pattern negated with `!` fails the whole list.
+pub fn match_pattern_list(list: &str, s: &str) -> bool {
+    match_list(list, |pattern| match_pattern(pattern, s))
+}
+
+/// Matches an address against a comma separated list of address patterns
+/// and CIDR networks, which may be negated with `!`
+pub fn match_address_list(list: &str, address: IpAddr) -> bool {
+    let text = address.to_string();
+    match_list(list, |pattern| if pattern.contains('/') {
+        address_matches(address, pattern)
+    }
+    else {
+        match_pattern(pattern, &text)
+    })
+}
+
+fn match_list<F: Fn(&str) -> bool>(list: &str, matches: F) -> bool {
+    let mut matched = false;
+
+    for pattern in list.split(',').map(str::trim) {
+        if pattern.starts_with('!') {
+            if matches(&pattern[1..]) {
+                return false;
+            }
+        }
+        else if matches(pattern) {
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+/// Matches an address against an address or a CIDR network
+pub fn address_matches(address: IpAddr, network: &str) -> bool {
+    let mut parts = network.trim().splitn(2, '/');
+    let network = match parts.next().and_then(|n| IpAddr::from_str(n).ok())
+    {
+        Some(network) => network,
+        None => return false,
+    };
+
+    let (address, network, max_len) = match (address, network)
+    {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            (u32::from(a) as u128, u32::from(n) as u128, 32)
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => (u128::from(a), u128::from(n), 128),
+        _ => return false,
+    };
+
+    let prefix_len = match parts.next()
+    {
+        Some(len) => {
+            match u32::from_str(len)
+            {
+                Ok(len) if len <= max_len => len,
+                _ => return false,
+            }
+        }
+        None => max_len,
+    };
+
+    if prefix_len == 0 {
+        return true;
+    }
+
+    let shift = max_len - prefix_len;
+    (address >> shift) == (network >> shift)
+}
+
+/// Parses a comma separated list of algorithms, which may contain
+/// wildcards. A list starting with `+` is appended to the defaults, one
+/// starting with `-` is removed from them and one starting with `^` is
+/// moved to their front.
+fn parse_algorithms<A>(arg: &str, defaults: &[A], supported: &[A])
+    -> Result<Vec<A>, String>
+where
+    A: Copy + PartialEq + ToString,
+{
+    let error = || format!("Bad SSH2 algorithm spec '{}'", arg);
+    let (prefix, list) = match arg.chars().next()
+    {
+        Some(c) if c == '+' || c == '-' || c == '^' => (Some(c), &arg[1..]),
+        _ => (None, arg),
+    };
+
+    let patterns: Vec<&str> = list.split(',').map(str::trim).collect();
+    let listed = |algorithm: &A| {
+        let name = algorithm.to_string();
+        patterns.iter().any(|pattern| match_pattern(pattern, &name))
+    };
+
+    let mut algorithms: Vec<A> = Vec::new();
+    if prefix != Some('-') {
+        for pattern in patterns.iter() {
+            let mut found = false;
+            for algorithm in supported.iter() {
+                if match_pattern(pattern, &algorithm.to_string()) {
+                    found = true;
+                    if !algorithms.contains(algorithm) {
+                        algorithms.push(*algorithm);
+                    }
+                }
+            }
+
+            if !found {
+                return Err(error());
+            }
+        }
+    }
+
+    match prefix
+    {
+        Some('+') => {
+            let mut all = defaults.to_vec();
+            all.extend(
+                algorithms.into_iter().filter(|a| !defaults.contains(a)),
+            );
+            algorithms = all;
+        }
+        Some('-') => {
+            algorithms =
+                defaults.iter().cloned().filter(|a| !listed(a)).collect();
+        }
+        Some('^') => {
+            let rest: Vec<A> = defaults
+                .iter()
+                .cloned()
+                .filter(|a| !algorithms.contains(a))
+                .collect();
+            algorithms.extend(rest);
+        }
+        _ => {}
+    }
+
+    if algorithms.is_empty() {
+        return Err(error());
+    }
+
+    Ok(algorithms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_line() {
+        assert_eq!(split_line("  # comment").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            split_line("Banner=\"/etc/my banner\" # comment").unwrap(),
+            vec!["Banner", "/etc/my banner"]
+        );
+        assert_eq!(
+            split_line("AllowUsers  alice bob@10.0.0.0/8").unwrap(),
+            vec!["AllowUsers", "alice", "bob@10.0.0.0/8"]
+        );
+        assert!(split_line("Banner \"/etc/banner").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_time("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_time("2M"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_timeout("0"), Ok(None));
+        assert!(parse_time("").is_err());
+        assert!(parse_time("5x").is_err());
+        assert!(parse_time("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn test_match_pattern_list() {
+        assert!(match_pattern("*.example.com", "www.example.com"));
+        assert!(match_pattern("us?r*", "user1"));
+        assert!(!match_pattern("a*b", "abc"));
+        assert!(match_pattern_list("alice,b*", "bob"));
+        assert!(!match_pattern_list("*,!root", "root"));
+        assert!(!match_pattern_list("!root", "alice"));
+
+        let address = "192.168.1.7".parse().unwrap();
+        assert!(match_address_list("10.0.0.0/8,192.168.1.*", address));
+        assert!(!match_address_list("192.168.0.0/16,!192.168.1.7", address));
+    }
+}
@@ -0,0 +1,380 @@
+use std::env;
+use std::io;
+use std::path::Path;
+
+use super::{Line, match_pattern_list, parse_host_port, parse_port,
+            parse_text, read_file};
+
+/// Location of the system wide client config file
+pub const SYSTEM_PATH: &str = "/etc/ssh/ssh_config";
+
+/// Location of the user's client config file, relative to the home
+/// directory
+pub const USER_PATH: &str = ".ssh/config";
+
+/// Keywords that may be given several times and add to a list, instead of
+/// the first value being used
+const LISTS: &[&str] = &[
+    "identityfile",
+    "localforward",
+    "remoteforward",
+    "userknownhostsfile",
+];
+
+/// Keywords of OpenSSH options that are not implemented, but common in
+/// existing configs
+const IGNORED: &[&str] = &[
+    "addkeystoagent",
+    "checkhostip",
+    "compression",
+    "controlmaster",
+    "controlpath",
+    "controlpersist",
+    "forwardagent",
+    "forwardx11",
+    "gssapiauthentication",
+    "gssapidelegatecredentials",
+    "hashknownhosts",
+    "identitiesonly",
+    "sendenv",
+    "serveralivecountmax",
+    "serveraliveinterval",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StrictHostKeyChecking {
+    /// Only connect to hosts whose key is known
+    Yes,
+    /// Add keys of unknown hosts, but refuse changed keys
+    AcceptNew,
+    /// Add keys of unknown hosts and connect despite changed keys
+    No,
+    /// Ask the user whether to add keys of unknown hosts
+    Ask,
+}
+
+impl Default for StrictHostKeyChecking {
+    fn default() -> StrictHostKeyChecking {
+        StrictHostKeyChecking::Ask
+    }
+}
+
+/// A port forwarding, e.g. `LocalForward localhost:8080 example.com:80`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Forward {
+    pub bind_address: Option<String>,
+    pub listen_port: u16,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Forward {
+    fn parse(listen: &str, target: &str) -> Result<Forward, String> {
+        let (bind_address, listen_port) =
+            if listen.chars().all(|c| c.is_digit(10)) {
+                (None, parse_port(listen)?)
+            }
+            else {
+                match parse_host_port(listen)?
+                {
+                    (address, Some(port)) => (Some(address), port),
+                    (_, None) => {
+                        return Err(format!("Bad forwarding port: {}", listen))
+                    }
+                }
+            };
+
+        match parse_host_port(target)?
+        {
+            (host, Some(port)) => Ok(Forward {
+                bind_address: bind_address,
+                listen_port: listen_port,
+                host: host,
+                port: port,
+            }),
+            (_, None) => Err(format!("Bad forwarding target: {}", target)),
+        }
+    }
+}
+
+/// The client options for a host, see ssh_config(5)
+#[derive(Clone, Debug, Default)]
+pub struct HostConfig {
+    /// The real host name to connect to, where `%h` is the given name
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    /// Jump hosts as `[user@]host[:port]`, separated by commas
+    pub proxy_jump: Option<String>,
+    pub local_forwards: Vec<Forward>,
+    pub remote_forwards: Vec<Forward>,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    pub user_known_hosts_files: Vec<String>,
+}
+
+impl HostConfig {
+    /// The host name to connect to for the name given by the user
+    pub fn host_name(&self, host: &str) -> String {
+        self.host_name.as_ref().map_or(
+            host.to_string(),
+            |name| name.replace("%h", host),
+        )
+    }
+
+    /// Sets an option unless it was set before. Lists are extended instead.
+    fn set(&mut self, keyword: &str, args: &[String]) -> Result<(), String> {
+        let arg = &*args[0];
+        match keyword
+        {
+            "hostname" => {
+                self.host_name = self.host_name.take().or(Some(arg.to_string()))
+            }
+            "user" => self.user = self.user.take().or(Some(arg.to_string())),
+            "port" => {
+                let port = parse_port(arg)?;
+                self.port = self.port.or(Some(port));
+            }
+            "identityfile" => self.identity_files.push(arg.to_string()),
+            "proxyjump" => {
+                self.proxy_jump = self.proxy_jump.take().or(
+                    Some(arg.to_string()),
+                )
+            }
+            "localforward" | "remoteforward" => {
+                let target = args.get(1).ok_or("missing forwarding target")?;
+                let forward = Forward::parse(arg, target)?;
+                if keyword == "localforward" {
+                    self.local_forwards.push(forward);
+                }
+                else {
+                    self.remote_forwards.push(forward);
+                }
+            }
+            "stricthostkeychecking" => {
+                let checking = match &*arg.to_lowercase()
+                {
+                    "yes" => StrictHostKeyChecking::Yes,
+                    "accept-new" => StrictHostKeyChecking::AcceptNew,
+                    "no" | "off" => StrictHostKeyChecking::No,
+                    "ask" => StrictHostKeyChecking::Ask,
+                    _ => {
+                        return Err(format!(
+                            "Bad StrictHostKeyChecking argument: {}",
+                            arg
+                        ))
+                    }
+                };
+                self.strict_host_key_checking =
+                    self.strict_host_key_checking.or(Some(checking));
+            }
+            "userknownhostsfile" => {
+                if self.user_known_hosts_files.is_empty() {
+                    self.user_known_hosts_files = args.to_vec();
+                }
+            }
+            _ => return Err(format!("Bad configuration option: {}", keyword)),
+        }
+
+        Ok(())
+    }
+}
+
+/// A Host block: options for hosts that match one of its patterns
+#[derive(Clone, Debug)]
+struct Block {
+    patterns: Option<String>,
+    options: Vec<(String, Vec<String>)>,
+}
+
+/// The contents of an ssh_config file
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+impl SshConfig {
+    /// Reads a config file, where relative paths of included files are
+    /// looked up in `include_dir`
+    pub fn read(path: &Path, include_dir: &Path) -> io::Result<SshConfig> {
+        SshConfig::from_lines(read_file(path, include_dir, 0)?)
+    }
+
+    /// Parses the text of a config file, or of options given on the
+    /// command line
+    pub fn parse(text: &str, name: &str) -> io::Result<SshConfig> {
+        SshConfig::from_lines(parse_text(text, name)?)
+    }
+
+    fn from_lines(lines: Vec<Line>) -> io::Result<SshConfig> {
+        // Options before the first Host line apply to all hosts
+        let mut blocks = vec![
+            Block {
+                patterns: Some("*".to_string()),
+                options: Vec::new(),
+            },
+        ];
+
+        for line in lines {
+            let keyword = &*line.keyword;
+
+            match keyword
+            {
+                "host" => {
+                    if line.args.is_empty() {
+                        return Err(line.error("missing argument"));
+                    }
+                    blocks.push(Block {
+                        patterns: Some(line.args.join(",")),
+                        options: Vec::new(),
+                    });
+                    continue;
+                }
+                "match" => {
+                    warn!(
+                        "{}: ignoring unsupported Match block",
+                        line.location
+                    );
+                    blocks.push(Block {
+                        patterns: None,
+                        options: Vec::new(),
+                    });
+                    continue;
+                }
+                _ if IGNORED.contains(&keyword) => {
+                    debug!("{}: ignoring unsupported option {}", line.location,
+                        keyword);
+                    continue;
+                }
+                _ => {}
+            }
+
+            if line.args.is_empty() {
+                return Err(line.error("missing argument"));
+            }
+            if !LISTS.contains(&keyword) && line.args.len() > 1 &&
+                keyword != "localforward" &&
+                keyword != "remoteforward"
+            {
+                return Err(line.error("garbage at end of line"));
+            }
+
+            HostConfig::default().set(keyword, &line.args).map_err(
+                |err| line.error(&err),
+            )?;
+
+            if let Some(block) = blocks.last_mut() {
+                block.options.push((keyword.to_string(), line.args.clone()));
+            }
+        }
+
+        Ok(SshConfig { blocks: blocks })
+    }
+
+    /// Adds the options for a host to a config. Options that are already
+    /// set are kept, so earlier files take precedence.
+    pub fn apply(&self, host: &str, config: &mut HostConfig) {
+        let blocks = self.blocks.iter().filter(|block| {
+            block.patterns.as_ref().map_or(
+                false,
+                |patterns| match_pattern_list(patterns, host),
+            )
+        });
+
+        for block in blocks {
+            for &(ref keyword, ref args) in block.options.iter() {
+                // The options were validated when parsing
+                let _ = config.set(keyword, args);
+            }
+        }
+    }
+}
+
+/// Replaces a leading `~` in a path with the home directory
+pub fn expand_home(path: &str) -> String {
+    match env::var("HOME")
+    {
+        Ok(ref home) if path == "~" || path.starts_with("~/") => {
+            format!("{}{}", home, &path[1..])
+        }
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+User everyone
+
+Host jump
+    HostName jump.example.com
+
+Host *.example.com !secret.example.com
+    Port 2222
+    IdentityFile ~/.ssh/id_work
+    ProxyJump admin@jump:22
+    LocalForward 8080 localhost:80
+    RemoteForward [::1]:9000 [::1]:9001
+    StrictHostKeyChecking accept-new
+
+Host *
+    IdentityFile ~/.ssh/id_ed25519
+    Port 22
+    User nobody
+    ControlMaster auto
+";
+
+    #[test]
+    fn test_host_config() {
+        let ssh_config = SshConfig::parse(CONFIG, "config").unwrap();
+
+        let mut config = HostConfig::default();
+        ssh_config.apply("www.example.com", &mut config);
+        assert_eq!(config.user, Some("everyone".to_string()));
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(
+            config.identity_files,
+            vec!["~/.ssh/id_work", "~/.ssh/id_ed25519"]
+        );
+        assert_eq!(config.proxy_jump, Some("admin@jump:22".to_string()));
+        assert_eq!(
+            config.local_forwards,
+            vec![Forward {
+                bind_address: None,
+                listen_port: 8080,
+                host: "localhost".to_string(),
+                port: 80,
+            }]
+        );
+        assert_eq!(
+            config.remote_forwards[0].bind_address,
+            Some("::1".to_string())
+        );
+        assert_eq!(
+            config.strict_host_key_checking,
+            Some(StrictHostKeyChecking::AcceptNew)
+        );
+
+        let mut config = HostConfig::default();
+        ssh_config.apply("secret.example.com", &mut config);
+        assert_eq!(config.port, Some(22));
+        assert_eq!(config.host_name("secret"), "secret");
+
+        let mut config = HostConfig::default();
+        ssh_config.apply("jump", &mut config);
+        assert_eq!(config.host_name("jump"), "jump.example.com");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(SshConfig::parse("Port x", "config").is_err());
+        assert!(SshConfig::parse("Host", "config").is_err());
+        assert!(SshConfig::parse("LocalForward 8080", "config").is_err());
+        assert!(SshConfig::parse("Frobnicate yes", "config").is_err());
+        assert!(
+            SshConfig::parse("StrictHostKeyChecking maybe", "config").is_err()
+        );
+    }
+}
@@ -0,0 +1,802 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use algorithm::{ENCRYPTION, EncryptionAlgorithm, KEY_EXCHANGE,
+                KeyExchangeAlgorithm, LEGACY_ENCRYPTION, MAC, MacAlgorithm};
+use server::Listener;
+use super::{Line, match_address_list, match_pattern, match_pattern_list,
+            parse_algorithms, parse_flag, parse_host_port, parse_number,
+            parse_port, parse_text, parse_timeout, read_file};
+
+/// Default location of the server config file
+pub const DEFAULT_PATH: &str = "/etc/ssh/sshd_config";
+
+/// Directory of included files with a relative path
+const INCLUDE_DIR: &str = "/etc/ssh";
+
+/// Keywords of the options that Match blocks can override
+const SETTINGS: &[&str] = &[
+    "allowusers",
+    "banner",
+    "clientalivecountmax",
+    "clientaliveinterval",
+    "denyusers",
+    "maxauthtries",
+    "maxsessions",
+    "passwordauthentication",
+    "permitrootlogin",
+    "pubkeyauthentication",
+];
+
+/// Keywords that may be given several times and add to a list, instead of
+/// the first value being used
+const LISTS: &[&str] = &[
+    "allowusers",
+    "denyusers",
+    "hostkey",
+    "listenaddress",
+    "port",
+    "subsystem",
+];
+
+/// Keywords of OpenSSH options that are not implemented, but common in
+/// existing configs
+const IGNORED: &[&str] = &[
+    "acceptenv",
+    "challengeresponseauthentication",
+    "kbdinteractiveauthentication",
+    "printlastlog",
+    "printmotd",
+    "tcpkeepalive",
+    "usedns",
+    "usepam",
+    "x11forwarding",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PermitRootLogin {
+    Yes,
+    ProhibitPassword,
+    ForcedCommandsOnly,
+    No,
+}
+
+impl PermitRootLogin {
+    /// Whether root may log in with a password
+    pub fn permits_password(&self) -> bool {
+        *self == PermitRootLogin::Yes
+    }
+
+    /// Whether root may log in with a key, which may have a forced command
+    pub fn permits_key(&self, forced_command: bool) -> bool {
+        match *self
+        {
+            PermitRootLogin::Yes |
+            PermitRootLogin::ProhibitPassword => true,
+            PermitRootLogin::ForcedCommandsOnly => forced_command,
+            PermitRootLogin::No => false,
+        }
+    }
+}
+
+/// The address families to listen on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressFamily {
+    Any,
+    Inet,
+    Inet6,
+}
+
+impl AddressFamily {
+    fn allows(&self, address: &SocketAddr) -> bool {
+        match *self
+        {
+            AddressFamily::Any => true,
+            AddressFamily::Inet => address.is_ipv4(),
+            AddressFamily::Inet6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Limits of unauthenticated connections, as `start:rate:full`. From
+/// `start` connections on, new ones are dropped with a probability of
+/// `rate` percent, which rises linearly to 100 percent at `full`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaxStartups {
+    pub start: usize,
+    pub rate: u32,
+    pub full: usize,
+}
+
+impl Default for MaxStartups {
+    fn default() -> MaxStartups {
+        MaxStartups {
+            start: 10,
+            rate: 30,
+            full: 100,
+        }
+    }
+}
+
+impl MaxStartups {
+    fn parse(arg: &str) -> Result<MaxStartups, String> {
+        let error = || format!("Bad MaxStartups spec: {}", arg);
+        let numbers: Vec<usize> = arg.split(':')
+            .map(|n| parse_number(n).map_err(|_| error()))
+            .collect::<Result<_, _>>()?;
+
+        let max_startups = match numbers.len()
+        {
+            1 => MaxStartups {
+                start: numbers[0],
+                rate: 100,
+                full: numbers[0],
+            },
+            3 => MaxStartups {
+                start: numbers[0],
+                rate: numbers[1] as u32,
+                full: numbers[2],
+            },
+            _ => return Err(error()),
+        };
+
+        if max_startups.start > max_startups.full || max_startups.rate > 100 {
+            return Err(error());
+        }
+        Ok(max_startups)
+    }
+
+    /// The percentage of new connections to drop with a number of
+    /// unauthenticated connections
+    pub fn drop_rate(&self, unauthenticated: usize) -> u32 {
+        if unauthenticated < self.start {
+            0
+        }
+        else if unauthenticated >= self.full {
+            100
+        }
+        else {
+            let excess = (unauthenticated - self.start) as u32;
+            let range = (self.full - self.start) as u32;
+            self.rate + (100 - self.rate) * excess / range
+        }
+    }
+}
+
+/// Options that Match blocks can override
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub password_authentication: bool,
+    pub pubkey_authentication: bool,
+    pub permit_root_login: PermitRootLogin,
+    /// User patterns, optionally with a host as in `user@10.0.0.0/8`
+    pub allow_users: Vec<String>,
+    pub deny_users: Vec<String>,
+    /// Failed authentication attempts before the connection is closed
+    pub max_auth_tries: u32,
+    /// Session channels that a connection may open
+    pub max_sessions: u32,
+    /// File that is sent to clients before authentication
+    pub banner: Option<String>,
+    /// Idle time after which the client is asked for a reply
+    pub client_alive_interval: Option<Duration>,
+    /// Unanswered requests after which the connection is closed
+    pub client_alive_count_max: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            password_authentication: true,
+            pubkey_authentication: true,
+            permit_root_login: PermitRootLogin::ProhibitPassword,
+            allow_users: Vec::new(),
+            deny_users: Vec::new(),
+            max_auth_tries: 6,
+            max_sessions: 10,
+            banner: None,
+            client_alive_interval: None,
+            client_alive_count_max: 3,
+        }
+    }
+}
+
+impl Settings {
+    /// Whether AllowUsers and DenyUsers let a user log in from an address
+    pub fn allows_user(&self, user: &str, address: Option<IpAddr>) -> bool {
+        let matches = |entry: &String| {
+            let mut parts = entry.splitn(2, '@');
+            let user_pattern = parts.next().unwrap_or("");
+            match_pattern(user_pattern, user) &&
+                parts.next().map_or(true, |host| {
+                    address.map_or(false, |a| match_address_list(host, a))
+                })
+        };
+
+        if self.deny_users.iter().any(&matches) {
+            return false;
+        }
+
+        self.allow_users.is_empty() || self.allow_users.iter().any(&matches)
+    }
+
+    /// The settings with the options of matching Match blocks applied. An
+    /// option is taken from the first block that sets it.
+    pub fn matched(&self, matches: &[Match], context: &MatchContext)
+        -> Settings {
+        let mut settings = self.clone();
+        let mut seen: Vec<&str> = Vec::new();
+
+        for block in matches.iter().filter(|block| block.matches(context)) {
+            let mut applied: Vec<&str> = Vec::new();
+
+            for &(ref keyword, ref args) in block.options.iter() {
+                let first = !applied.contains(&&**keyword);
+                if seen.contains(&&**keyword) ||
+                    (!first && !LISTS.contains(&&**keyword))
+                {
+                    continue;
+                }
+
+                // The options were validated when parsing
+                let _ = settings.set(keyword, args, first);
+                applied.push(keyword);
+            }
+
+            seen.extend(applied);
+        }
+
+        settings
+    }
+
+    /// Sets an option, the first value of a list replaces the current one
+    fn set(&mut self, keyword: &str, args: &[String], first: bool)
+        -> Result<(), String> {
+        let arg = &*args[0];
+        match keyword
+        {
+            "allowusers" => {
+                if first {
+                    self.allow_users.clear();
+                }
+                self.allow_users.extend(args.iter().cloned());
+            }
+            "banner" => {
+                self.banner = if arg == "none" {
+                    None
+                }
+                else {
+                    Some(arg.to_string())
+                };
+            }
+            "clientalivecountmax" => {
+                self.client_alive_count_max = parse_number(arg)?;
+            }
+            "clientaliveinterval" => {
+                self.client_alive_interval = parse_timeout(arg)?;
+            }
+            "denyusers" => {
+                if first {
+                    self.deny_users.clear();
+                }
+                self.deny_users.extend(args.iter().cloned());
+            }
+            "maxauthtries" => self.max_auth_tries = parse_number(arg)?,
+            "maxsessions" => self.max_sessions = parse_number(arg)?,
+            "passwordauthentication" => {
+                self.password_authentication = parse_flag(arg)?;
+            }
+            "permitrootlogin" => {
+                self.permit_root_login = match arg
+                {
+                    "yes" => PermitRootLogin::Yes,
+                    "prohibit-password" |
+                    "without-password" => PermitRootLogin::ProhibitPassword,
+                    "forced-commands-only" => {
+                        PermitRootLogin::ForcedCommandsOnly
+                    }
+                    "no" => PermitRootLogin::No,
+                    _ => {
+                        return Err(
+                            format!("Bad PermitRootLogin argument: {}", arg),
+                        )
+                    }
+                };
+            }
+            "pubkeyauthentication" => {
+                self.pubkey_authentication = parse_flag(arg)?;
+            }
+            _ => return Err(format!("Bad configuration option: {}", keyword)),
+        }
+
+        Ok(())
+    }
+}
+
+/// What the criteria of Match blocks are checked against. Criteria for
+/// unknown values do not match, e.g. User before authentication.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MatchContext<'a> {
+    pub user: Option<&'a str>,
+    pub address: Option<IpAddr>,
+    pub local_address: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+enum Criterion {
+    All,
+    User(String),
+    Address(String),
+    LocalAddress(String),
+    LocalPort(String),
+}
+
+/// A Match block, whose options apply to connections that match all of
+/// its criteria
+#[derive(Clone, Debug)]
+pub struct Match {
+    criteria: Vec<Criterion>,
+    options: Vec<(String, Vec<String>)>,
+}
+
+impl Match {
+    fn parse(args: &[String]) -> Result<Match, String> {
+        let mut criteria = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(name) = args.next() {
+            let name = name.to_lowercase();
+            if name == "all" {
+                criteria.push(Criterion::All);
+                continue;
+            }
+
+            let value = args.next().cloned().ok_or_else(|| {
+                format!("Missing Match criteria for {}", name)
+            })?;
+
+            criteria.push(match &*name
+            {
+                "user" => Criterion::User(value),
+                "address" => Criterion::Address(value),
+                "localaddress" => Criterion::LocalAddress(value),
+                "localport" => Criterion::LocalPort(value),
+                _ => {
+                    return Err(format!("Unsupported Match attribute {}", name))
+                }
+            });
+        }
+
+        let all = criteria.iter().any(|criterion| match criterion
+        {
+            &Criterion::All => true,
+            _ => false,
+        });
+
+        if criteria.is_empty() {
+            return Err("One or more attributes required for Match".to_string());
+        }
+
+        if all && criteria.len() > 1 {
+            return Err("'all' cannot be combined with other Match attributes"
+                .to_string());
+        }
+
+        Ok(Match {
+            criteria: criteria,
+            options: Vec::new(),
+        })
+    }
+
+    pub fn matches(&self, context: &MatchContext) -> bool {
+        self.criteria.iter().all(|criterion| match criterion
+        {
+            &Criterion::All => true,
+            &Criterion::User(ref list) => {
+                context.user.map_or(false, |u| match_pattern_list(list, u))
+            }
+            &Criterion::Address(ref list) => {
+                context.address.map_or(
+                    false,
+                    |a| match_address_list(list, a),
+                )
+            }
+            &Criterion::LocalAddress(ref list) => {
+                context.local_address.map_or(false, |a| {
+                    match_address_list(list, a.ip())
+                })
+            }
+            &Criterion::LocalPort(ref list) => {
+                context.local_address.map_or(false, |a| {
+                    match_pattern_list(list, &a.port().to_string())
+                })
+            }
+        })
+    }
+}
+
+/// The server configuration from an sshd_config file, see sshd_config(5)
+#[derive(Clone, Debug)]
+pub struct SshdConfig {
+    /// Addresses to listen on, with an optional port
+    pub listen_addresses: Vec<(String, Option<u16>)>,
+    pub ports: Vec<u16>,
+    pub address_family: AddressFamily,
+    /// Paths of the host private keys
+    pub host_keys: Vec<String>,
+    pub kex_algorithms: Vec<KeyExchangeAlgorithm>,
+    pub ciphers: Vec<EncryptionAlgorithm>,
+    pub macs: Vec<MacAlgorithm>,
+    /// Time a client has to authenticate
+    pub login_grace_time: Option<Duration>,
+    pub max_startups: MaxStartups,
+    /// Unauthenticated connections from one address
+    pub per_source_max_startups: Option<usize>,
+    /// Subsystem names and the commands that implement them
+    pub subsystems: Vec<(String, String)>,
+    pub settings: Settings,
+    pub matches: Vec<Match>,
+}
+
+impl Default for SshdConfig {
+    fn default() -> SshdConfig {
+        SshdConfig {
+            listen_addresses: Vec::new(),
+            ports: Vec::new(),
+            address_family: AddressFamily::Any,
+            host_keys: Vec::new(),
+            kex_algorithms: KEY_EXCHANGE.to_vec(),
+            ciphers: ENCRYPTION.to_vec(),
+            macs: MAC.to_vec(),
+            login_grace_time: Some(Duration::from_secs(120)),
+            max_startups: MaxStartups::default(),
+            per_source_max_startups: None,
+            subsystems: Vec::new(),
+            settings: Settings::default(),
+            matches: Vec::new(),
+        }
+    }
+}
+
+impl SshdConfig {
+    /// Reads a config file if a path is given. The options, given as
+    /// `Keyword=value` on the command line, take precedence over it.
+    pub fn read(path: Option<&str>, options: &[String])
+        -> io::Result<SshdConfig> {
+        let mut lines = parse_text(&options.join("\n"), "command line")?;
+        if let Some(path) = path {
+            lines.extend(read_file(
+                Path::new(path),
+                Path::new(INCLUDE_DIR),
+                0,
+            )?);
+        }
+
+        SshdConfig::from_lines(lines)
+    }
+
+    /// Parses the text of a config file
+    pub fn parse(text: &str) -> io::Result<SshdConfig> {
+        SshdConfig::from_lines(parse_text(text, "sshd_config")?)
+    }
+
+    fn from_lines(lines: Vec<Line>) -> io::Result<SshdConfig> {
+        let mut config = SshdConfig::default();
+        let mut seen: Vec<String> = Vec::new();
+        let mut block: Option<Match> = None;
+
+        for line in lines {
+            let keyword = &*line.keyword;
+
+            if keyword == "match" {
+                config.matches.extend(block.take());
+                block = Some(Match::parse(&line.args)
+                    .map_err(|err| line.error(&err))?);
+                continue;
+            }
+
+            if IGNORED.contains(&keyword) {
+                warn!("{}: ignoring unsupported option {}", line.location,
+                    keyword);
+                continue;
+            }
+
+            let list = LISTS.contains(&keyword);
+            let min_args = if keyword == "subsystem" { 2 } else { 1 };
+            if line.args.len() < min_args {
+                return Err(line.error("missing argument"));
+            }
+            if !list && line.args.len() > 1 {
+                return Err(line.error("garbage at end of line"));
+            }
+
+            if let Some(ref mut block) = block {
+                if !SETTINGS.contains(&keyword) {
+                    return Err(line.error(&format!(
+                        "Directive '{}' is not allowed within a Match block",
+                        keyword
+                    )));
+                }
+
+                Settings::default().set(keyword, &line.args, true).map_err(
+                    |err| line.error(&err),
+                )?;
+                block.options.push((keyword.to_string(), line.args.clone()));
+                continue;
+            }
+
+            let first = !seen.iter().any(|k| k == keyword);
+            if !first && !list {
+                continue;
+            }
+
+            config.set(keyword, &line.args, first).map_err(
+                |err| line.error(&err),
+            )?;
+            seen.push(keyword.to_string());
+        }
+
+        config.matches.extend(block);
+
+        if config.ports.is_empty() {
+            config.ports.push(22);
+        }
+
+        Ok(config)
+    }
+
+    /// The addresses to listen on. ListenAddress options without a port are
+    /// used with each Port, and without ListenAddress options the wildcard
+    /// addresses are used.
+    pub fn listeners(&self) -> io::Result<Vec<Listener>> {
+        let wildcards =
+            [("0.0.0.0".to_string(), None), ("::".to_string(), None)];
+        let hosts = if self.listen_addresses.is_empty() {
+            &wildcards[..]
+        }
+        else {
+            &self.listen_addresses[..]
+        };
+
+        let mut addresses: Vec<SocketAddr> = Vec::new();
+        for &(ref host, port) in hosts.iter() {
+            let ports = port.map_or(self.ports.clone(), |port| vec![port]);
+            for port in ports {
+                let resolved = (&**host, port).to_socket_addrs().map_err(|err| {
+                    io::Error::new(err.kind(), format!("{}: {}", host, err))
+                })?;
+
+                for address in resolved {
+                    if self.address_family.allows(&address) &&
+                        !addresses.contains(&address)
+                    {
+                        addresses.push(address);
+                    }
+                }
+            }
+        }
+
+        // An IPv6 socket is dual-stack, unless IPv4 connections to its port
+        // are accepted by another listener, which would fail to bind
+        let v6_only = |address: &SocketAddr| {
+            self.address_family == AddressFamily::Inet6 ||
+                addresses.iter().any(|other| {
+                    other.is_ipv4() && other.port() == address.port()
+                })
+        };
+
+        Ok(
+            addresses
+                .iter()
+                .map(|address| Listener {
+                    address: *address,
+                    v6_only: address.is_ipv6() && v6_only(address),
+                })
+                .collect(),
+        )
+    }
+
+    fn set(&mut self, keyword: &str, args: &[String], first: bool)
+        -> Result<(), String> {
+        let arg = &*args[0];
+        match keyword
+        {
+            "addressfamily" => {
+                self.address_family = match arg
+                {
+                    "any" => AddressFamily::Any,
+                    "inet" => AddressFamily::Inet,
+                    "inet6" => AddressFamily::Inet6,
+                    _ => {
+                        return Err(
+                            format!("Bad AddressFamily argument: {}", arg),
+                        )
+                    }
+                };
+            }
+            "ciphers" => {
+                let supported: Vec<EncryptionAlgorithm> = ENCRYPTION
+                    .iter()
+                    .chain(LEGACY_ENCRYPTION)
+                    .cloned()
+                    .collect();
+                self.ciphers = parse_algorithms(arg, ENCRYPTION, &supported)?;
+            }
+            "hostkey" => self.host_keys.push(arg.to_string()),
+            "kexalgorithms" => {
+                self.kex_algorithms =
+                    parse_algorithms(arg, KEY_EXCHANGE, KEY_EXCHANGE)?;
+            }
+            "listenaddress" => {
+                self.listen_addresses.push(parse_host_port(arg)?);
+            }
+            "logingracetime" => self.login_grace_time = parse_timeout(arg)?,
+            "macs" => self.macs = parse_algorithms(arg, MAC, MAC)?,
+            "maxstartups" => self.max_startups = MaxStartups::parse(arg)?,
+            "persourcemaxstartups" => {
+                self.per_source_max_startups = if arg == "none" {
+                    None
+                }
+                else {
+                    Some(parse_number(arg)?)
+                };
+            }
+            "port" => self.ports.push(parse_port(arg)?),
+            "subsystem" => {
+                if self.subsystems.iter().any(|&(ref name, _)| name == arg) {
+                    return Err(format!("Subsystem '{}' already defined", arg));
+                }
+                self.subsystems.push((arg.to_string(), args[1..].join(" ")));
+            }
+            _ => return self.settings.set(keyword, args, first),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+Port 2222
+Port 2223
+ListenAddress [::1]:22
+PasswordAuthentication no
+PasswordAuthentication yes
+Ciphers +aes128-cbc
+MACs -hmac-sha1*,umac-*
+ClientAliveInterval 1m
+Subsystem sftp /usr/lib/openssh/sftp-server -l INFO
+UsePAM yes
+
+Match User alice,bob Address 10.0.0.0/8
+    PasswordAuthentication yes
+    AllowUsers alice
+Match All
+    PasswordAuthentication no
+    Banner /etc/issue.net
+";
+
+    #[test]
+    fn test_parse() {
+        let config = SshdConfig::parse(CONFIG).unwrap();
+
+        assert_eq!(config.ports, vec![2222, 2223]);
+        assert_eq!(
+            config.listen_addresses,
+            vec![("::1".to_string(), Some(22))]
+        );
+        assert!(!config.settings.password_authentication);
+        assert_eq!(
+            config.ciphers.last(),
+            Some(&EncryptionAlgorithm::AES128_CBC)
+        );
+        assert_eq!(
+            config.macs,
+            vec![MacAlgorithm::HMAC_SHA2_256_ETM,
+                 MacAlgorithm::HMAC_SHA2_512_ETM,
+                 MacAlgorithm::HMAC_SHA2_256,
+                 MacAlgorithm::HMAC_SHA2_512]
+        );
+        assert_eq!(
+            config.settings.client_alive_interval,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            config.subsystems,
+            vec![("sftp".to_string(),
+                  "/usr/lib/openssh/sftp-server -l INFO".to_string())]
+        );
+        assert_eq!(config.matches.len(), 2);
+    }
+
+    #[test]
+    fn test_match() {
+        let config = SshdConfig::parse(CONFIG).unwrap();
+        let mut context = MatchContext {
+            user: None,
+            address: Some("10.1.2.3".parse().unwrap()),
+            local_address: None,
+        };
+
+        // User criteria do not match before authentication
+        let settings = config.settings.matched(&config.matches, &context);
+        assert!(!settings.password_authentication);
+        assert_eq!(settings.banner, Some("/etc/issue.net".to_string()));
+
+        context.user = Some("bob");
+        let settings = config.settings.matched(&config.matches, &context);
+        assert!(settings.password_authentication);
+        assert!(settings.allows_user("alice", context.address));
+        assert!(!settings.allows_user("bob", context.address));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let listeners = |text| {
+            SshdConfig::parse(text)
+                .unwrap()
+                .listeners()
+                .unwrap()
+                .into_iter()
+                .map(|l| (l.address.to_string(), l.v6_only))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            listeners(""),
+            vec![("0.0.0.0:22".to_string(), false),
+                 ("[::]:22".to_string(), true)]
+        );
+        assert_eq!(
+            listeners("ListenAddress [::]\nPort 2222\nPort 2223"),
+            vec![("[::]:2222".to_string(), false),
+                 ("[::]:2223".to_string(), false)]
+        );
+        assert_eq!(
+            listeners("ListenAddress 127.0.0.1\nListenAddress [::1]:2222"),
+            vec![("127.0.0.1:22".to_string(), false),
+                 ("[::1]:2222".to_string(), false)]
+        );
+        assert_eq!(
+            listeners("AddressFamily inet6"),
+            vec![("[::]:22".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn test_max_startups() {
+        let config = SshdConfig::parse("MaxStartups 5:50:15").unwrap();
+        let rates: Vec<u32> = [0, 4, 5, 10, 14, 15, 20]
+            .iter()
+            .map(|&n| config.max_startups.drop_rate(n))
+            .collect();
+        assert_eq!(rates, vec![0, 0, 50, 75, 95, 100, 100]);
+
+        let config = SshdConfig::parse("MaxStartups 3").unwrap();
+        assert_eq!(config.max_startups.drop_rate(2), 0);
+        assert_eq!(config.max_startups.drop_rate(3), 100);
+
+        assert!(SshdConfig::parse("MaxStartups 10:30").is_err());
+        assert!(SshdConfig::parse("MaxStartups 10:130:100").is_err());
+        assert!(SshdConfig::parse("MaxStartups 100:30:10").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(SshdConfig::parse("Port 0").is_err());
+        assert!(SshdConfig::parse("NoSuchOption yes").is_err());
+        assert!(SshdConfig::parse("PubkeyAuthentication maybe").is_err());
+        assert!(SshdConfig::parse("Ciphers +rot13").is_err());
+        assert!(SshdConfig::parse("Match User root\nPort 22").is_err());
+        assert!(SshdConfig::parse("Match Host example.com").is_err());
+        assert!(SshdConfig::parse("Subsystem sftp").is_err());
+
+        let err = SshdConfig::parse("\nMaxAuthTries x").unwrap_err();
+        assert_eq!(err.to_string(), "sshd_config line 2: Bad number: x");
+    }
+}
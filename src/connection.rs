@@ -1,15 +1,42 @@
 use std::collections::{BTreeMap, VecDeque};
-use std::io::{self, BufReader, Read, Write};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr, TcpStream};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use algorithm::{self, CompressionAlgorithm, EncryptionAlgorithm,
+                KeyExchangeAlgorithm, PublicKeyAlgorithm};
 use channel::{Channel, ChannelId, ChannelRequest};
-use encryption::{AesCtr, Decryptor, Encryption};
+use client::ClientConfig;
+use compression::{Compressor, Decompressor};
+use config::ssh::StrictHostKeyChecking;
+use config::sshd::{MatchContext, Settings};
+use crypto::util::fixed_time_eq;
+use encryption::{AeadEncryption, Encryption};
 use error::{ConnectionError, ConnectionResult as Result};
 use key_exchange::{KexResult, KeyExchange};
-use mac::{Hmac, MacAlgorithm};
+use known_hosts::{self, HostKeyStatus};
+use mac::MacAlgorithm;
 use message::MessageType;
-use packet::{Packet, ReadPacketExt, WritePacketExt};
-use server::ServerConfig;
+use packet::{MAX_PACKET_LEN, Packet, ReadPacketExt, WritePacketExt};
+use public_key::{self, Certificate, CertificateType, FingerprintHash,
+                 KeyPair};
+use server::{ServerConfig, Startup};
+
+const USERAUTH_PK_OK: u8 = 60;
+const OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+
+// Disconnect reasons, see RFC 4253, section 11.1
+const DISCONNECT_BY_APPLICATION: u32 = 11;
+const DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u32 = 14;
+
+const KEEPALIVE: &str = "keepalive@openssh.com";
+
+// Host key rotation, see PROTOCOL in OpenSSH, section 2.5
+const HOSTKEYS: &str = "hostkeys-00@openssh.com";
+const HOSTKEYS_PROVE: &str = "hostkeys-prove-00@openssh.com";
 
 #[derive(PartialEq)]
 enum ConnectionState {
@@ -18,9 +45,28 @@ enum ConnectionState {
     Established,
 }
 
+/// Limits after which a new key exchange is started, see RFC 4253, section 9
+#[derive(Clone, Debug)]
+pub struct RekeyLimit {
+    pub bytes: u64,
+    pub packets: u64,
+    pub time: Option<Duration>,
+}
+
+impl Default for RekeyLimit {
+    fn default() -> RekeyLimit {
+        RekeyLimit {
+            bytes: 1 << 30,
+            packets: 1 << 31,
+            time: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum ConnectionType {
     Server(Arc<ServerConfig>),
+    Client(Arc<ClientConfig>),
 }
 
 #[derive(Default, Debug)]
@@ -31,112 +77,556 @@ pub struct HashData {
     pub server_kexinit: Option<Vec<u8>>,
 }
 
+/// Algorithms negotiated for the inbound and outbound direction
+#[derive(Clone, Copy)]
+struct Algorithms {
+    host_key: PublicKeyAlgorithm,
+    encryption: (EncryptionAlgorithm, EncryptionAlgorithm),
+    mac: (algorithm::MacAlgorithm, algorithm::MacAlgorithm),
+    compression: (CompressionAlgorithm, CompressionAlgorithm),
+}
+
+/// Restrictions of an authenticated session, e.g. from a user certificate
+#[derive(Clone, Debug)]
+struct AuthOptions {
+    force_command: Option<String>,
+    permit_pty: bool,
+    permit_port_forwarding: bool,
+}
+
+impl Default for AuthOptions {
+    fn default() -> AuthOptions {
+        AuthOptions {
+            force_command: None,
+            permit_pty: true,
+            permit_port_forwarding: true,
+        }
+    }
+}
+
+/// A stream to the peer that can wait for incoming data
+pub trait Stream: Read + Write {
+    /// Waits until data arrives or the timeout expires, which returns false
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool>;
+
+    /// Sets the time after which reads fail, or no timeout
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.set_read_timeout(Some(timeout))?;
+        let result = self.peek(&mut [0; 1]);
+        self.set_read_timeout(None)?;
+
+        match result
+        {
+            Ok(_) => Ok(true),
+            Err(ref err) if is_timeout(err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock ||
+        err.kind() == io::ErrorKind::TimedOut
+}
+
+/// A stream whose reads fail once a deadline has passed, so that a peer
+/// can't hold a connection open by sending data slowly
+struct Deadline<'a, S: Stream + 'a> {
+    stream: &'a mut S,
+    deadline: Option<Instant>,
+}
+
+impl<'a, S: Stream> Deadline<'a, S> {
+    fn clear(&mut self) -> io::Result<()> {
+        self.deadline = None;
+        self.stream.set_timeout(None)
+    }
+}
+
+impl<'a, S: Stream> Read for Deadline<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = match self.deadline
+        {
+            Some(deadline) => deadline,
+            None => return self.stream.read(buf),
+        };
+
+        let now = Instant::now();
+        if now < deadline {
+            self.stream.set_timeout(Some(deadline - now))?;
+            match self.stream.read(buf)
+            {
+                Err(ref err) if is_timeout(err) => (),
+                result => return result,
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timeout before authentication",
+        ))
+    }
+}
+
+impl<'a, S: Stream> Write for Deadline<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<'a, S: Stream> Stream for Deadline<'a, S> {
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.stream.wait(timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_timeout(timeout)
+    }
+}
+
 pub struct Connection {
     pub conn_type: ConnectionType,
     pub hash_data: HashData,
+    /// Address of the peer, which certificates can restrict
+    pub peer_addr: Option<IpAddr>,
+    /// Address the server accepted the connection on
+    pub local_addr: Option<SocketAddr>,
+    /// Counts the connection as unauthenticated until it is dropped
+    pub startup: Option<Startup>,
     state: ConnectionState,
     key_exchange: Option<Box<KeyExchange>>,
+    algorithms: Option<Algorithms>,
     session_id: Option<Vec<u8>>,
-    encryption: Option<(Box<Encryption>, Box<Encryption>)>,
-    mac: Option<(Box<MacAlgorithm>, Box<MacAlgorithm>)>,
+    // Inbound and outbound encryption and integrity protection
+    cipher: (Option<Cipher>, Option<Cipher>),
+    // Inbound and outbound ciphers that are used after the next NEWKEYS
+    next_cipher: (Option<Cipher>, Option<Cipher>),
+    // Inbound and outbound zlib streams, which outlive key re-exchanges
+    compression: (Option<Decompressor>, Option<Compressor>),
+    kex_init_sent: bool,
+    // Whether both sides agreed on strict key exchange in the initial KEXINIT
+    strict_kex: bool,
+    // Whether the client accepts EXT_INFO, see RFC 8308
+    ext_info: bool,
+    // Signature algorithms the server accepts for user authentication
+    server_sig_algs: Option<Vec<algorithm::PublicKeyAlgorithm>>,
+    authenticated: bool,
+    auth_options: AuthOptions,
+    // Server settings after applying the Match blocks for this connection
+    settings: Settings,
+    auth_failures: u32,
+    banner_sent: bool,
+    // Keepalive requests the idle client has not answered
+    client_alive_missed: u32,
+    // Session channels that were opened, channels are never closed, so this
+    // only grows and MaxSessions limits the sessions of the whole connection
+    sessions: u32,
+    // Whether the connection ends after the queued packets were sent
+    closing: bool,
+    // Host key the server presented in the last key exchange
+    server_host_key: Option<Vec<u8>>,
+    // Host keys the server announced, waiting for the proof that it owns them
+    unproven_host_keys: Vec<Vec<u8>>,
+    // Additional host keys the server proved to own
+    learned_host_keys: Vec<Box<KeyPair>>,
     seq: (u32, u32),
+    // Bytes and packets transferred since the last key exchange
+    transferred: (u64, u64),
+    last_kex: Instant,
     tx_queue: VecDeque<Packet>,
+    // Packets held back until the running key exchange is finished
+    delayed_queue: VecDeque<Packet>,
     channels: BTreeMap<ChannelId, Channel>,
 }
 
+/// Encryption and integrity protection of one direction
+enum Cipher {
+    /// A cipher with a separate MAC over the unencrypted packet
+    Mac(Box<Encryption>, Box<MacAlgorithm>),
+    /// A cipher with a separate MAC over the encrypted packet, which leaves
+    /// the packet length unencrypted
+    Etm(Box<Encryption>, Box<MacAlgorithm>),
+    /// An AEAD cipher that protects the integrity itself
+    Aead(Box<AeadEncryption>),
+}
+
 impl<'a> Connection {
     pub fn new(conn_type: ConnectionType) -> Connection {
         Connection {
             conn_type: conn_type,
             hash_data: HashData::default(),
+            peer_addr: None,
+            local_addr: None,
+            startup: None,
             state: ConnectionState::Initial,
             key_exchange: None,
+            algorithms: None,
             session_id: None,
-            encryption: None,
-            mac: None,
+            cipher: (None, None),
+            next_cipher: (None, None),
+            compression: (None, None),
+            kex_init_sent: false,
+            strict_kex: false,
+            ext_info: false,
+            server_sig_algs: None,
+            authenticated: false,
+            auth_options: AuthOptions::default(),
+            settings: Settings::default(),
+            auth_failures: 0,
+            banner_sent: false,
+            client_alive_missed: 0,
+            sessions: 0,
+            closing: false,
+            server_host_key: None,
+            unproven_host_keys: Vec::new(),
+            learned_host_keys: Vec::new(),
             seq: (0, 0),
+            transferred: (0, 0),
+            last_kex: Instant::now(),
             tx_queue: VecDeque::new(),
+            delayed_queue: VecDeque::new(),
             channels: BTreeMap::new(),
         }
     }
 
-    pub fn run<S: Read + Write>(&mut self, stream: &mut S) -> Result<()> {
-        self.send_id(stream)?;
-        self.read_id(stream)?;
+    pub fn run<S: Stream>(&mut self, stream: &mut S) -> Result<()> {
+        // A server gives clients the login grace time to authenticate
+        let login_grace_time = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config.login_grace_time,
+            ConnectionType::Client(_) => None,
+        };
+        let mut stream = Deadline {
+            stream: stream,
+            deadline: login_grace_time.map(|time| Instant::now() + time),
+        };
+
+        self.send_id(&mut stream)?;
+        self.read_id(&mut stream)?;
 
         let mut reader = BufReader::new(stream);
 
+        // The client always starts the key exchange
+        if !self.is_server() {
+            let packet = self.start_kex()?;
+            self.send(reader.get_mut(), packet)?;
+        }
+        else {
+            self.settings = self.settings_for(None);
+        }
+
         loop {
+            // Check whether an idle client is still alive
+            if let Some(interval) = self.settings.client_alive_interval {
+                if reader.buffer().is_empty() &&
+                    !reader.get_mut().wait(interval)?
+                {
+                    let packet = self.client_alive()?;
+                    self.send(reader.get_mut(), packet)?;
+                    if self.closing {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+
             let packet = self.recv(&mut reader)?;
+            self.client_alive_missed = 0;
             let response = self.process(packet)?;
 
             let mut stream = reader.get_mut();
 
+            // Limits are checked whenever a packet arrives, and in `send`
+            if self.rekey_needed() {
+                debug!("Rekey limit reached, starting key re-exchange");
+                let packet = self.start_kex()?;
+                self.send(&mut stream, packet)?;
+            }
+
             if let Some(packet) = response {
                 self.send(&mut stream, packet)?;
             }
 
             // Send additional packets from the queue
-            let mut packets: Vec<Packet> = self.tx_queue.drain(..).collect();
-            for packet in packets.drain(..) {
+            while let Some(packet) = self.tx_queue.pop_front() {
                 self.send(&mut stream, packet)?;
             }
+
+            // The limits of unauthenticated connections no longer apply
+            if self.authenticated && stream.deadline.is_some() {
+                self.startup = None;
+                stream.clear()?;
+            }
+
+            if self.closing {
+                return Ok(());
+            }
         }
     }
 
-    fn recv(&mut self, mut stream: &mut Read) -> Result<Packet> {
-        let packet = if let Some((ref mut c2s, _)) = self.encryption {
-            let mut decryptor = Decryptor::new(&mut **c2s, &mut stream);
-            Packet::read_from(&mut decryptor)?
+    fn rekey_limit(&self) -> &RekeyLimit {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => &config.rekey_limit,
+            ConnectionType::Client(ref config) => &config.rekey_limit,
         }
-        else {
-            Packet::read_from(&mut stream)?
-        };
+    }
 
-        if let Some((ref mut mac, _)) = self.mac {
-            let mut sig = vec![0; mac.size()];
-            stream.read_exact(&mut sig)?;
+    /// Whether a key re-exchange has to be started. Like OpenSSH, we only
+    /// start one after user authentication.
+    fn rekey_needed(&self) -> bool {
+        if self.state != ConnectionState::Established || !self.authenticated {
+            return false;
+        }
+
+        let limit = self.rekey_limit();
+
+        self.transferred.0 >= limit.bytes || self.transferred.1 >= limit.packets ||
+            limit.time.map_or(false, |t| self.last_kex.elapsed() >= t)
+    }
+
+    fn recv(&mut self, mut stream: &mut Read) -> Result<Packet> {
+        let packet = match self.cipher.0
+        {
+            Some(Cipher::Mac(ref mut encryption, ref mut mac)) => {
+                // Decrypt the first block to learn the packet length
+                let block_size = encryption.block_size();
+                let mut data = vec![0; block_size];
+                stream.read_exact(&mut data)?;
+
+                let mut raw = vec![0; block_size];
+                encryption.decrypt(&data, &mut raw);
+
+                let packet_len = (&raw[0..4]).read_uint32()? as usize;
+                if packet_len > MAX_PACKET_LEN || packet_len + 4 < block_size ||
+                    (packet_len + 4) % block_size != 0
+                {
+                    return Err(ConnectionError::ProtocolError);
+                }
 
-            let mut sig_cmp = vec![0; mac.size()];
-            mac.sign(packet.data(), self.seq.0, sig_cmp.as_mut_slice());
+                data.resize(4 + packet_len, 0);
+                stream.read_exact(&mut data[block_size..])?;
 
-            if sig != sig_cmp {
-                return Err(ConnectionError::IntegrityError);
+                raw.resize(4 + packet_len, 0);
+                encryption.decrypt(&data[block_size..], &mut raw[block_size..]);
+
+                let packet = Packet::from_raw(raw)?;
+
+                let mut sig = vec![0; mac.size()];
+                stream.read_exact(&mut sig)?;
+
+                let mut sig_cmp = vec![0; mac.size()];
+                mac.sign(packet.data(), self.seq.0, sig_cmp.as_mut_slice());
+
+                if !fixed_time_eq(&sig, &sig_cmp) {
+                    return Err(ConnectionError::IntegrityError);
+                }
+
+                packet
             }
-        }
+            Some(Cipher::Etm(ref mut encryption, ref mut mac)) => {
+                let mut data = vec![0; 4];
+                stream.read_exact(&mut data)?;
+
+                let packet_len = (&data[0..4]).read_uint32()? as usize;
+                if packet_len > MAX_PACKET_LEN ||
+                    packet_len % encryption.block_size() != 0
+                {
+                    return Err(ConnectionError::ProtocolError);
+                }
+
+                // Verify the MAC over the encrypted packet before decrypting
+                data.resize(4 + packet_len, 0);
+                stream.read_exact(&mut data[4..])?;
+
+                let mut sig = vec![0; mac.size()];
+                stream.read_exact(&mut sig)?;
+
+                let mut sig_cmp = vec![0; mac.size()];
+                mac.sign(&data, self.seq.0, sig_cmp.as_mut_slice());
+
+                if !fixed_time_eq(&sig, &sig_cmp) {
+                    return Err(ConnectionError::IntegrityError);
+                }
+
+                let mut raw = data.clone();
+                encryption.decrypt(&data[4..], &mut raw[4..]);
+
+                Packet::from_raw(raw)?
+            }
+            Some(Cipher::Aead(ref mut aead)) => {
+                let mut data = vec![0; 4];
+                stream.read_exact(&mut data)?;
+
+                let packet_len = aead.decrypt_length(self.seq.0, &data) as usize;
+                if packet_len > MAX_PACKET_LEN {
+                    return Err(ConnectionError::ProtocolError);
+                }
+
+                // Read the rest of the packet and the authentication tag
+                data.resize(4 + packet_len + aead.tag_size(), 0);
+                stream.read_exact(&mut data[4..])?;
+
+                let mut raw = vec![0; 4 + packet_len];
+                if !aead.open(self.seq.0, &data, &mut raw) {
+                    return Err(ConnectionError::IntegrityError);
+                }
+
+                Packet::from_raw(raw)?
+            }
+            None => Packet::read_from(&mut stream)?,
+        };
+
+        self.transferred.0 += packet.data().len() as u64;
+        self.transferred.1 += 1;
+
+        let packet = match self.compression.0
+        {
+            Some(ref mut decompressor) => {
+                Packet::Payload(decompressor.decompress(&packet.payload())?)
+            }
+            None => packet,
+        };
 
         debug!("Packet {} received: {:?}", self.seq.0, packet);
 
         // Count up the received packet sequence number
         self.seq.0 = self.seq.0.wrapping_add(1);
 
+        // Only the server can tell a client that it is authenticated
+        if !self.is_server() &&
+            packet.msg_type() == MessageType::UserAuthSuccess
+        {
+            self.authenticated = true;
+            self.update_compression(true, true);
+        }
+
         Ok(packet)
     }
 
-    fn send(&mut self, mut stream: &mut Write, packet: Packet)
-        -> io::Result<()> {
+    fn send(&mut self, mut stream: &mut Write, packet: Packet) -> Result<()> {
+        // Check the limits before sending, so that a session which mostly
+        // sends does not run past them until the peer sends something
+        if !packet.msg_type().is_kex_allowed() && self.rekey_needed() {
+            debug!("Rekey limit reached, starting key re-exchange");
+            let kex_init = self.start_kex()?;
+            self.send(stream, kex_init)?;
+        }
+
+        // Only transport layer messages may be sent while keys are exchanged
+        if self.state == ConnectionState::KeyExchange &&
+            !packet.msg_type().is_kex_allowed()
+        {
+            trace!("Delaying packet until key exchange is done: {:?}", packet);
+            self.delayed_queue.push_back(packet);
+            return Ok(());
+        }
+
         debug!("Sending packet {}: {:?}", self.seq.1, packet);
 
-        let packet = packet.to_raw()?;
+        let msg_type = packet.msg_type();
+
+        let packet = match self.compression.1
+        {
+            Some(ref mut compressor) => {
+                Packet::Payload(compressor.compress(&packet.payload())?)
+            }
+            None => packet,
+        };
+
+        match self.cipher.1
+        {
+            Some(Cipher::Mac(ref mut encryption, ref mut mac)) => {
+                let packet = packet.to_raw(true)?;
 
-        if let Some((_, ref mut s2c)) = self.encryption {
-            let mut encrypted = vec![0; packet.data().len()];
-            s2c.encrypt(packet.data(), encrypted.as_mut_slice());
+                let mut encrypted = vec![0; packet.data().len()];
+                encryption.encrypt(packet.data(), encrypted.as_mut_slice());
 
-            // Sending encrypted packet
-            stream.write_all(encrypted.as_slice())?;
-        }
-        else {
-            packet.write_to(&mut stream)?;
-        }
+                // Sending encrypted packet
+                stream.write_all(encrypted.as_slice())?;
+
+                let mut sig = vec![0; mac.size()];
+                mac.sign(packet.data(), self.seq.1, sig.as_mut_slice());
+                stream.write_all(sig.as_slice())?;
+
+                self.transferred.0 += packet.data().len() as u64;
+            }
+            Some(Cipher::Etm(ref mut encryption, ref mut mac)) => {
+                // The packet length is sent unencrypted
+                let packet = packet.to_raw(false)?;
+
+                let mut encrypted = packet.data().to_vec();
+                encryption.encrypt(&packet.data()[4..], &mut encrypted[4..]);
+                stream.write_all(encrypted.as_slice())?;
+
+                let mut sig = vec![0; mac.size()];
+                mac.sign(encrypted.as_slice(), self.seq.1, sig.as_mut_slice());
+                stream.write_all(sig.as_slice())?;
+
+                self.transferred.0 += packet.data().len() as u64;
+            }
+            Some(Cipher::Aead(ref mut aead)) => {
+                // The packet length is not part of the encrypted blocks
+                let packet = packet.to_raw(false)?;
+
+                let mut sealed = vec![0; packet.data().len() + aead.tag_size()];
+                aead.seal(self.seq.1, packet.data(), sealed.as_mut_slice());
+                stream.write_all(sealed.as_slice())?;
+
+                self.transferred.0 += packet.data().len() as u64;
+            }
+            None => {
+                let packet = packet.to_raw(true)?;
+                packet.write_to(&mut stream)?;
 
-        if let Some((_, ref mut mac)) = self.mac {
-            let mut sig = vec![0; mac.size()];
-            mac.sign(packet.data(), self.seq.1, sig.as_mut_slice());
-            stream.write_all(sig.as_slice())?;
+                self.transferred.0 += packet.data().len() as u64;
+            }
         }
 
         self.seq.1 = self.seq.1.wrapping_add(1);
+        self.transferred.1 += 1;
+
+        if msg_type == MessageType::UserAuthSuccess {
+            self.authenticated = true;
+            self.update_compression(true, true);
+
+            // Tell the client about all our host keys
+            if let Some(packet) = self.hostkeys_packet()? {
+                self.tx_queue.push_back(packet);
+            }
+        }
+
+        // All packets after our NEWKEYS use the new keys
+        if msg_type == MessageType::NewKeys {
+            if let Some(cipher) = self.next_cipher.1.take() {
+                debug!("Switching to new outbound keys");
+                self.cipher.1 = Some(cipher);
+            }
+            self.update_compression(false, true);
+
+            if self.strict_kex {
+                self.seq.1 = 0;
+            }
+
+            // The server sends EXT_INFO right after its first NEWKEYS
+            if self.ext_info && self.is_server() {
+                self.ext_info = false;
+                self.tx_queue.push_front(self.ext_info_packet()?);
+            }
+            self.finish_kex();
+        }
 
         Ok(())
     }
@@ -149,7 +639,12 @@ impl<'a> Connection {
         stream.write(b"\r\n")?;
         stream.flush()?;
 
-        self.hash_data.server_id = Some(id);
+        if self.is_server() {
+            self.hash_data.server_id = Some(id);
+        }
+        else {
+            self.hash_data.client_id = Some(id);
+        }
 
         Ok(())
     }
@@ -157,20 +652,51 @@ impl<'a> Connection {
     fn read_id(&mut self, stream: &mut Read) -> io::Result<()> {
         use std::str;
 
-        let mut buf = [0; 255];
-        let count = stream.read(&mut buf)?;
+        loop {
+            // Read byte by byte to not consume the first packet of the peer
+            let mut line = Vec::with_capacity(255);
+            while line.last() != Some(&b'\n') {
+                if line.len() == 255 {
+                    return Err(
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid id"),
+                    );
+                }
+
+                let mut byte = [0; 1];
+                stream.read_exact(&mut byte)?;
+                line.push(byte[0]);
+            }
+
+            let id = str::from_utf8(&line).map(str::trim).or(Err(
+                io::Error::new(io::ErrorKind::InvalidData, "invalid id"),
+            ))?;
 
-        let id = str::from_utf8(&buf[0..count]).map(str::trim).or(Err(
-            io::Error::new(io::ErrorKind::InvalidData, "invalid id"),
-        ))?;
+            if id.starts_with("SSH-") {
+                info!("Peer identifies as {:?}", id);
+                if self.is_server() {
+                    self.hash_data.client_id = Some(id.to_owned());
+                }
+                else {
+                    self.hash_data.server_id = Some(id.to_owned());
+                }
+                return Ok(());
+            }
+            else if self.is_server() {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid id"),
+                );
+            }
 
-        if id.starts_with("SSH-") {
-            info!("Peer identifies as {:?}", id);
-            self.hash_data.client_id = Some(id.to_owned());
-            Ok(())
+            // Servers may send other lines before their version string
+            debug!("Ignoring line from server: {:?}", id);
         }
-        else {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "invalid id"))
+    }
+
+    fn is_server(&self) -> bool {
+        match self.conn_type
+        {
+            ConnectionType::Server(_) => true,
+            ConnectionType::Client(_) => false,
         }
     }
 
@@ -179,17 +705,31 @@ impl<'a> Connection {
 
         let kex = self.key_exchange.take().ok_or(KeyGenerationError)?;
 
-        let key = kex.hash(
-            &[
-                kex.shared_secret().ok_or(KeyGenerationError)?,
-                kex.exchange_hash().ok_or(KeyGenerationError)?,
-                id,
-                self.session_id
-                    .as_ref()
-                    .ok_or(KeyGenerationError)?
-                    .as_slice(),
-            ],
-        );
+        let key = {
+            let shared_secret = kex.shared_secret().ok_or(KeyGenerationError)?;
+            let exchange_hash = kex.exchange_hash().ok_or(KeyGenerationError)?;
+
+            let mut key = kex.hash(
+                &[
+                    shared_secret,
+                    exchange_hash,
+                    id,
+                    self.session_id
+                        .as_ref()
+                        .ok_or(KeyGenerationError)?
+                        .as_slice(),
+                ],
+            );
+
+            // Extend the key if the hash is too short, see RFC 4253, 7.2
+            while key.len() < len {
+                let next = kex.hash(&[shared_secret, exchange_hash, &key]);
+                key.extend(next);
+            }
+
+            key.truncate(len);
+            key
+        };
 
         self.key_exchange = Some(kex);
 
@@ -197,9 +737,26 @@ impl<'a> Connection {
     }
 
     pub fn process(&mut self, packet: Packet) -> Result<Option<Packet>> {
+        // Strict key exchange only allows key exchange messages until the
+        // initial key exchange is finished
+        if self.strict_kex && self.cipher.0.is_none() &&
+            !packet.msg_type().is_strict_kex_allowed()
+        {
+            error!("Unexpected packet during initial key exchange: {:?}", packet);
+            return Err(ConnectionError::ProtocolError);
+        }
+
+        if !self.authenticated && packet.msg_type().is_connection() {
+            error!("Unexpected packet before authentication: {:?}", packet);
+            return Err(ConnectionError::ProtocolError);
+        }
+
         match packet.msg_type()
         {
+            MessageType::Ignore | MessageType::Debug |
+            MessageType::Unimplemented => Ok(None),
             MessageType::KexInit => self.kex_init(packet),
+            MessageType::ExtInfo => self.ext_info(packet),
             MessageType::NewKeys => self.new_keys(packet),
             MessageType::ServiceRequest => self.service_request(packet),
             MessageType::UserAuthRequest => self.user_auth_request(packet),
@@ -207,6 +764,10 @@ impl<'a> Connection {
             MessageType::ChannelRequest => self.channel_request(packet),
             MessageType::ChannelData => self.channel_data(packet),
             MessageType::KeyExchange(_) => self.key_exchange(packet),
+            MessageType::GlobalRequest => self.global_request(packet),
+            MessageType::RequestSuccess | MessageType::RequestFailure => {
+                self.global_request_reply(packet)
+            }
             _ => {
                 error!("Unhandled packet: {:?}", packet);
                 Err(ConnectionError::ProtocolError)
@@ -214,30 +775,149 @@ impl<'a> Connection {
         }
     }
 
-    fn new_keys(&mut self, packet: Packet) -> Result<Option<Packet>> {
-        debug!("Switching to new keys");
+    fn new_keys(&mut self, _: Packet) -> Result<Option<Packet>> {
+        let cipher = self.next_cipher.0.take().ok_or(
+            ConnectionError::ProtocolError,
+        )?;
 
-        let iv_c2s = self.generate_key(b"A", 256)?;
-        let iv_s2c = self.generate_key(b"B", 256)?;
-        let enc_c2s = self.generate_key(b"C", 256)?;
-        let enc_s2c = self.generate_key(b"D", 256)?;
-        let mac_c2s = self.generate_key(b"E", 256)?;
-        let mac_s2c = self.generate_key(b"F", 256)?;
+        debug!("Switching to new inbound keys");
+        self.cipher.0 = Some(cipher);
+        self.update_compression(true, false);
 
-        self.encryption =
-            Some((
-                Box::new(AesCtr::new(enc_c2s.as_slice(), iv_c2s.as_slice())),
-                Box::new(AesCtr::new(enc_s2c.as_slice(), iv_s2c.as_slice())),
-            ));
+        if self.strict_kex {
+            self.seq.0 = 0;
+        }
 
-        self.mac = Some((
-            Box::new(Hmac::new(mac_c2s.as_slice())),
-            Box::new(Hmac::new(mac_s2c.as_slice())),
-        ));
+        self.finish_kex();
 
         Ok(None)
     }
 
+    fn derive_keys(&mut self) -> Result<()> {
+        let algorithms = self.algorithms.ok_or(
+            ConnectionError::KeyGenerationError,
+        )?;
+
+        // Client to server keys are derived from the letters A, C and E,
+        // server to client keys from B, D and F
+        let ids = self.directed([b"A", b"C", b"E"], [b"B", b"D", b"F"]);
+
+        let inbound = self.create_cipher(
+            algorithms.encryption.0,
+            algorithms.mac.0,
+            ids.0,
+        )?;
+        let outbound = self.create_cipher(
+            algorithms.encryption.1,
+            algorithms.mac.1,
+            ids.1,
+        )?;
+
+        self.next_cipher = (Some(inbound), Some(outbound));
+
+        Ok(())
+    }
+
+    fn create_cipher(
+        &mut self,
+        encryption: EncryptionAlgorithm,
+        mac: algorithm::MacAlgorithm,
+        ids: [&[u8; 1]; 3],
+    ) -> Result<Cipher> {
+        use self::ConnectionError::KeyGenerationError;
+
+        let iv = self.generate_key(ids[0], encryption.iv_len())?;
+        let key = self.generate_key(ids[1], encryption.key_len())?;
+
+        if encryption.is_aead() {
+            return Ok(Cipher::Aead(
+                encryption.aead_instance(&key, &iv).ok_or(KeyGenerationError)?,
+            ));
+        }
+
+        let mac_key = self.generate_key(ids[2], mac.key_len())?;
+
+        let cipher = encryption.instance(&key, &iv).ok_or(KeyGenerationError)?;
+        let mac_instance = mac.instance(&mac_key).ok_or(KeyGenerationError)?;
+
+        if mac.is_etm() {
+            Ok(Cipher::Etm(cipher, mac_instance))
+        }
+        else {
+            Ok(Cipher::Mac(cipher, mac_instance))
+        }
+    }
+
+    /// Starts or stops compression in the given directions according to the
+    /// negotiated algorithms. Delayed compression waits for authentication.
+    fn update_compression(&mut self, inbound: bool, outbound: bool) {
+        let algorithms = match self.algorithms
+        {
+            Some(ref algorithms) => algorithms.compression,
+            None => return,
+        };
+
+        let authenticated = self.authenticated;
+        let enabled = |algorithm: CompressionAlgorithm| {
+            algorithm != CompressionAlgorithm::None &&
+                (authenticated || !algorithm.is_delayed())
+        };
+
+        if inbound {
+            if !enabled(algorithms.0) {
+                self.compression.0 = None;
+            }
+            else if self.compression.0.is_none() {
+                debug!("Starting inbound compression");
+                self.compression.0 = Some(Decompressor::new());
+            }
+        }
+
+        if outbound {
+            if !enabled(algorithms.1) {
+                self.compression.1 = None;
+            }
+            else if self.compression.1.is_none() {
+                debug!("Starting outbound compression");
+                self.compression.1 = Some(Compressor::new());
+            }
+        }
+    }
+
+    /// Orders client to server and server to client values as inbound and
+    /// outbound according to our role
+    fn directed<T>(&self, c2s: T, s2c: T) -> (T, T) {
+        if self.is_server() { (c2s, s2c) } else { (s2c, c2s) }
+    }
+
+    /// Starts a new key exchange by creating our KEXINIT packet
+    fn start_kex(&mut self) -> Result<Packet> {
+        let packet = self.kex_init_packet()?;
+        self.state = ConnectionState::KeyExchange;
+        self.kex_init_sent = true;
+        Ok(packet)
+    }
+
+    /// Completes the key exchange once NEWKEYS was sent and received
+    fn finish_kex(&mut self) {
+        if self.key_exchange.is_none() || self.next_cipher.0.is_some() ||
+            self.next_cipher.1.is_some()
+        {
+            return;
+        }
+
+        debug!("Key exchange finished");
+
+        self.state = ConnectionState::Established;
+        self.key_exchange = None;
+        self.kex_init_sent = false;
+        self.transferred = (0, 0);
+        self.last_kex = Instant::now();
+
+        // Release packets that were held back during the key exchange
+        self.tx_queue.extend(self.delayed_queue.drain(..));
+    }
+
     fn service_request(&mut self, packet: Packet) -> Result<Option<Packet>> {
         let mut reader = packet.reader();
         let name = reader.read_string()?;
@@ -259,27 +939,340 @@ impl<'a> Connection {
         let service = reader.read_utf8()?;
         let method = reader.read_utf8()?;
 
-        let success = if method == "password" {
-            assert!(reader.read_bool()? == false);
-            let pass = reader.read_utf8()?;
-            pass == "hunter2"
+        debug!("User Auth {:?}, {:?}, {:?}", name, service, method);
+
+        let settings = self.settings_for(Some(&name));
+        let allowed = settings.allows_user(&name, self.peer_addr);
+        let root = name == "root";
+
+        if !allowed {
+            info!("User {} is not allowed by AllowUsers or DenyUsers", name);
         }
-        else {
-            false
-        };
 
-        debug!("User Auth {:?}, {:?}, {:?}", name, service, method);
+        let success = match &*method
+        {
+            "password" if allowed && settings.password_authentication => {
+                assert!(reader.read_bool()? == false);
+                let pass = reader.read_utf8()?;
+                pass == "hunter2" &&
+                    (!root || settings.permit_root_login.permits_password())
+            }
+            "publickey" if allowed && settings.pubkey_authentication => {
+                let has_signature = reader.read_bool()?;
+                let algorithm = reader.read_utf8()?;
+                let key_blob = reader.read_string()?;
+
+                let key = self.authorized_key(&name, &algorithm, &key_blob)
+                    .filter(|&(_, ref options)| {
+                        !root ||
+                            settings.permit_root_login.permits_key(
+                                options.force_command.is_some(),
+                            )
+                    });
+
+                match key
+                {
+                    None => false,
+                    Some(_) if !has_signature => {
+                        // The client only asks whether the key is acceptable
+                        let mut res =
+                            Packet::new(MessageType::UserAuth(USERAUTH_PK_OK));
+                        res.write_string(&algorithm)?;
+                        res.write_bytes(&key_blob)?;
+                        return Ok(Some(res));
+                    }
+                    Some((key, options)) => {
+                        let signature = reader.read_string()?;
+                        let verified = self.verify_user_signature(
+                            &*key,
+                            &name,
+                            &service,
+                            &algorithm,
+                            &key_blob,
+                            &signature,
+                        )?;
+
+                        if verified {
+                            info!(
+                                "Accepted publickey for {} with {} {}",
+                                name,
+                                algorithm,
+                                key.fingerprint(FingerprintHash::Sha256)
+                            );
+                            self.auth_options = options;
+                        }
+                        verified
+                    }
+                }
+            }
+            _ => false,
+        };
 
-        if success {
-            Ok(Some(Packet::new(MessageType::UserAuthSuccess)))
+        let response = if success {
+            self.settings = settings.clone();
+            Packet::new(MessageType::UserAuthSuccess)
         }
         else {
+            // The "none" method only asks for the available methods
+            if method != "none" {
+                self.auth_failures += 1;
+            }
+
+            if self.auth_failures >= settings.max_auth_tries {
+                let description = format!(
+                    "Too many authentication failures for {}",
+                    name
+                );
+                return Ok(Some(self.disconnect(
+                    DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE,
+                    &description,
+                )?));
+            }
+
+            let mut methods = Vec::new();
+            if settings.pubkey_authentication {
+                methods.push("publickey");
+            }
+            if settings.password_authentication {
+                methods.push("password");
+            }
+
             let mut res = Packet::new(MessageType::UserAuthFailure);
-            res.write_string("password")?;
+            res.write_string(&methods.join(","))?;
             res.write_bool(false)?;
+            res
+        };
 
-            Ok(Some(res))
+        // The banner is sent once, before the answer to the first request
+        if !self.banner_sent {
+            self.banner_sent = true;
+            if let Some(banner) = self.banner_packet(&settings)? {
+                self.tx_queue.push_front(response);
+                return Ok(Some(banner));
+            }
         }
+
+        Ok(Some(response))
+    }
+
+    /// The server settings for this connection, with the Match blocks for
+    /// the user applied if known
+    fn settings_for(&self, user: Option<&str>) -> Settings {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => {
+                let context = MatchContext {
+                    user: user,
+                    address: self.peer_addr,
+                    local_address: self.local_addr,
+                };
+                config.settings.matched(&config.matches, &context)
+            }
+            ConnectionType::Client(_) => Settings::default(),
+        }
+    }
+
+    /// The USERAUTH_BANNER message with the banner file, see RFC 4252,
+    /// section 5.4
+    fn banner_packet(&self, settings: &Settings) -> Result<Option<Packet>> {
+        let path = match settings.banner
+        {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+
+        let mut message = String::new();
+        let result = File::open(path).and_then(
+            |mut file| file.read_to_string(&mut message),
+        );
+
+        if let Err(err) = result {
+            warn!("Failed to read banner {}: {}", path, err);
+            return Ok(None);
+        }
+
+        let mut packet = Packet::new(MessageType::UserAuthBanner);
+        packet.write_string(&message)?;
+        packet.write_string("")?;
+        Ok(Some(packet))
+    }
+
+    /// Creates a DISCONNECT message, after which the connection is closed
+    fn disconnect(&mut self, reason: u32, description: &str)
+        -> Result<Packet> {
+        info!("Disconnecting: {}", description);
+
+        let mut packet = Packet::new(MessageType::Disconnect);
+        packet.write_uint32(reason)?;
+        packet.write_string(description)?;
+        packet.write_string("")?;
+
+        self.closing = true;
+        Ok(packet)
+    }
+
+    /// Asks an idle client for a reply, or disconnects when it did not
+    /// answer the previous requests, see ClientAliveInterval in OpenSSH
+    fn client_alive(&mut self) -> Result<Packet> {
+        self.client_alive_missed += 1;
+
+        if self.client_alive_missed > self.settings.client_alive_count_max {
+            return self.disconnect(
+                DISCONNECT_BY_APPLICATION,
+                "Timeout, client not responding",
+            );
+        }
+
+        let mut packet = Packet::new(MessageType::GlobalRequest);
+        packet.write_string(KEEPALIVE)?;
+        packet.write_bool(true)?;
+        Ok(packet)
+    }
+
+    /// Looks up the key of a publickey authentication request. Plain keys
+    /// have to be listed in the authorized keys of the server, certificates
+    /// have to be signed by a trusted user CA.
+    fn authorized_key(&self, user: &str, algorithm: &str, key_blob: &[u8])
+        -> Option<(Box<KeyPair>, AuthOptions)> {
+        let config = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config,
+            _ => return None,
+        };
+
+        let certified = public_key::certified_algorithm(algorithm);
+        if let Some(key_algorithm) = certified {
+            let cert = Certificate::read(key_blob).ok()?;
+            let signature_algorithms = cert.key.system().signature_algorithms;
+
+            if !signature_algorithms.contains(&&*key_algorithm) ||
+                !cert.is_signed_by(&config.trusted_user_ca_keys)
+            {
+                debug!("Certificate {:?} is not trusted", cert.key_id);
+                return None;
+            }
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            if let Err(err) =
+                cert.validate(CertificateType::User, user, now, self.peer_addr)
+            {
+                debug!("Rejecting certificate {:?}: {}", cert.key_id, err);
+                return None;
+            }
+
+            let options = AuthOptions {
+                force_command: cert.force_command().map(|c| c.to_string()),
+                permit_pty: cert.has_extension("permit-pty"),
+                permit_port_forwarding: cert.has_extension(
+                    "permit-port-forwarding",
+                ),
+            };
+
+            return Some((cert.key, options));
+        }
+
+        let key = public_key::read_public_key(&mut &key_blob[..]).ok()?;
+
+        if !key.system().signature_algorithms.contains(&algorithm) {
+            return None;
+        }
+
+        let authorized = config.authorized_keys.iter().any(|authorized| {
+            let mut blob = Vec::new();
+            authorized.write_public(&mut blob).is_ok() && blob == key_blob
+        });
+
+        if authorized {
+            Some((key, AuthOptions::default()))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Verifies the signature of a publickey authentication request, see
+    /// RFC 4252, section 7
+    fn verify_user_signature(
+        &self,
+        key: &KeyPair,
+        name: &str,
+        service: &str,
+        algorithm: &str,
+        key_blob: &[u8],
+        signature: &[u8],
+    ) -> Result<bool> {
+        let session_id = self.session_id.as_ref().ok_or(
+            ConnectionError::ProtocolError,
+        )?;
+
+        // Certificates are signed with the algorithm of the certified key
+        let signature_algorithm = public_key::certified_algorithm(algorithm)
+            .unwrap_or(algorithm.to_string());
+
+        if (&signature[..]).read_utf8()? != signature_algorithm {
+            debug!("Signature does not match algorithm {}", algorithm);
+            return Ok(false);
+        }
+
+        let mut request = Packet::new(MessageType::UserAuthRequest);
+        request.write_string(name)?;
+        request.write_string(service)?;
+        request.write_string("publickey")?;
+        request.write_bool(true)?;
+        request.write_string(algorithm)?;
+        request.write_bytes(key_blob)?;
+
+        let mut data = Vec::new();
+        data.write_bytes(session_id)?;
+        data.write_raw_bytes(request.data())?;
+
+        if key.verify(&data, signature) != Ok(true) {
+            return Ok(false);
+        }
+
+        Ok(self.check_security_key(signature))
+    }
+
+    /// Enforces the user presence and verification flags of security key
+    /// signatures as required by the server config
+    fn check_security_key(&self, signature: &[u8]) -> bool {
+        let info = match public_key::sk_signature_info(signature)
+        {
+            Some(info) => info,
+            None => return true,
+        };
+
+        debug!(
+            "Security key flags {:#04x}, counter {}",
+            info.flags,
+            info.counter
+        );
+
+        let (touch_required, verify_required) = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => {
+                (config.sk_touch_required, config.sk_verify_required)
+            }
+            _ => return false,
+        };
+
+        if touch_required && info.flags & public_key::SK_USER_PRESENCE == 0 {
+            debug!("Security key signature without user presence");
+            return false;
+        }
+
+        if verify_required &&
+            info.flags & public_key::SK_USER_VERIFICATION == 0
+        {
+            debug!("Security key signature without user verification");
+            return false;
+        }
+
+        true
     }
 
     fn channel_open(&mut self, packet: Packet) -> Result<Option<Packet>> {
@@ -289,6 +1282,35 @@ impl<'a> Connection {
         let window_size = reader.read_uint32()?;
         let max_packet_size = reader.read_uint32()?;
 
+        if channel_type == "direct-tcpip" &&
+            !self.auth_options.permit_port_forwarding
+        {
+            debug!("Port forwarding is not permitted");
+
+            let mut res = Packet::new(MessageType::ChannelOpenFailure);
+            res.write_uint32(peer_id)?;
+            res.write_uint32(OPEN_ADMINISTRATIVELY_PROHIBITED)?;
+            res.write_string("port forwarding not permitted")?;
+            res.write_string("")?;
+            return Ok(Some(res));
+        }
+
+        // Only reached after authentication, so the limit applies to the
+        // settings of the authenticated user
+        if channel_type == "session" {
+            if self.sessions >= self.settings.max_sessions {
+                debug!("Too many sessions, MaxSessions is reached");
+
+                let mut res = Packet::new(MessageType::ChannelOpenFailure);
+                res.write_uint32(peer_id)?;
+                res.write_uint32(OPEN_ADMINISTRATIVELY_PROHIBITED)?;
+                res.write_string("too many sessions")?;
+                res.write_string("")?;
+                return Ok(Some(res));
+            }
+            self.sessions += 1;
+        }
+
         let id = if let Some((id, chan)) = self.channels.iter().next_back() {
             id + 1
         }
@@ -317,7 +1339,7 @@ impl<'a> Connection {
         let name = reader.read_utf8()?;
         let want_reply = reader.read_bool()?;
 
-
+        let mut refused = false;
         let request = match &*name
         {
             "pty-req" => Some(ChannelRequest::Pty {
@@ -329,20 +1351,53 @@ impl<'a> Connection {
                 modes: reader.read_string()?,
             }),
             "shell" => Some(ChannelRequest::Shell),
+            "exec" => Some(ChannelRequest::Exec(reader.read_utf8()?)),
+            "subsystem" => {
+                let subsystem = reader.read_utf8()?;
+                let command = self.subsystem_command(&subsystem);
+                if command.is_none() {
+                    debug!("Unknown subsystem {:?}", subsystem);
+                    refused = true;
+                }
+                command.map(ChannelRequest::Exec)
+            }
             _ => None,
         };
 
+        // Apply the restrictions of the certificate used to authenticate
+        let request = match (request, &self.auth_options.force_command)
+        {
+            (Some(ChannelRequest::Pty { .. }), _)
+                if !self.auth_options.permit_pty => {
+                debug!("PTY allocation is not permitted");
+                refused = true;
+                None
+            }
+            (Some(ChannelRequest::Shell), &Some(ref command)) |
+            (Some(ChannelRequest::Exec(_)), &Some(ref command)) => {
+                debug!("Running forced command {:?}", command);
+                Some(ChannelRequest::Exec(command.clone()))
+            }
+            (request, _) => request,
+        };
 
         if let Some(request) = request {
             let channel = self.channels.get_mut(&channel_id).unwrap();
             channel.request(request);
         }
-        else {
+        else if !refused {
             warn!("Unkown channel request {}", name);
         }
 
         if want_reply {
-            let mut res = Packet::new(MessageType::ChannelSuccess);
+            let msg_type = if refused {
+                MessageType::ChannelFailure
+            }
+            else {
+                MessageType::ChannelSuccess
+            };
+
+            let mut res = Packet::new(msg_type);
             res.write_uint32(0)?;
             Ok(Some(res))
         }
@@ -351,6 +1406,21 @@ impl<'a> Connection {
         }
     }
 
+    /// The command of a subsystem in the server config
+    fn subsystem_command(&self, name: &str) -> Option<String> {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => {
+                config
+                    .subsystems
+                    .iter()
+                    .find(|&&(ref subsystem, _)| subsystem == name)
+                    .map(|&(_, ref command)| command.clone())
+            }
+            ConnectionType::Client(_) => None,
+        }
+    }
+
     fn channel_data(&mut self, packet: Packet) -> Result<Option<Packet>> {
         let mut reader = packet.reader();
         let channel_id = reader.read_uint32()?;
@@ -365,7 +1435,7 @@ impl<'a> Connection {
     fn kex_init(&mut self, packet: Packet) -> Result<Option<Packet>> {
         use algorithm::*;
 
-        let (kex_algo, srv_host_key_algo, enc_algo, mac_algo, comp_algo) = {
+        let (kex_algo, algorithms, kex_algos) = {
             let mut reader = packet.reader();
             let _ = reader.read_bytes(16)?; // Cookie. Throw it away.
 
@@ -384,23 +1454,104 @@ impl<'a> Connection {
             let comp_algos_s2c = reader
                 .read_enum_list::<CompressionAlgorithm>()?;
 
+            let encryption = self.encryption_algorithms();
+            let enc_c2s = self.negotiate(&encryption, enc_algos_c2s.as_slice())?;
+            let enc_s2c = self.negotiate(&encryption, enc_algos_s2c.as_slice())?;
+
+            let host_keys = self.host_key_algorithms();
+            let host_key =
+                self.negotiate(&host_keys, srv_host_key_algos.as_slice())?;
+
+            let algorithms = Algorithms {
+                host_key: host_key,
+                encryption: self.directed(enc_c2s, enc_s2c),
+                mac: self.directed(
+                    self.negotiate_mac(enc_c2s, mac_algos_c2s.as_slice())?,
+                    self.negotiate_mac(enc_s2c, mac_algos_s2c.as_slice())?,
+                ),
+                compression: self.directed(
+                    self.negotiate(COMPRESSION, comp_algos_c2s.as_slice())?,
+                    self.negotiate(COMPRESSION, comp_algos_s2c.as_slice())?,
+                ),
+            };
+
             (
-                negotiate(KEY_EXCHANGE, kex_algos.as_slice())?,
-                negotiate(HOST_KEY, srv_host_key_algos.as_slice())?,
-                negotiate(ENCRYPTION, enc_algos_s2c.as_slice())?,
-                negotiate(MAC, mac_algos_s2c.as_slice())?,
-                negotiate(COMPRESSION, comp_algos_s2c.as_slice())?,
+                self.negotiate(&self.kex_algorithms(), kex_algos.as_slice())?,
+                algorithms,
+                kex_algos,
             )
         };
 
+        // Extensions are only negotiated in the initial KEXINIT. With strict
+        // key exchange, the KEXINIT also has to be the first packet.
+        if self.session_id.is_none() {
+            let strict_marker = if self.is_server() {
+                KeyExchangeAlgorithm::KEX_STRICT_C
+            }
+            else {
+                KeyExchangeAlgorithm::KEX_STRICT_S
+            };
+
+            self.strict_kex = kex_algos.contains(&strict_marker);
+            self.ext_info = self.is_server() &&
+                kex_algos.contains(&KeyExchangeAlgorithm::EXT_INFO_C);
+
+            if self.strict_kex && self.seq.0 != 1 {
+                error!("Strict key exchange violation: KEXINIT was not first");
+                return Err(ConnectionError::ProtocolError);
+            }
+        }
+
+        debug!("Strict key exchange: {}", self.strict_kex);
+
         debug!("Negotiated Kex Algorithm: {:?}", kex_algo);
-        debug!("Negotiated Host Key Algorithm: {:?}", srv_host_key_algo);
-        debug!("Negotiated Encryption Algorithm: {:?}", enc_algo);
-        debug!("Negotiated Mac Algorithm: {:?}", mac_algo);
-        debug!("Negotiated Comp Algorithm: {:?}", comp_algo);
+        debug!(
+            "Negotiated Host Key Algorithm: {:?}",
+            algorithms.host_key
+        );
+        debug!(
+            "Negotiated Encryption Algorithms (in, out): {:?}",
+            algorithms.encryption
+        );
+        debug!("Negotiated Mac Algorithms (in, out): {:?}", algorithms.mac);
+        debug!(
+            "Negotiated Comp Algorithms (in, out): {:?}",
+            algorithms.compression
+        );
+
+        self.algorithms = Some(algorithms);
 
         // Save payload for hash generation
-        self.hash_data.client_kexinit = Some(packet.payload());
+        if self.is_server() {
+            self.hash_data.client_kexinit = Some(packet.payload());
+        }
+        else {
+            self.hash_data.server_kexinit = Some(packet.payload());
+        }
+
+        // Answer with our own KEXINIT if the peer started the key exchange
+        let response = if !self.kex_init_sent {
+            Some(self.start_kex()?)
+        }
+        else {
+            None
+        };
+
+        let mut kex = kex_algo.instance().ok_or(
+            ConnectionError::KeyExchangeError,
+        )?;
+
+        if let Some(packet) = kex.initiate(self) {
+            self.tx_queue.push_back(packet);
+        }
+
+        self.key_exchange = Some(kex);
+
+        Ok(response)
+    }
+
+    fn kex_init_packet(&mut self) -> Result<Packet> {
+        use algorithm::*;
 
         // Create a random 16 byte cookie
         use rand::Rng;
@@ -409,12 +1560,26 @@ impl<'a> Connection {
 
         let mut packet = Packet::new(MessageType::KexInit);
         packet.write_raw_bytes(cookie.as_slice())?;
-        packet.write_list(KEY_EXCHANGE)?;
-        packet.write_list(HOST_KEY)?;
-        packet.write_list(ENCRYPTION)?;
-        packet.write_list(ENCRYPTION)?;
-        packet.write_list(MAC)?;
-        packet.write_list(MAC)?;
+        // Offer extensions and strict key exchange in the initial KEXINIT
+        let mut kex_algos = self.kex_algorithms();
+        if self.session_id.is_none() {
+            if self.is_server() {
+                kex_algos.push(KeyExchangeAlgorithm::KEX_STRICT_S);
+            }
+            else {
+                kex_algos.push(KeyExchangeAlgorithm::EXT_INFO_C);
+                kex_algos.push(KeyExchangeAlgorithm::KEX_STRICT_C);
+            }
+        }
+
+        packet.write_list(&kex_algos)?;
+        packet.write_list(&self.host_key_algorithms())?;
+        let encryption = self.encryption_algorithms();
+        packet.write_list(&encryption)?;
+        packet.write_list(&encryption)?;
+        let macs = self.mac_algorithms();
+        packet.write_list(&macs)?;
+        packet.write_list(&macs)?;
         packet.write_list(COMPRESSION)?;
         packet.write_list(COMPRESSION)?;
         packet.write_string("")?;
@@ -422,39 +1587,1147 @@ impl<'a> Connection {
         packet.write_bool(false)?;
         packet.write_uint32(0)?;
 
-        self.state = ConnectionState::KeyExchange;
-        self.key_exchange = kex_algo.instance();
-
         // Save payload for hash generation
-        self.hash_data.server_kexinit = Some(packet.data().to_vec());
+        if self.is_server() {
+            self.hash_data.server_kexinit = Some(packet.data().to_vec());
+        }
+        else {
+            self.hash_data.client_kexinit = Some(packet.data().to_vec());
+        }
+
+        Ok(packet)
+    }
+
+    /// Key exchange algorithms we offer, a server those of its config
+    fn kex_algorithms(&self) -> Vec<KeyExchangeAlgorithm> {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => {
+                config.kex_algorithms.clone()
+            }
+            ConnectionType::Client(_) => algorithm::KEY_EXCHANGE.to_vec(),
+        }
+    }
+
+    /// Encryption algorithms we offer, a server those of its config
+    fn encryption_algorithms(&self) -> Vec<EncryptionAlgorithm> {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config.ciphers.clone(),
+            ConnectionType::Client(_) => algorithm::ENCRYPTION.to_vec(),
+        }
+    }
+
+    /// MAC algorithms we offer, a server those of its config
+    fn mac_algorithms(&self) -> Vec<algorithm::MacAlgorithm> {
+        match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config.macs.clone(),
+            ConnectionType::Client(_) => algorithm::MAC.to_vec(),
+        }
+    }
+
+    /// Host key algorithms we offer. A server offers the algorithms of its
+    /// host keys, and certificate algorithms for keys with a certificate.
+    fn host_key_algorithms(&self) -> Vec<PublicKeyAlgorithm> {
+        let config = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config,
+            ConnectionType::Client(ref config) => {
+                // Like OpenSSH, prefer certificates if a CA is known
+                let mut algorithms = Vec::new();
+                if config.known_hosts.has_cert_authority(
+                    &config.host,
+                    config.port,
+                )
+                {
+                    algorithms.extend_from_slice(algorithm::HOST_CERTIFICATE);
+                }
+                algorithms.extend_from_slice(algorithm::HOST_KEY);
+                return algorithms;
+            }
+        };
+
+        let mut algorithms: Vec<PublicKeyAlgorithm> =
+            algorithm::HOST_CERTIFICATE
+                .iter()
+                .cloned()
+                .filter(|&a| {
+                    config.host_certificate(a).is_some() &&
+                        config.host_key(a).is_some()
+                })
+                .collect();
+
+        algorithms.extend(
+            algorithm::HOST_KEY.iter().cloned().filter(
+                |&a| config.host_key(a).is_some(),
+            ),
+        );
+        algorithms
+    }
+
+    /// The host key algorithm negotiated in the last KEXINIT
+    pub fn host_key_algorithm(&self) -> Option<PublicKeyAlgorithm> {
+        self.algorithms.as_ref().map(|a| a.host_key)
+    }
+
+    /// Checks the server host key, which may be a certificate for the key,
+    /// against the known hosts, see StrictHostKeyChecking in ssh_config(5)
+    fn check_host_key(&self, blob: &[u8]) -> Result<()> {
+        let config = match self.conn_type
+        {
+            ConnectionType::Client(ref config) => config,
+            ConnectionType::Server(_) => return Ok(()),
+        };
+
+        // The host key must not change in a key re-exchange
+        if let Some(ref host_key) = self.server_host_key {
+            if host_key[..] != blob[..] {
+                error!("Host key changed during key re-exchange");
+                return Err(ConnectionError::HostKeyError);
+            }
+            return Ok(());
+        }
+
+        let key = match Certificate::read(blob)
+        {
+            Ok(cert) => cert.key,
+            Err(_) => public_key::read_public_key(&mut &blob[..])?,
+        };
+
+        let name = known_hosts::host_name(&config.host, config.port);
+        let key_type = key.system().name.to_uppercase();
+        let fingerprint = key.fingerprint(FingerprintHash::Sha256);
+        let checking = config.strict_host_key_checking;
+
+        match config.known_hosts.check(&config.host, config.port, blob)
+        {
+            HostKeyStatus::Known => return Ok(()),
+            HostKeyStatus::Revoked => {
+                error!("{} host key for {} is revoked", key_type, name);
+                return Err(ConnectionError::HostKeyError);
+            }
+            HostKeyStatus::Changed => {
+                error!(
+                    "REMOTE HOST IDENTIFICATION HAS CHANGED! The {} host key \
+                     for {} is now {}",
+                    key_type,
+                    name,
+                    fingerprint
+                );
+
+                if checking != StrictHostKeyChecking::No {
+                    return Err(ConnectionError::HostKeyError);
+                }
+                return Ok(());
+            }
+            HostKeyStatus::Unknown => {}
+        }
+
+        let accepted = match checking
+        {
+            StrictHostKeyChecking::Yes => false,
+            StrictHostKeyChecking::AcceptNew |
+            StrictHostKeyChecking::No => true,
+            StrictHostKeyChecking::Ask => {
+                let question = format!(
+                    "The authenticity of host '{}' can't be established.\n\
+                     {} key fingerprint is {}.\n\
+                     Are you sure you want to continue connecting (yes/no)? ",
+                    name,
+                    key_type,
+                    fingerprint
+                );
+                config.prompt.map_or(false, |prompt| prompt(&question))
+            }
+        };
+
+        if !accepted {
+            error!("No {} host key is known for {}", key_type, name);
+            return Err(ConnectionError::HostKeyError);
+        }
+
+        self.add_known_host_key(&*key);
+        Ok(())
+    }
+
+    /// Adds a host key of the server to the known_hosts file
+    fn add_known_host_key(&self, key: &KeyPair) {
+        let config = match self.conn_type
+        {
+            ConnectionType::Client(ref config) => config,
+            ConnectionType::Server(_) => return,
+        };
+
+        let path = match config.known_hosts_file
+        {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let name = known_hosts::host_name(&config.host, config.port);
+        match known_hosts::add_host_key(path, &config.host, config.port, key)
+        {
+            Ok(()) => {
+                warn!(
+                    "Permanently added '{}' ({}) to the list of known hosts",
+                    name,
+                    key.system().name.to_uppercase()
+                );
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to add the host key to {}: {}",
+                    path.display(),
+                    err
+                )
+            }
+        }
+    }
+
+    fn ext_info_packet(&self) -> io::Result<Packet> {
+        let mut packet = Packet::new(MessageType::ExtInfo);
+        packet.write_uint32(1)?;
+        packet.write_string("server-sig-algs")?;
+        // Every algorithm that publickey authentication accepts
+        packet.write_list(&public_key::signature_algorithms())?;
+        Ok(packet)
+    }
+
+    fn ext_info(&mut self, packet: Packet) -> Result<Option<Packet>> {
+        let mut reader = packet.reader();
+        let count = reader.read_uint32()?;
+
+        for _ in 0..count {
+            let name = reader.read_utf8()?;
+
+            if name == "server-sig-algs" {
+                let algorithms = reader.read_enum_list()?;
+                debug!("Server signature algorithms: {:?}", algorithms);
+                self.server_sig_algs = Some(algorithms);
+            }
+            else {
+                let _ = reader.read_string()?;
+                debug!("Ignoring unknown extension {:?}", name);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Handles global requests, see RFC 4254, section 4
+    fn global_request(&mut self, packet: Packet) -> Result<Option<Packet>> {
+        let mut reader = packet.reader();
+        let name = reader.read_utf8()?;
+        let want_reply = reader.read_bool()?;
+
+        let response = match (&*name, self.is_server())
+        {
+            (HOSTKEYS, false) => {
+                self.request_host_key_proof(read_blobs(&mut reader)?)?
+            }
+            (HOSTKEYS_PROVE, true) => {
+                self.prove_host_keys(&read_blobs(&mut reader)?)?
+            }
+            _ => {
+                debug!("Ignoring global request {:?}", name);
+                None
+            }
+        };
+
+        match response
+        {
+            Some(packet) => Ok(Some(packet)),
+            None if want_reply => {
+                Ok(Some(Packet::new(MessageType::RequestFailure)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Announces our host keys after authentication, so that clients can
+    /// learn about new keys before the old ones are removed
+    fn hostkeys_packet(&self) -> io::Result<Option<Packet>> {
+        let config = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config,
+            _ => return Ok(None),
+        };
+
+        let mut packet = Packet::new(MessageType::GlobalRequest);
+        packet.write_string(HOSTKEYS)?;
+        packet.write_bool(false)?;
+
+        for key in config.host_keys.iter() {
+            let mut blob = Vec::new();
+            key.write_public(&mut blob)?;
+            packet.write_bytes(&blob)?;
+        }
 
         Ok(Some(packet))
     }
 
+    /// Asks the server to prove that it owns the host keys it announced,
+    /// except for the one it already signed the key exchange with
+    fn request_host_key_proof(&mut self, blobs: Vec<Vec<u8>>)
+        -> Result<Option<Packet>> {
+        let blobs: Vec<Vec<u8>> = blobs
+            .into_iter()
+            .filter(|blob| Some(blob) != self.server_host_key.as_ref())
+            .filter(|blob| public_key::read_public_key(&mut &blob[..]).is_ok())
+            .collect();
+
+        if blobs.is_empty() || !self.unproven_host_keys.is_empty() {
+            return Ok(None);
+        }
+
+        let mut request = Packet::new(MessageType::GlobalRequest);
+        request.write_string(HOSTKEYS_PROVE)?;
+        request.write_bool(true)?;
+        for blob in blobs.iter() {
+            request.write_bytes(blob)?;
+        }
+
+        self.unproven_host_keys = blobs;
+        Ok(Some(request))
+    }
+
+    /// Signs the requested host keys. Like OpenSSH, RSA keys sign with the
+    /// negotiated host key algorithm if it is an RSA algorithm as well.
+    fn prove_host_keys(&self, blobs: &[Vec<u8>]) -> Result<Option<Packet>> {
+        let config = match self.conn_type
+        {
+            ConnectionType::Server(ref config) => config,
+            _ => return Ok(None),
+        };
+
+        let session_id = self.session_id.as_ref().ok_or(
+            ConnectionError::ProtocolError,
+        )?;
+        let negotiated = self.host_key_algorithm().map(
+            |a| a.certified().unwrap_or(a),
+        );
+
+        let mut response = Packet::new(MessageType::RequestSuccess);
+
+        for blob in blobs {
+            let key = config.host_keys.iter().find(|key| {
+                let mut key_blob = Vec::new();
+                key.write_public(&mut key_blob).is_ok() && key_blob == *blob
+            });
+
+            let key = match key
+            {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+
+            let algorithm = match negotiated
+            {
+                Some(a) if a.system().id == key.system().id => a.to_string(),
+                _ => key.system().signature_algorithms[0].to_string(),
+            };
+
+            let data = host_key_proof(session_id, blob)?;
+            match key.sign_with(&data, &algorithm)
+            {
+                Ok(signature) => response.write_bytes(&signature)?,
+                Err(_) => return Ok(None),
+            }
+        }
+
+        Ok(Some(response))
+    }
+
+    /// Checks the signatures of the host keys we asked the server to prove
+    fn global_request_reply(&mut self, packet: Packet)
+        -> Result<Option<Packet>> {
+        let blobs = mem::replace(&mut self.unproven_host_keys, Vec::new());
+        if blobs.is_empty() {
+            return Ok(None);
+        }
+
+        if packet.msg_type() == MessageType::RequestFailure {
+            debug!("Server refused to prove its host keys");
+            return Ok(None);
+        }
+
+        let session_id = self.session_id.clone().ok_or(
+            ConnectionError::ProtocolError,
+        )?;
+
+        let mut reader = packet.reader();
+        let mut keys = Vec::new();
+
+        for blob in blobs {
+            let key = public_key::read_public_key(&mut &blob[..])?;
+            let signature = reader.read_string()?;
+            let data = host_key_proof(&session_id, &blob)?;
+
+            if key.verify(&data, &signature) != Ok(true) {
+                error!(
+                    "Server failed to prove ownership of host key {}",
+                    key.fingerprint(FingerprintHash::Sha256)
+                );
+                return Ok(None);
+            }
+
+            keys.push(key);
+        }
+
+        for key in keys.iter() {
+            info!(
+                "Learned host key {} {}",
+                key.system().id,
+                key.fingerprint(FingerprintHash::Sha256)
+            );
+        }
+
+        // Like UpdateHostKeys in OpenSSH, new keys are only added if the
+        // host was verified with a known plain key
+        if let ConnectionType::Client(ref config) = self.conn_type {
+            let status = |blob: &[u8]| {
+                config.known_hosts.check(&config.host, config.port, blob)
+            };
+            let verified = self.server_host_key.as_ref().map_or(
+                false,
+                |host_key| {
+                    Certificate::read(host_key).is_err() &&
+                        status(host_key) == HostKeyStatus::Known
+                },
+            );
+
+            for key in keys.iter() {
+                let mut blob = Vec::new();
+                key.write_public(&mut blob)?;
+                if verified && status(&blob) == HostKeyStatus::Unknown {
+                    self.add_known_host_key(&**key);
+                }
+            }
+        }
+
+        self.learned_host_keys.extend(keys);
+        Ok(None)
+    }
+
+    /// MACs are only negotiated for ciphers without integrity protection
+    fn negotiate_mac(
+        &self,
+        encryption: EncryptionAlgorithm,
+        theirs: &[algorithm::MacAlgorithm],
+    ) -> Result<algorithm::MacAlgorithm> {
+        if encryption.is_aead() {
+            Ok(algorithm::MacAlgorithm::None)
+        }
+        else {
+            self.negotiate(&self.mac_algorithms(), theirs)
+        }
+    }
+
+    /// Picks the first algorithm of the client that the server supports
+    fn negotiate<A: PartialEq + Copy>(&self, ours: &[A], theirs: &[A])
+        -> Result<A> {
+        use algorithm::negotiate;
+
+        if self.is_server() {
+            negotiate(ours, theirs)
+        }
+        else {
+            negotiate(theirs, ours)
+        }
+    }
+
     fn key_exchange(&mut self, packet: Packet) -> Result<Option<Packet>> {
         let mut kex = self.key_exchange.take().ok_or(
             ConnectionError::KeyExchangeError,
         )?;
 
-        let result = match kex.process(self, packet)
-        {
-            KexResult::Done(packet) => {
-                self.state = ConnectionState::Established;
+        let result = kex.process(self, packet);
 
-                if self.session_id.is_none() {
-                    self.session_id = kex.exchange_hash().map(|h| h.to_vec());
-                }
+        // The session id is the exchange hash of the first key exchange
+        if let KexResult::Done(_) = result {
+            if let Some(host_key) = kex.host_key() {
+                self.check_host_key(host_key)?;
+            }
 
-                self.tx_queue.push_back(Packet::new(MessageType::NewKeys));
+            if self.session_id.is_none() {
+                self.session_id = kex.exchange_hash().map(|h| h.to_vec());
+            }
+            self.server_host_key = kex.host_key().map(|k| k.to_vec());
+        }
+
+        self.key_exchange = Some(kex);
 
-                Ok(Some(packet))
+        match result
+        {
+            KexResult::Done(packet) => {
+                self.derive_keys()?;
+                self.tx_queue.push_back(Packet::new(MessageType::NewKeys));
+                Ok(packet)
             }
             KexResult::Ok(packet) => Ok(Some(packet)),
             KexResult::Error => Err(ConnectionError::KeyExchangeError),
+        }
+    }
+}
+
+/// Reads the key blobs of a host key announcement or proof request
+fn read_blobs<R: BufRead>(reader: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    let mut blobs = Vec::new();
+    while !reader.fill_buf()?.is_empty() {
+        blobs.push(reader.read_string()?);
+    }
+    Ok(blobs)
+}
+
+/// The data that is signed to prove the ownership of a host key
+fn host_key_proof(session_id: &[u8], blob: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    data.write_string(HOSTKEYS_PROVE)?;
+    data.write_bytes(session_id)?;
+    data.write_bytes(blob)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::*;
+    use client::ClientConfig;
+    use encryption::{AesCtr, AesGcm, ChaCha20Poly1305};
+    use public_key::{ECDSA_NISTP256, ED25519, RSA};
+
+    // Two IGNORE packets sealed with aes256-gcm@openssh.com, using the key
+    // 00 01 .. 1f and the IV a0 a1 .. ab
+    static GCM_PACKETS: &[&str] = &[
+        "00000020f31a7c2d45ce70da060affd3077ac0de70ac591092b7426c9c0e26867fab\
+         75012043f664854ef59b14834bf18d676659",
+        "00000020cece4b1a2bf16e5dd0a2658440c70d8cb1f7172f77327684aa08a00f5660\
+         d814370fa56fc020f35ad1443c7184f38d63",
+    ];
+
+    // The same packets sealed with chacha20-poly1305@openssh.com, using the
+    // key 00 01 .. 3f. The length is encrypted with the second half.
+    static CHACHA_PACKETS: &[&str] = &[
+        "94450e790dba4231ade3d4b4770e2461af434e27f8b1f3f5e1ad5b5cecf8fc122a35\
+         755c198fe2542e47ec1821e7b6a7f52b0460",
+        "c922a7857c5e7cd9310fca1f491e062fb314171210f13f40ceb1fc75453ab52829ee\
+         9f0ad0208cc55112854e2c1072cd87ff39ad",
+    ];
+
+    // The same packets with aes128-ctr and hmac-sha2-256-etm@openssh.com,
+    // using the key 00 01 .. 0f, the IV f0 f1 .. ff and the MAC key 20 21 ..
+    // The MAC covers the sequence number, the length and the ciphertext.
+    static ETM_PACKETS: &[&str] = &[
+        "0000002073a5c7e83457432df33ea6073316adadb281d700b79e3cada4ad73bb6e9c\
+         1fea71f62c8fe7267796bf681761838a379eaada4946336f8d033e556e37a542235c",
+        "00000020c77392567c5e99f89feef3594f92557170d8665a3cbf5847576d03184f60\
+         379a11c7f83ad55266a369a4acaf5595b1cdf540af49161c811dd9e7a7fe5db70411",
+    ];
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn gcm_cipher() -> Option<Cipher> {
+        let key: Vec<u8> = (0..32).collect();
+        let iv: Vec<u8> = (0xa0..0xac).collect();
+        Some(Cipher::Aead(Box::new(AesGcm::new(&key, &iv))))
+    }
+
+    fn chacha_cipher() -> Option<Cipher> {
+        let key: Vec<u8> = (0..64).collect();
+        Some(Cipher::Aead(Box::new(ChaCha20Poly1305::new(&key))))
+    }
+
+    fn etm_cipher(mac: algorithm::MacAlgorithm) -> Option<Cipher> {
+        let key: Vec<u8> = (0..16).collect();
+        let iv: Vec<u8> = (0xf0..0x100).map(|i| i as u8).collect();
+        let mac_key: Vec<u8> = (0x20..0x20 + mac.key_len() as u8).collect();
+        Some(Cipher::Etm(
+            Box::new(AesCtr::new(&key, &iv)),
+            mac.instance(&mac_key).unwrap(),
+        ))
+    }
+
+    fn hmac_sha2_256_etm() -> Option<Cipher> {
+        etm_cipher(algorithm::MacAlgorithm::HMAC_SHA2_256_ETM)
+    }
+
+    /// Fails if a packet is decrypted at all
+    struct NoDecryption;
+
+    impl Encryption for NoDecryption {
+        fn block_size(&self) -> usize {
+            16
+        }
+
+        fn encrypt(&mut self, _: &[u8], _: &mut [u8]) {
+            unreachable!();
+        }
+
+        fn decrypt(&mut self, _: &[u8], _: &mut [u8]) {
+            panic!("packet was decrypted before its MAC was verified");
+        }
+    }
+
+    fn server_config(host_keys: Vec<Box<KeyPair>>) -> ServerConfig {
+        ServerConfig {
+            host_keys: host_keys,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn cipher_connection(cipher: fn() -> Option<Cipher>) -> Connection {
+        let config = server_config(vec![(ED25519.generate_key_pair)(None)]);
+        let mut conn = Connection::new(ConnectionType::Server(Arc::new(config)));
+        conn.cipher = (cipher(), cipher());
+        conn
+    }
+
+    fn server_connection(host_keys: Vec<Box<KeyPair>>) -> Connection {
+        let config = server_config(host_keys);
+        let mut conn =
+            Connection::new(ConnectionType::Server(Arc::new(config)));
+        conn.hash_data.client_id = Some(String::from("SSH-2.0-client"));
+        conn.hash_data.server_id = Some(String::from("SSH-2.0-server"));
+        conn
+    }
+
+    fn client_connection() -> Connection {
+        let config = ClientConfig {
+            host: String::from("127.0.0.1"),
+            strict_host_key_checking: StrictHostKeyChecking::No,
+            ..ClientConfig::default()
         };
+        let mut conn =
+            Connection::new(ConnectionType::Client(Arc::new(config)));
+        conn.hash_data.client_id = Some(String::from("SSH-2.0-client"));
+        conn.hash_data.server_id = Some(String::from("SSH-2.0-server"));
+        conn
+    }
 
+    /// Sends a packet from one connection to the other and returns the
+    /// replies of the receiver, like `run` would send them
+    fn transfer(from: &mut Connection, to: &mut Connection, packet: Packet)
+        -> Result<Vec<Packet>> {
+        let mut buf = Vec::new();
+        from.send(&mut buf, packet)?;
+
+        let mut stream = Cursor::new(buf);
+        let mut replies = Vec::new();
+        while (stream.position() as usize) < stream.get_ref().len() {
+            let packet = to.recv(&mut stream)?;
+            replies.extend(to.process(packet)?);
+        }
+        replies.extend(to.tx_queue.drain(..));
+        Ok(replies)
+    }
 
-        self.key_exchange = Some(kex);
-        result
+    /// Passes packets between a client and a server, starting with packets
+    /// of the client, until neither has anything left to send
+    fn pump(
+        client: &mut Connection,
+        server: &mut Connection,
+        packets: Vec<Packet>,
+    ) -> Result<()> {
+        let mut to_server: VecDeque<Packet> = packets.into_iter().collect();
+        let mut to_client = VecDeque::new();
+
+        while !to_server.is_empty() || !to_client.is_empty() {
+            while let Some(packet) = to_server.pop_front() {
+                to_client.extend(transfer(client, server, packet)?);
+                to_server.extend(client.tx_queue.drain(..));
+            }
+            while let Some(packet) = to_client.pop_front() {
+                to_server.extend(transfer(server, client, packet)?);
+                to_client.extend(server.tx_queue.drain(..));
+            }
+        }
+        Ok(())
+    }
+
+    fn handshake(client: &mut Connection, server: &mut Connection)
+        -> Result<()> {
+        let kex_init = client.start_kex()?;
+        pump(client, server, vec![kex_init])
+    }
+
+    fn ignore_packet() -> Packet {
+        let mut packet = Packet::new(MessageType::Ignore);
+        packet.write_string("redox").unwrap();
+        packet
+    }
+
+    fn global_request() -> Packet {
+        let mut packet = Packet::new(MessageType::GlobalRequest);
+        packet.write_string("test@redox-os.org").unwrap();
+        packet.write_bool(false).unwrap();
+        packet
+    }
+
+    fn session_open(peer_id: u32) -> Packet {
+        let mut packet = Packet::new(MessageType::ChannelOpen);
+        packet.write_string("session").unwrap();
+        packet.write_uint32(peer_id).unwrap();
+        packet.write_uint32(0x200000).unwrap();
+        packet.write_uint32(0x8000).unwrap();
+        packet
+    }
+
+    /// Sends requests from an authenticated client until it starts a key
+    /// re-exchange, finishes that and returns the number of requests sent
+    fn requests_until_rekey(limit: RekeyLimit) -> u64 {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        if let ConnectionType::Client(ref mut config) = client.conn_type {
+            Arc::get_mut(config).unwrap().rekey_limit = limit;
+        }
+
+        handshake(&mut client, &mut server).unwrap();
+        client.authenticated = true;
+        server.authenticated = true;
+        client.transferred = (0, 0);
+        // Pretend the last key exchange was a minute ago
+        client.last_kex = Instant::now() - Duration::from_secs(60);
+
+        let mut sent = 0;
+        let mut buf = Vec::new();
+        while client.state == ConnectionState::Established {
+            assert!(sent < 100, "no key re-exchange was started");
+            client.send(&mut buf, global_request()).unwrap();
+            sent += 1;
+        }
+
+        // The last request waits for the new keys, our KEXINIT went out
+        assert_eq!(client.delayed_queue.len(), 1);
+        let mut stream = Cursor::new(buf);
+        for _ in 1..sent {
+            let packet = server.recv(&mut stream).unwrap();
+            assert!(server.process(packet).unwrap().is_none());
+        }
+        let kex_init = server.recv(&mut stream).unwrap();
+        assert_eq!(kex_init.msg_type(), MessageType::KexInit);
+
+        let replies = server.process(kex_init).unwrap();
+        pump(&mut server, &mut client, replies.into_iter().collect())
+            .unwrap();
+
+        assert!(client.state == ConnectionState::Established);
+        assert!(server.state == ConnectionState::Established);
+        assert!(client.delayed_queue.is_empty());
+        sent
+    }
+
+    fn check_send(mut conn: Connection, packets: &[&str]) {
+        let mut buf = Vec::new();
+
+        for expected in packets {
+            buf.clear();
+            conn.send(&mut buf, ignore_packet()).unwrap();
+            assert_eq!(buf, from_hex(expected));
+        }
+    }
+
+    fn check_recv(mut conn: Connection, packets: &[&str]) {
+        let data: Vec<u8> = packets.iter().flat_map(|p| from_hex(p)).collect();
+        let mut stream = Cursor::new(data);
+
+        for _ in packets {
+            let packet = conn.recv(&mut stream).unwrap();
+            assert_eq!(packet.payload(), ignore_packet().payload());
+        }
+    }
+
+    fn check_tampered(mut conn: Connection, packet: &str, pos: usize) {
+        let mut data = from_hex(packet);
+        data[pos] ^= 1;
+
+        match conn.recv(&mut Cursor::new(data))
+        {
+            Err(ConnectionError::IntegrityError) => (),
+            _ => panic!("tampered packet was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_aes_gcm_send() {
+        check_send(cipher_connection(gcm_cipher), GCM_PACKETS);
+    }
+
+    #[test]
+    fn test_aes_gcm_recv() {
+        check_recv(cipher_connection(gcm_cipher), GCM_PACKETS);
+    }
+
+    #[test]
+    fn test_aes_gcm_tampered() {
+        check_tampered(cipher_connection(gcm_cipher), GCM_PACKETS[0], 10);
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_send() {
+        check_send(cipher_connection(chacha_cipher), CHACHA_PACKETS);
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_recv() {
+        check_recv(cipher_connection(chacha_cipher), CHACHA_PACKETS);
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_tampered() {
+        let packet = CHACHA_PACKETS[0];
+        check_tampered(cipher_connection(chacha_cipher), packet, 10);
+
+        // The tag also covers the encrypted length
+        let packets = CHACHA_PACKETS.concat();
+        check_tampered(cipher_connection(chacha_cipher), &packets, 3);
+    }
+
+    #[test]
+    fn test_etm_send() {
+        check_send(cipher_connection(hmac_sha2_256_etm), ETM_PACKETS);
+
+        // The first packet with the other HMACs
+        let macs = [
+            (
+                algorithm::MacAlgorithm::HMAC_SHA1_ETM,
+                "0000002073a5c7e83457432df33ea6073316adadb281d700b79e3cada4ad\
+                 73bb6e9c1fea67a7e08c1348cd48c1746ac7c022626e2aba839c",
+            ),
+            (
+                algorithm::MacAlgorithm::HMAC_SHA2_512_ETM,
+                "0000002073a5c7e83457432df33ea6073316adadb281d700b79e3cada4ad\
+                 73bb6e9c1fea6305f0450a7708655b471cc55f9a5e150c648ff7ca265183\
+                 907aef6b7fb1faa8a570b2ba7b4c85456b403c939f10c4ea055eac2ed170\
+                 af87cd29d361c92f00bd",
+            ),
+        ];
+        for &(mac, packet) in macs.iter() {
+            let mut conn = cipher_connection(hmac_sha2_256_etm);
+            conn.cipher.1 = etm_cipher(mac);
+            check_send(conn, &[packet]);
+        }
+    }
+
+    #[test]
+    fn test_etm_recv() {
+        check_recv(cipher_connection(hmac_sha2_256_etm), ETM_PACKETS);
+    }
+
+    #[test]
+    fn test_etm_tampered() {
+        let packet = ETM_PACKETS[0];
+        check_tampered(cipher_connection(hmac_sha2_256_etm), packet, 10);
+
+        // The MAC is checked before anything is decrypted
+        let mut conn = cipher_connection(hmac_sha2_256_etm);
+        if let Some(Cipher::Etm(ref mut encryption, _)) = conn.cipher.0 {
+            *encryption = Box::new(NoDecryption);
+        }
+        check_tampered(conn, packet, 10);
+    }
+
+    #[test]
+    fn test_rekey_limits() {
+        let limit = RekeyLimit {
+            bytes: 1 << 30,
+            packets: 10,
+            time: None,
+        };
+        assert_eq!(requests_until_rekey(limit), 11);
+
+        let limit = RekeyLimit {
+            bytes: 1,
+            packets: 1 << 31,
+            time: None,
+        };
+        assert_eq!(requests_until_rekey(limit), 2);
+
+        let limit = RekeyLimit {
+            bytes: 1 << 30,
+            packets: 1 << 31,
+            time: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(requests_until_rekey(limit), 1);
+    }
+
+    #[test]
+    fn test_host_key_rotation() {
+        let config = server_config(vec![
+            (ED25519.generate_key_pair)(None),
+            (ECDSA_NISTP256.generate_key_pair)(None),
+        ]);
+        let mut server =
+            Connection::new(ConnectionType::Server(Arc::new(config)));
+        server.session_id = Some(vec![42; 32]);
+        server.authenticated = true;
+
+        let config = ClientConfig {
+            host: String::from("127.0.0.1"),
+            ..ClientConfig::default()
+        };
+        let mut client =
+            Connection::new(ConnectionType::Client(Arc::new(config)));
+        client.session_id = Some(vec![42; 32]);
+        client.authenticated = true;
+
+        // The client asks the server to prove the announced keys
+        let announcement = server.hostkeys_packet().unwrap().unwrap();
+        let request = client.process(announcement).unwrap().unwrap();
+        let reply = server.process(request).unwrap().unwrap();
+        assert_eq!(reply.msg_type(), MessageType::RequestSuccess);
+
+        client.process(reply).unwrap();
+        assert_eq!(client.learned_host_keys.len(), 2);
+
+        // Signatures over another session are rejected
+        let mut client = Connection::new(client.conn_type.clone());
+        client.session_id = Some(vec![23; 32]);
+        client.authenticated = true;
+
+        let announcement = server.hostkeys_packet().unwrap().unwrap();
+        let request = client.process(announcement).unwrap().unwrap();
+        let reply = server.process(request).unwrap().unwrap();
+        client.process(reply).unwrap();
+        assert!(client.learned_host_keys.is_empty());
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+
+        assert!(client.state == ConnectionState::Established);
+        assert!(server.state == ConnectionState::Established);
+        assert!(client.session_id.is_some());
+        assert_eq!(client.session_id, server.session_id);
+        assert_eq!(
+            client.host_key_algorithm(),
+            Some(PublicKeyAlgorithm::SSH_ED25519)
+        );
+        assert_eq!(server.host_key_algorithm(), client.host_key_algorithm());
+
+        // Both sides switched to the same keys
+        let replies = transfer(&mut client, &mut server, ignore_packet());
+        assert!(replies.unwrap().is_empty());
+        let replies = transfer(&mut server, &mut client, ignore_packet());
+        assert!(replies.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_strict_kex_unexpected_packet() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+
+        let kex_init = client.start_kex().unwrap();
+        transfer(&mut client, &mut server, kex_init).unwrap();
+        assert!(server.strict_kex);
+
+        // An IGNORE packet before NEWKEYS, like in the Terrapin attack
+        match transfer(&mut client, &mut server, ignore_packet())
+        {
+            Err(ConnectionError::ProtocolError) => (),
+            _ => panic!("packet during strict key exchange was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_strict_kex_sequence_numbers() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+        assert!(client.strict_kex && server.strict_kex);
+
+        // Both directions count from zero after NEWKEYS, and only EXT_INFO
+        // was sent since
+        assert_eq!(server.seq, (0, 1));
+        assert_eq!(client.seq, (1, 0));
+    }
+
+    #[test]
+    fn test_user_auth_success_from_client() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+
+        let success = Packet::new(MessageType::UserAuthSuccess);
+        assert!(transfer(&mut client, &mut server, success).is_err());
+        assert!(!server.authenticated);
+        assert!(server.compression.0.is_none());
+    }
+
+    #[test]
+    fn test_channel_open_unauthenticated() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+
+        match transfer(&mut client, &mut server, session_open(0))
+        {
+            Err(ConnectionError::ProtocolError) => (),
+            _ => panic!("channel opened before authentication"),
+        }
+        assert!(server.channels.is_empty());
+    }
+
+    #[test]
+    fn test_max_sessions() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+        client.authenticated = true;
+        server.authenticated = true;
+        server.settings.max_sessions = 2;
+
+        for id in 0..2 {
+            let reply = transfer(&mut client, &mut server, session_open(id));
+            assert_eq!(
+                reply.unwrap()[0].msg_type(),
+                MessageType::ChannelOpenConfirmation
+            );
+        }
+        let reply = transfer(&mut client, &mut server, session_open(2));
+        assert_eq!(
+            reply.unwrap()[0].msg_type(),
+            MessageType::ChannelOpenFailure
+        );
+    }
+
+    #[test]
+    fn test_ext_info() {
+        let mut server =
+            server_connection(vec![(ED25519.generate_key_pair)(None)]);
+        let mut client = client_connection();
+        handshake(&mut client, &mut server).unwrap();
+
+        // server-sig-algs lists what publickey authentication accepts
+        let packet = server.ext_info_packet().unwrap();
+        let mut reader = packet.reader();
+        assert_eq!(reader.read_uint32().unwrap(), 1);
+        assert_eq!(reader.read_utf8().unwrap(), "server-sig-algs");
+
+        let algorithms = reader.read_name_list().unwrap();
+        for algorithm in algorithms.iter() {
+            assert!(public_key::system_by_algorithm(algorithm).is_some());
+        }
+        for algorithm in &[
+            "rsa-sha2-512",
+            "sk-ssh-ed25519@openssh.com",
+            "ssh-ed25519-cert-v01@openssh.com",
+            "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com",
+        ]
+        {
+            assert!(algorithms.contains(&algorithm.to_string()));
+        }
+
+        // The client got it right after the server's NEWKEYS
+        let algorithms = client.server_sig_algs.unwrap();
+        assert!(algorithms.contains(&PublicKeyAlgorithm::RSA_SHA2_256));
+        assert!(algorithms.contains(&PublicKeyAlgorithm::SSH_ED25519_CERT));
+    }
+
+    #[test]
+    fn test_key_exchange_signature_algorithm() {
+        let key = (RSA.generate_key_pair)(Some(1024));
+        let mut server = server_connection(vec![key]);
+        let mut client = client_connection();
+
+        let kex_init = client.start_kex().unwrap();
+        let kex_init = transfer(&mut client, &mut server, kex_init)
+            .unwrap()
+            .remove(0);
+        let ecdh_init = transfer(&mut server, &mut client, kex_init)
+            .unwrap()
+            .remove(0);
+        let ecdh_reply = transfer(&mut client, &mut server, ecdh_init)
+            .unwrap()
+            .remove(0);
+
+        assert_eq!(
+            client.host_key_algorithm(),
+            Some(PublicKeyAlgorithm::RSA_SHA2_512)
+        );
+
+        // A valid signature, but with another algorithm than negotiated
+        let (host_key, server_public) = {
+            let mut reader = ecdh_reply.reader();
+            (reader.read_string().unwrap(), reader.read_string().unwrap())
+        };
+        let hash = server.session_id.clone().unwrap();
+        let signature = match server.conn_type
+        {
+            ConnectionType::Server(ref config) => {
+                config.host_keys[0].sign_with(&hash, "rsa-sha2-256").unwrap()
+            }
+            _ => unreachable!(),
+        };
+
+        let mut forged = Packet::new(MessageType::KeyExchange(31));
+        forged.write_bytes(&host_key).unwrap();
+        forged.write_bytes(&server_public).unwrap();
+        forged.write_bytes(&signature).unwrap();
+
+        match transfer(&mut server, &mut client, forged)
+        {
+            Err(ConnectionError::KeyExchangeError) => (),
+            _ => panic!("signature with another algorithm was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_key_exchange_short_signature() {
+        let key = (ED25519.generate_key_pair)(None);
+        let mut server = server_connection(vec![key]);
+        let mut client = client_connection();
+
+        let kex_init = client.start_kex().unwrap();
+        let kex_init = transfer(&mut client, &mut server, kex_init)
+            .unwrap()
+            .remove(0);
+        let ecdh_init = transfer(&mut server, &mut client, kex_init)
+            .unwrap()
+            .remove(0);
+        let ecdh_reply = transfer(&mut client, &mut server, ecdh_init)
+            .unwrap()
+            .remove(0);
+
+        // Shorten the ed25519 signature blob from 64 to 32 bytes
+        let (host_key, server_public, mut signature) = {
+            let mut reader = ecdh_reply.reader();
+            (
+                reader.read_string().unwrap(),
+                reader.read_string().unwrap(),
+                reader.read_string().unwrap(),
+            )
+        };
+        signature.truncate(4 + 11 + 4 + 32);
+        signature[18] = 32;
+
+        let mut forged = Packet::new(MessageType::KeyExchange(31));
+        forged.write_bytes(&host_key).unwrap();
+        forged.write_bytes(&server_public).unwrap();
+        forged.write_bytes(&signature).unwrap();
+
+        let mut kex = client.key_exchange.take().unwrap();
+        match kex.process(&mut client, forged)
+        {
+            KexResult::Error => (),
+            _ => panic!("short signature was accepted"),
+        }
     }
 }
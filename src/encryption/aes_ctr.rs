@@ -9,11 +9,22 @@ pub struct AesCtr {
 
 impl AesCtr {
     pub fn new(key: &[u8], iv: &[u8]) -> AesCtr {
-        AesCtr { cipher: ctr(KeySize::KeySize256, key, &iv[0..16]) }
+        let key_size = match key.len()
+        {
+            16 => KeySize::KeySize128,
+            24 => KeySize::KeySize192,
+            _ => KeySize::KeySize256,
+        };
+
+        AesCtr { cipher: ctr(key_size, key, &iv[0..16]) }
     }
 }
 
 impl Encryption for AesCtr {
+    fn block_size(&self) -> usize {
+        16
+    }
+
     fn encrypt(&mut self, data: &[u8], buf: &mut [u8]) {
         self.cipher.process(data, buf);
     }
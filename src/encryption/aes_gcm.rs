@@ -0,0 +1,75 @@
+use byteorder::{BigEndian, ByteOrder};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm;
+
+use encryption::AeadEncryption;
+
+/// AES-GCM for the SSH transport, as specified in RFC 5647. The packet
+/// length is sent in the clear, but authenticated as additional data.
+pub struct AesGcm {
+    key_size: KeySize,
+    key: Vec<u8>,
+    iv: [u8; 12],
+}
+
+impl AesGcm {
+    pub fn new(key: &[u8], iv: &[u8]) -> AesGcm {
+        let key_size = match key.len()
+        {
+            16 => KeySize::KeySize128,
+            24 => KeySize::KeySize192,
+            _ => KeySize::KeySize256,
+        };
+
+        let mut nonce = [0; 12];
+        nonce.copy_from_slice(&iv[0..12]);
+
+        AesGcm {
+            key_size: key_size,
+            key: key.to_vec(),
+            iv: nonce,
+        }
+    }
+
+    /// Returns the IV for the next packet and advances the invocation
+    /// counter in its last 8 bytes
+    fn next_iv(&mut self) -> [u8; 12] {
+        let iv = self.iv;
+        let counter = BigEndian::read_u64(&self.iv[4..12]);
+        BigEndian::write_u64(&mut self.iv[4..12], counter.wrapping_add(1));
+        iv
+    }
+}
+
+impl AeadEncryption for AesGcm {
+    fn tag_size(&self) -> usize {
+        16
+    }
+
+    fn decrypt_length(&mut self, _: u32, data: &[u8]) -> u32 {
+        BigEndian::read_u32(&data[0..4])
+    }
+
+    fn seal(&mut self, _: u32, packet: &[u8], buf: &mut [u8]) {
+        let len = packet.len();
+        let iv = self.next_iv();
+        let mut cipher =
+            aes_gcm::AesGcm::new(self.key_size, &self.key, &iv, &packet[0..4]);
+
+        buf[0..4].copy_from_slice(&packet[0..4]);
+
+        let (encrypted, tag) = buf[4..].split_at_mut(len - 4);
+        cipher.encrypt(&packet[4..], encrypted, &mut tag[0..16]);
+    }
+
+    fn open(&mut self, _: u32, data: &[u8], buf: &mut [u8]) -> bool {
+        let (ciphertext, tag) = data.split_at(data.len() - 16);
+        let iv = self.next_iv();
+        let mut cipher =
+            aes_gcm::AesGcm::new(self.key_size, &self.key, &iv, &data[0..4]);
+
+        buf[0..4].copy_from_slice(&ciphertext[0..4]);
+        cipher.decrypt(&ciphertext[4..], &mut buf[4..ciphertext.len()], tag)
+    }
+}
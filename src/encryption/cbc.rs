@@ -0,0 +1,152 @@
+use crypto::aessafe::{AesSafe128Decryptor, AesSafe128Encryptor,
+                      AesSafe192Decryptor, AesSafe192Encryptor,
+                      AesSafe256Decryptor, AesSafe256Encryptor};
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+
+use encryption::Encryption;
+use encryption::des::TripleDes;
+
+/// Cipher block chaining over any block cipher. Data is always passed in
+/// whole blocks, because SSH packets are padded to the block size.
+pub struct Cbc {
+    encryptor: Box<BlockEncryptor + Send>,
+    decryptor: Box<BlockDecryptor + Send>,
+    iv: Vec<u8>,
+}
+
+impl Cbc {
+    pub fn aes(key: &[u8], iv: &[u8]) -> Cbc {
+        let (encryptor, decryptor): (
+            Box<BlockEncryptor + Send>,
+            Box<BlockDecryptor + Send>,
+        ) = match key.len()
+        {
+            16 => (
+                Box::new(AesSafe128Encryptor::new(key)),
+                Box::new(AesSafe128Decryptor::new(key)),
+            ),
+            24 => (
+                Box::new(AesSafe192Encryptor::new(key)),
+                Box::new(AesSafe192Decryptor::new(key)),
+            ),
+            _ => (
+                Box::new(AesSafe256Encryptor::new(key)),
+                Box::new(AesSafe256Decryptor::new(key)),
+            ),
+        };
+
+        Cbc {
+            encryptor: encryptor,
+            decryptor: decryptor,
+            iv: iv[0..16].to_vec(),
+        }
+    }
+
+    pub fn triple_des(key: &[u8], iv: &[u8]) -> Cbc {
+        Cbc {
+            encryptor: Box::new(TripleDes::new(key)),
+            decryptor: Box::new(TripleDes::new(key)),
+            iv: iv[0..8].to_vec(),
+        }
+    }
+}
+
+impl Encryption for Cbc {
+    fn block_size(&self) -> usize {
+        self.iv.len()
+    }
+
+    fn encrypt(&mut self, data: &[u8], buf: &mut [u8]) {
+        let block_size = self.iv.len();
+        let mut block = vec![0; block_size];
+
+        for (input, output) in data.chunks(block_size)
+            .zip(buf.chunks_mut(block_size))
+        {
+            for i in 0..block_size {
+                block[i] = input[i] ^ self.iv[i];
+            }
+            self.encryptor.encrypt_block(&block, output);
+            self.iv.copy_from_slice(output);
+        }
+    }
+
+    fn decrypt(&mut self, data: &[u8], buf: &mut [u8]) {
+        let block_size = self.iv.len();
+
+        for (input, output) in data.chunks(block_size)
+            .zip(buf.chunks_mut(block_size))
+        {
+            self.decryptor.decrypt_block(input, output);
+            for i in 0..block_size {
+                output[i] ^= self.iv[i];
+            }
+            self.iv.copy_from_slice(input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Encrypts and decrypts in two calls, to check that the IV is carried
+    /// over from one packet to the next
+    fn check(
+        mut encryptor: Cbc,
+        mut decryptor: Cbc,
+        plain: &str,
+        cipher: &str,
+    ) {
+        let plain = from_hex(plain);
+        let cipher = from_hex(cipher);
+        let half = plain.len() / 2;
+
+        let mut buf = vec![0; plain.len()];
+        encryptor.encrypt(&plain[..half], &mut buf[..half]);
+        encryptor.encrypt(&plain[half..], &mut buf[half..]);
+        assert_eq!(buf, cipher);
+
+        decryptor.decrypt(&cipher[..half], &mut buf[..half]);
+        decryptor.decrypt(&cipher[half..], &mut buf[half..]);
+        assert_eq!(buf, plain);
+    }
+
+    #[test]
+    fn test_aes_cbc() {
+        // CBC-AES128 from NIST SP 800-38A, F.2.1 and F.2.2
+        let key = from_hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let iv = from_hex("000102030405060708090a0b0c0d0e0f");
+
+        check(
+            Cbc::aes(&key, &iv),
+            Cbc::aes(&key, &iv),
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+             30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+            "7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2\
+             73bed6b8e3c1743b7116e69e222295163ff1caa1681fac09120eca307586e1a7",
+        );
+    }
+
+    #[test]
+    fn test_triple_des_cbc() {
+        // The keys of the NIST SP 800-67 example and the first blocks of
+        // SP 800-38A, checked against OpenSSL
+        let key = from_hex("0123456789abcdef23456789abcdef01456789abcdef0123");
+        let iv = from_hex("f69f2445df4f9b17");
+
+        check(
+            Cbc::triple_des(&key, &iv),
+            Cbc::triple_des(&key, &iv),
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "2079c3d53aa763e193b79e2569ab5262516570481f25b50f73c0bda85c8e0da7",
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use byteorder::{BigEndian, ByteOrder};
+use crypto::chacha20::ChaCha20;
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+
+use encryption::AeadEncryption;
+
+/// The chacha20-poly1305@openssh.com cipher, as specified in
+/// PROTOCOL.chacha20poly1305 of OpenSSH
+pub struct ChaCha20Poly1305 {
+    main_key: [u8; 32],
+    header_key: [u8; 32],
+}
+
+impl ChaCha20Poly1305 {
+    pub fn new(key: &[u8]) -> ChaCha20Poly1305 {
+        let mut main_key = [0; 32];
+        let mut header_key = [0; 32];
+        main_key.copy_from_slice(&key[0..32]);
+        header_key.copy_from_slice(&key[32..64]);
+
+        ChaCha20Poly1305 {
+            main_key: main_key,
+            header_key: header_key,
+        }
+    }
+
+    fn nonce(seq: u32) -> [u8; 8] {
+        let mut nonce = [0; 8];
+        BigEndian::write_u32(&mut nonce[4..8], seq);
+        nonce
+    }
+
+    fn header_cipher(&self, seq: u32) -> ChaCha20 {
+        ChaCha20::new(&self.header_key, &Self::nonce(seq))
+    }
+
+    /// Creates the payload cipher and takes the Poly1305 key from its first
+    /// block, so that the payload is encrypted starting with block 1
+    fn main_cipher(&self, seq: u32) -> (ChaCha20, [u8; 32]) {
+        let mut cipher = ChaCha20::new(&self.main_key, &Self::nonce(seq));
+
+        let mut block = [0; 64];
+        cipher.process(&[0; 64], &mut block);
+
+        let mut poly_key = [0; 32];
+        poly_key.copy_from_slice(&block[0..32]);
+
+        (cipher, poly_key)
+    }
+
+    fn tag(poly_key: &[u8], data: &[u8]) -> [u8; 16] {
+        let mut tag = [0; 16];
+        let mut poly = Poly1305::new(poly_key);
+        poly.input(data);
+        poly.raw_result(&mut tag);
+        tag
+    }
+}
+
+impl AeadEncryption for ChaCha20Poly1305 {
+    fn tag_size(&self) -> usize {
+        16
+    }
+
+    fn decrypt_length(&mut self, seq: u32, data: &[u8]) -> u32 {
+        let mut length = [0; 4];
+        self.header_cipher(seq).process(&data[0..4], &mut length);
+        BigEndian::read_u32(&length)
+    }
+
+    fn seal(&mut self, seq: u32, packet: &[u8], buf: &mut [u8]) {
+        let len = packet.len();
+        let (mut cipher, poly_key) = self.main_cipher(seq);
+
+        self.header_cipher(seq).process(&packet[0..4], &mut buf[0..4]);
+        cipher.process(&packet[4..], &mut buf[4..len]);
+
+        let tag = Self::tag(&poly_key, &buf[0..len]);
+        buf[len..len + 16].copy_from_slice(&tag);
+    }
+
+    fn open(&mut self, seq: u32, data: &[u8], buf: &mut [u8]) -> bool {
+        let (ciphertext, tag) = data.split_at(data.len() - 16);
+        let (mut cipher, poly_key) = self.main_cipher(seq);
+
+        if !fixed_time_eq(&Self::tag(&poly_key, ciphertext), tag) {
+            return false;
+        }
+
+        self.header_cipher(seq).process(&ciphertext[0..4], &mut buf[0..4]);
+        cipher.process(&ciphertext[4..], &mut buf[4..ciphertext.len()]);
+
+        true
+    }
+}
@@ -0,0 +1,258 @@
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+
+// Permutation and substitution tables from FIPS 46-3. Bit positions are
+// counted from the most significant bit, starting at 1.
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static IP: [u8; 64] = [
+    58, 50, 42, 34, 26, 18, 10, 2, 60, 52, 44, 36, 28, 20, 12, 4,
+    62, 54, 46, 38, 30, 22, 14, 6, 64, 56, 48, 40, 32, 24, 16, 8,
+    57, 49, 41, 33, 25, 17, 9, 1, 59, 51, 43, 35, 27, 19, 11, 3,
+    61, 53, 45, 37, 29, 21, 13, 5, 63, 55, 47, 39, 31, 23, 15, 7,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static FP: [u8; 64] = [
+    40, 8, 48, 16, 56, 24, 64, 32, 39, 7, 47, 15, 55, 23, 63, 31,
+    38, 6, 46, 14, 54, 22, 62, 30, 37, 5, 45, 13, 53, 21, 61, 29,
+    36, 4, 44, 12, 52, 20, 60, 28, 35, 3, 43, 11, 51, 19, 59, 27,
+    34, 2, 42, 10, 50, 18, 58, 26, 33, 1, 41, 9, 49, 17, 57, 25,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static E: [u8; 48] = [
+    32, 1, 2, 3, 4, 5, 4, 5, 6, 7, 8, 9, 8, 9, 10, 11,
+    12, 13, 12, 13, 14, 15, 16, 17, 16, 17, 18, 19, 20, 21, 20, 21,
+    22, 23, 24, 25, 24, 25, 26, 27, 28, 29, 28, 29, 30, 31, 32, 1,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static P: [u8; 32] = [
+    16, 7, 20, 21, 29, 12, 28, 17, 1, 15, 23, 26, 5, 18, 31, 10,
+    2, 8, 24, 14, 32, 27, 3, 9, 19, 13, 30, 6, 22, 11, 4, 25,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static PC1: [u8; 56] = [
+    57, 49, 41, 33, 25, 17, 9, 1, 58, 50, 42, 34, 26, 18,
+    10, 2, 59, 51, 43, 35, 27, 19, 11, 3, 60, 52, 44, 36,
+    63, 55, 47, 39, 31, 23, 15, 7, 62, 54, 46, 38, 30, 22,
+    14, 6, 61, 53, 45, 37, 29, 21, 13, 5, 28, 20, 12, 4,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static PC2: [u8; 48] = [
+    14, 17, 11, 24, 1, 5, 3, 28, 15, 6, 21, 10,
+    23, 19, 12, 4, 26, 8, 16, 7, 27, 20, 13, 2,
+    41, 52, 31, 37, 47, 55, 30, 40, 51, 45, 33, 48,
+    44, 49, 39, 56, 34, 53, 46, 42, 50, 36, 29, 32,
+];
+
+static SHIFTS: [u32; 16] = [1, 1, 2, 2, 2, 2, 2, 2, 1, 2, 2, 2, 2, 2, 2, 1];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static S: [[u8; 64]; 8] = [
+    [
+        14, 4, 13, 1, 2, 15, 11, 8, 3, 10, 6, 12, 5, 9, 0, 7,
+        0, 15, 7, 4, 14, 2, 13, 1, 10, 6, 12, 11, 9, 5, 3, 8,
+        4, 1, 14, 8, 13, 6, 2, 11, 15, 12, 9, 7, 3, 10, 5, 0,
+        15, 12, 8, 2, 4, 9, 1, 7, 5, 11, 3, 14, 10, 0, 6, 13,
+    ],
+    [
+        15, 1, 8, 14, 6, 11, 3, 4, 9, 7, 2, 13, 12, 0, 5, 10,
+        3, 13, 4, 7, 15, 2, 8, 14, 12, 0, 1, 10, 6, 9, 11, 5,
+        0, 14, 7, 11, 10, 4, 13, 1, 5, 8, 12, 6, 9, 3, 2, 15,
+        13, 8, 10, 1, 3, 15, 4, 2, 11, 6, 7, 12, 0, 5, 14, 9,
+    ],
+    [
+        10, 0, 9, 14, 6, 3, 15, 5, 1, 13, 12, 7, 11, 4, 2, 8,
+        13, 7, 0, 9, 3, 4, 6, 10, 2, 8, 5, 14, 12, 11, 15, 1,
+        13, 6, 4, 9, 8, 15, 3, 0, 11, 1, 2, 12, 5, 10, 14, 7,
+        1, 10, 13, 0, 6, 9, 8, 7, 4, 15, 14, 3, 11, 5, 2, 12,
+    ],
+    [
+        7, 13, 14, 3, 0, 6, 9, 10, 1, 2, 8, 5, 11, 12, 4, 15,
+        13, 8, 11, 5, 6, 15, 0, 3, 4, 7, 2, 12, 1, 10, 14, 9,
+        10, 6, 9, 0, 12, 11, 7, 13, 15, 1, 3, 14, 5, 2, 8, 4,
+        3, 15, 0, 6, 10, 1, 13, 8, 9, 4, 5, 11, 12, 7, 2, 14,
+    ],
+    [
+        2, 12, 4, 1, 7, 10, 11, 6, 8, 5, 3, 15, 13, 0, 14, 9,
+        14, 11, 2, 12, 4, 7, 13, 1, 5, 0, 15, 10, 3, 9, 8, 6,
+        4, 2, 1, 11, 10, 13, 7, 8, 15, 9, 12, 5, 6, 3, 0, 14,
+        11, 8, 12, 7, 1, 14, 2, 13, 6, 15, 0, 9, 10, 4, 5, 3,
+    ],
+    [
+        12, 1, 10, 15, 9, 2, 6, 8, 0, 13, 3, 4, 14, 7, 5, 11,
+        10, 15, 4, 2, 7, 12, 9, 5, 6, 1, 13, 14, 0, 11, 3, 8,
+        9, 14, 15, 5, 2, 8, 12, 3, 7, 0, 4, 10, 1, 13, 11, 6,
+        4, 3, 2, 12, 9, 5, 15, 10, 11, 14, 1, 7, 6, 0, 8, 13,
+    ],
+    [
+        4, 11, 2, 14, 15, 0, 8, 13, 3, 12, 9, 7, 5, 10, 6, 1,
+        13, 0, 11, 7, 4, 9, 1, 10, 14, 3, 5, 12, 2, 15, 8, 6,
+        1, 4, 11, 13, 12, 3, 7, 14, 10, 15, 6, 8, 0, 5, 9, 2,
+        6, 11, 13, 8, 1, 4, 10, 7, 9, 5, 0, 15, 14, 2, 3, 12,
+    ],
+    [
+        13, 2, 8, 4, 6, 15, 11, 1, 10, 9, 3, 14, 5, 0, 12, 7,
+        1, 15, 13, 8, 10, 3, 7, 4, 12, 5, 6, 11, 0, 14, 9, 2,
+        7, 11, 4, 1, 9, 12, 14, 2, 0, 6, 10, 13, 15, 3, 5, 8,
+        2, 1, 14, 7, 4, 10, 8, 13, 15, 12, 9, 0, 3, 5, 6, 11,
+    ],
+];
+
+fn permute(input: u64, input_bits: u32, table: &[u8]) -> u64 {
+    table.iter().fold(0, |out, &pos| {
+        (out << 1) | ((input >> (input_bits - pos as u32)) & 1)
+    })
+}
+
+fn read_block(data: &[u8]) -> u64 {
+    data[0..8].iter().fold(0, |block, &b| (block << 8) | b as u64)
+}
+
+fn write_block(block: u64, buf: &mut [u8]) {
+    for i in 0..8 {
+        buf[i] = (block >> (56 - 8 * i)) as u8;
+    }
+}
+
+/// A single DES key schedule
+struct Des {
+    subkeys: [u64; 16],
+}
+
+impl Des {
+    fn new(key: &[u8]) -> Des {
+        let key = permute(read_block(key), 64, &PC1);
+        let (mut c, mut d) = (key >> 28, key & 0xfffffff);
+        let mut subkeys = [0; 16];
+
+        for (subkey, &shift) in subkeys.iter_mut().zip(SHIFTS.iter()) {
+            c = ((c << shift) | (c >> (28 - shift))) & 0xfffffff;
+            d = ((d << shift) | (d >> (28 - shift))) & 0xfffffff;
+            *subkey = permute((c << 28) | d, 56, &PC2);
+        }
+
+        Des { subkeys: subkeys }
+    }
+
+    fn feistel(right: u64, subkey: u64) -> u64 {
+        let x = permute(right, 32, &E) ^ subkey;
+
+        let substituted = S.iter().enumerate().fold(0, |out, (i, sbox)| {
+            let six = (x >> (42 - 6 * i)) & 0x3f;
+            let row = ((six & 0x20) >> 4) | (six & 1);
+            let col = (six >> 1) & 0xf;
+            (out << 4) | sbox[(row * 16 + col) as usize] as u64
+        });
+
+        permute(substituted, 32, &P)
+    }
+
+    fn process(&self, block: u64, decrypt: bool) -> u64 {
+        let block = permute(block, 64, &IP);
+        let (mut left, mut right) = (block >> 32, block & 0xffffffff);
+
+        for round in 0..16 {
+            let subkey = self.subkeys[if decrypt { 15 - round } else { round }];
+            let next = left ^ Des::feistel(right, subkey);
+            left = right;
+            right = next;
+        }
+
+        permute((right << 32) | left, 64, &FP)
+    }
+}
+
+/// Triple DES in encrypt-decrypt-encrypt mode with three keys, as used by
+/// 3des-cbc
+pub struct TripleDes {
+    keys: [Des; 3],
+}
+
+impl TripleDes {
+    pub fn new(key: &[u8]) -> TripleDes {
+        TripleDes {
+            keys: [
+                Des::new(&key[0..8]),
+                Des::new(&key[8..16]),
+                Des::new(&key[16..24]),
+            ],
+        }
+    }
+}
+
+impl BlockEncryptor for TripleDes {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let block = self.keys[0].process(read_block(input), false);
+        let block = self.keys[1].process(block, true);
+        let block = self.keys[2].process(block, false);
+        write_block(block, output);
+    }
+}
+
+impl BlockDecryptor for TripleDes {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn decrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let block = self.keys[2].process(read_block(input), true);
+        let block = self.keys[1].process(block, false);
+        let block = self.keys[0].process(block, true);
+        write_block(block, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn check(key: &str, plaintext: &[u8], ciphertext: &str) {
+        let cipher = TripleDes::new(&from_hex(key));
+        let ciphertext = from_hex(ciphertext);
+
+        for (input, expected) in plaintext.chunks(8).zip(ciphertext.chunks(8))
+        {
+            let mut output = [0; 8];
+            cipher.encrypt_block(input, &mut output);
+            assert_eq!(&output, expected);
+
+            cipher.decrypt_block(expected, &mut output);
+            assert_eq!(&output, input);
+        }
+    }
+
+    #[test]
+    fn test_des() {
+        // Variable plaintext known answers from NIST SP 800-20, with three
+        // equal keys 3DES is single DES
+        let key = "010101010101010101010101010101010101010101010101";
+        check(key, &from_hex("8000000000000000"), "95f8a5e5dd31d900");
+        check(key, &from_hex("4000000000000000"), "dd7f121ca5015619");
+        check(key, &from_hex("0000000000000001"), "166b40b44aba4bd6");
+    }
+
+    #[test]
+    fn test_triple_des() {
+        // The TDEA example of NIST SP 800-67, including its typo
+        check(
+            "0123456789abcdef23456789abcdef01456789abcdef0123",
+            b"The qufck brown fox jump",
+            "a826fd8ce53b855fcce21c8112256fe668d5c05dd9b6b900",
+        );
+    }
+}
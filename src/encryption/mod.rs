@@ -1,37 +1,35 @@
-use std::io::{self, Read};
-
 mod aes_ctr;
+mod aes_gcm;
+mod cbc;
+mod chacha20_poly1305;
+mod des;
 
 pub use self::aes_ctr::AesCtr;
+pub use self::aes_gcm::AesGcm;
+pub use self::cbc::Cbc;
+pub use self::chacha20_poly1305::ChaCha20Poly1305;
 
 pub trait Encryption {
+    /// Size of the cipher blocks. The packet length has to be decrypted from
+    /// the first block before the rest of a packet can be read.
+    fn block_size(&self) -> usize;
+
     fn encrypt(&mut self, data: &[u8], buf: &mut [u8]);
     fn decrypt(&mut self, data: &[u8], buf: &mut [u8]);
 }
 
-pub struct Decryptor<'a> {
-    encryption: &'a mut Encryption,
-    stream: &'a mut Read,
-}
+/// Authenticated encryption of whole packets, which replaces the MAC.
+/// Packets passed in and out start with the 4 byte packet length.
+pub trait AeadEncryption {
+    fn tag_size(&self) -> usize;
 
-impl<'a> Decryptor<'a> {
-    pub fn new(encryption: &'a mut Encryption, stream: &'a mut Read)
-        -> Decryptor<'a> {
-        Decryptor {
-            encryption: encryption,
-            stream: stream,
-        }
-    }
-}
+    /// Returns the packet length from the first 4 bytes of a packet
+    fn decrypt_length(&mut self, seq: u32, data: &[u8]) -> u32;
+
+    /// Encrypts the packet into `buf` and appends the authentication tag
+    fn seal(&mut self, seq: u32, packet: &[u8], buf: &mut [u8]);
 
-impl<'a> Read for Decryptor<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut tmp = vec![0; buf.len()];
-        let count = self.stream.read(tmp.as_mut_slice())?;
-        self.encryption.decrypt(
-            &tmp.as_slice()[0..count],
-            &mut buf[0..count],
-        );
-        Ok(count)
-    }
+    /// Verifies the authentication tag at the end of `data` and decrypts the
+    /// packet into `buf` only if it is valid
+    fn open(&mut self, seq: u32, data: &[u8], buf: &mut [u8]) -> bool;
 }
@@ -9,6 +9,7 @@ pub enum ConnectionError {
     ProtocolError,
     NegotiationError,
     KeyExchangeError,
+    HostKeyError,
     KeyGenerationError,
     IntegrityError,
 }
@@ -22,6 +23,7 @@ impl fmt::Display for ConnectionError {
             ProtocolError => "protocol error".to_owned(),
             NegotiationError => "negotiation error".to_owned(),
             KeyExchangeError => "key exchange error".to_owned(),
+            HostKeyError => "host key verification failed".to_owned(),
             KeyGenerationError => "key generation error".to_owned(),
             IntegrityError => "integrity error".to_owned(),
         }))
@@ -1,31 +1,250 @@
+use std::io::Cursor;
+
 use connection::{Connection, ConnectionType};
 use crypto::curve25519;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use error::{ConnectionError, ConnectionResult};
 use key_exchange::{KexResult, KeyExchange};
 use message::MessageType;
 use num_bigint::{BigInt, Sign};
 use packet::{Packet, ReadPacketExt, WritePacketExt};
+use public_key::{self, Certificate, FingerprintHash};
 use rand::Rng;
 
 const ECDH_KEX_INIT: u8 = 30;
 const ECDH_KEX_REPLY: u8 = 31;
 
 pub struct Curve25519 {
+    secret: Option<[u8; 32]>,
     shared_secret: Option<Vec<u8>>,
     exchange_hash: Option<Vec<u8>>,
+    host_key: Option<Vec<u8>>,
 }
 
 impl Curve25519 {
     pub fn new() -> Curve25519 {
         Curve25519 {
+            secret: None,
             shared_secret: None,
             exchange_hash: None,
+            host_key: None,
+        }
+    }
+
+    fn generate_secret() -> [u8; 32] {
+        let mut secret = [0; 32];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut secret);
+
+        secret[0] &= 248;
+        secret[31] &= 127;
+        secret[31] |= 64;
+
+        secret
+    }
+
+    /// Computes the shared secret and encodes it as mpint, while rejecting
+    /// invalid peer keys as required by RFC 8731, section 3.
+    fn compute_shared_secret(secret: &[u8; 32], peer_public: &[u8])
+        -> ConnectionResult<Vec<u8>> {
+        if peer_public.len() != 32 {
+            debug!("Invalid curve25519 public key length");
+            return Err(ConnectionError::KeyExchangeError);
+        }
+
+        let shared = curve25519::curve25519(secret, peer_public);
+
+        // An all-zero result means the peer sent a low order point
+        if shared.iter().all(|b| *b == 0) {
+            debug!("Rejecting all-zero curve25519 shared secret");
+            return Err(ConnectionError::KeyExchangeError);
+        }
+
+        let mut buf = Vec::new();
+        buf.write_mpint(BigInt::from_bytes_be(Sign::Plus, &shared))?;
+        Ok(buf)
+    }
+
+    fn compute_hash(
+        &self,
+        conn: &Connection,
+        host_key: &[u8],
+        client_public: &[u8],
+        server_public: &[u8],
+        shared_secret: &[u8],
+    ) -> ConnectionResult<Vec<u8>> {
+        use self::ConnectionError::KeyExchangeError;
+
+        let mut buf = Vec::new();
+        let data = &conn.hash_data;
+
+        let items = [
+            data.client_id.as_ref().ok_or(KeyExchangeError)?.as_bytes(),
+            data.server_id.as_ref().ok_or(KeyExchangeError)?.as_bytes(),
+            data.client_kexinit.as_ref().ok_or(KeyExchangeError)?.as_slice(),
+            data.server_kexinit.as_ref().ok_or(KeyExchangeError)?.as_slice(),
+            host_key,
+            client_public,
+            server_public,
+        ];
+
+        for item in items.iter() {
+            buf.write_bytes(item)?;
+        }
+
+        buf.write_raw_bytes(shared_secret)?;
+
+        Ok(self.hash(&[buf.as_slice()]))
+    }
+
+    fn process_init(&mut self, conn: &mut Connection, packet: Packet)
+        -> ConnectionResult<Packet> {
+        let mut reader = packet.reader();
+        let client_public = reader.read_string()?;
+
+        let config = match &conn.conn_type
+        {
+            &ConnectionType::Server(ref config) => config.clone(),
+            _ => return Err(ConnectionError::KeyExchangeError),
+        };
+
+        let algorithm = conn.host_key_algorithm().ok_or(
+            ConnectionError::KeyExchangeError,
+        )?;
+
+        let key = config.host_key(algorithm).ok_or(
+            ConnectionError::KeyExchangeError,
+        )?;
+
+        // A negotiated certificate algorithm presents the host certificate,
+        // but signs with the plain key
+        let public_key = {
+            let mut blob = Vec::new();
+            match (algorithm.certified(), config.host_certificate(algorithm))
+            {
+                (Some(_), Some(cert)) => cert.write(&mut blob)?,
+                (Some(_), None) => {
+                    return Err(ConnectionError::KeyExchangeError)
+                }
+                (None, _) => key.write_public(&mut blob)?,
+            }
+            blob
+        };
+        let algorithm = algorithm.certified().unwrap_or(algorithm);
+
+        let server_secret = Self::generate_secret();
+        let server_public = curve25519::curve25519_base(&server_secret);
+        let shared_secret =
+            Self::compute_shared_secret(&server_secret, &client_public)?;
+
+        let hash = self.compute_hash(
+            conn,
+            &public_key,
+            &client_public,
+            &server_public,
+            &shared_secret,
+        )?;
+
+        let signature = key.sign_with(&hash, &algorithm.to_string()).or(Err(
+            ConnectionError::KeyExchangeError,
+        ))?;
+
+        let mut packet = Packet::new(MessageType::KeyExchange(ECDH_KEX_REPLY));
+        packet.write_bytes(public_key.as_slice())?;
+        packet.write_bytes(&server_public)?;
+        packet.write_bytes(signature.as_slice())?;
+
+        self.exchange_hash = Some(hash);
+        self.shared_secret = Some(shared_secret);
+        self.host_key = Some(public_key);
+
+        Ok(packet)
+    }
+
+    fn process_reply(&mut self, conn: &mut Connection, packet: Packet)
+        -> ConnectionResult<()> {
+        let client_secret =
+            self.secret.take().ok_or(ConnectionError::KeyExchangeError)?;
+        let client_public = curve25519::curve25519_base(&client_secret);
+
+        let mut reader = packet.reader();
+        let host_key = reader.read_string()?;
+        let server_public = reader.read_string()?;
+        let signature = reader.read_string()?;
+
+        let shared_secret =
+            Self::compute_shared_secret(&client_secret, &server_public)?;
+
+        let hash = self.compute_hash(
+            conn,
+            &host_key,
+            &client_public,
+            &server_public,
+            &shared_secret,
+        )?;
+
+        // The signature has to use the negotiated host key algorithm, which
+        // is the certified one for certificates
+        let algorithm = conn.host_key_algorithm().ok_or(
+            ConnectionError::KeyExchangeError,
+        )?;
+        let signature_algorithm = algorithm.certified().unwrap_or(algorithm);
+
+        if Cursor::new(&signature).read_utf8()? !=
+            signature_algorithm.to_string()
+        {
+            error!("Host key signature does not match {}", algorithm);
+            return Err(ConnectionError::KeyExchangeError);
+        }
+
+        let key = match algorithm.certified()
+        {
+            Some(_) => Certificate::read(&host_key)?.key,
+            None => public_key::read_public_key(&mut Cursor::new(&host_key))?,
+        };
+        if key.system().id != algorithm.system().id {
+            error!("Host key does not match {}", algorithm);
+            return Err(ConnectionError::KeyExchangeError);
         }
+
+        info!(
+            "Server host key: {} {}",
+            algorithm,
+            key.fingerprint(FingerprintHash::Sha256)
+        );
+
+        if key.verify(&hash, &signature) != Ok(true) {
+            error!("Host key signature verification failed");
+            return Err(ConnectionError::KeyExchangeError);
+        }
+
+        self.exchange_hash = Some(hash);
+        self.shared_secret = Some(shared_secret);
+        self.host_key = Some(host_key);
+
+        Ok(())
     }
 }
 
 impl KeyExchange for Curve25519 {
+    fn initiate(&mut self, conn: &mut Connection) -> Option<Packet> {
+        match &conn.conn_type
+        {
+            &ConnectionType::Client(_) => {
+                let secret = Self::generate_secret();
+                let public = curve25519::curve25519_base(&secret);
+                self.secret = Some(secret);
+
+                let mut packet =
+                    Packet::new(MessageType::KeyExchange(ECDH_KEX_INIT));
+                packet.write_bytes(&public).ok()?;
+                Some(packet)
+            }
+            &ConnectionType::Server(_) => None,
+        }
+    }
+
     fn shared_secret<'a>(&'a self) -> Option<&'a [u8]> {
         self.shared_secret.as_ref().map(|x| x as &[u8])
     }
@@ -34,6 +253,10 @@ impl KeyExchange for Curve25519 {
         self.exchange_hash.as_ref().map(|x| x.as_slice())
     }
 
+    fn host_key<'a>(&'a self) -> Option<&'a [u8]> {
+        self.host_key.as_ref().map(|x| x.as_slice())
+    }
+
     fn hash(&self, data: &[&[u8]]) -> Vec<u8> {
         let mut hash = [0; 32];
         let mut hasher = Sha256::new();
@@ -47,90 +270,97 @@ impl KeyExchange for Curve25519 {
     }
 
     fn process(&mut self, conn: &mut Connection, packet: Packet) -> KexResult {
-        match packet.msg_type()
+        let result = match packet.msg_type()
         {
             MessageType::KeyExchange(ECDH_KEX_INIT) => {
-                let mut reader = packet.reader();
-                let client_public = reader.read_string().unwrap();
-
-                let config = match &conn.conn_type
-                {
-                    &ConnectionType::Server(ref config) => config.clone(),
-                    _ => return KexResult::Error,
-                };
-
-                let public_key = {
-                    let mut key = Vec::new();
-                    config.as_ref().key.write_public(&mut key).unwrap();
-                    key
-                };
-
-                let mut packet =
-                    Packet::new(MessageType::KeyExchange(ECDH_KEX_REPLY));
-
-                let server_secret = {
-                    let mut secret = [0; 32];
-                    let mut rng = rand::thread_rng();
-                    rng.fill_bytes(&mut secret);
-
-                    secret[0] &= 248;
-                    secret[31] &= 127;
-                    secret[31] |= 64;
-
-                    secret
-                };
-
-                let server_public = curve25519::curve25519_base(&server_secret);
-                let shared_secret = {
-                    let mut buf = Vec::new();
-                    buf.write_mpint(BigInt::from_bytes_be(
-                        Sign::Plus,
-                        &curve25519::curve25519(&server_secret, &client_public),
-                    )).ok();
-                    buf
-                };
-
-                let hash_data = {
-                    let mut buf = Vec::new();
-                    let data = &conn.hash_data;
-
-                    let items =
-                        [
-                            data.client_id.as_ref().unwrap().as_bytes(),
-                            data.server_id.as_ref().unwrap().as_bytes(),
-                            data.client_kexinit.as_ref().unwrap().as_slice(),
-                            data.server_kexinit.as_ref().unwrap().as_slice(),
-                            public_key.as_slice(),
-                            client_public.as_slice(),
-                            &server_public,
-                        ];
-
-                    for item in items.iter() {
-                        buf.write_bytes(item).ok();
-                    }
-
-                    buf.write_raw_bytes(&shared_secret).ok();
-
-                    buf
-                };
-
-                // Calculate hash
-                let hash = self.hash(&[hash_data.as_slice()]);
-                let signature = config.as_ref().key.sign(&hash).unwrap();
-
-                packet.write_bytes(public_key.as_slice()).unwrap();
-                packet.write_bytes(&server_public).unwrap();
-                packet.write_bytes(signature.as_slice()).unwrap(); // Signature
-
-                self.exchange_hash = Some(hash);
-                self.shared_secret = Some(shared_secret);
-
-                KexResult::Done(packet)
+                self.process_init(conn, packet).map(Some)
+            }
+            MessageType::KeyExchange(ECDH_KEX_REPLY) => {
+                self.process_reply(conn, packet).map(|_| None)
             }
             _ => {
                 debug!("Unhandled key exchange packet: {:?}", packet);
+                return KexResult::Error;
+            }
+        };
+
+        match result
+        {
+            Ok(packet) => KexResult::Done(packet),
+            Err(err) => {
+                error!("Key exchange failed: {}", err);
                 KexResult::Error
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn secret(hex: &str) -> [u8; 32] {
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&from_hex(hex));
+        secret
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        // RFC 7748, section 6.1
+        let alice = secret(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+        );
+        let bob = secret(
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+        );
+
+        let alice_public = curve25519::curve25519_base(&alice);
+        let bob_public = curve25519::curve25519_base(&bob);
+        assert_eq!(
+            alice_public.to_vec(),
+            from_hex(
+                "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eb\
+                 a4a98eaa9b4e6a",
+            )
+        );
+        assert_eq!(
+            bob_public.to_vec(),
+            from_hex(
+                "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dad\
+                 fc7e146f882b4f",
+            )
+        );
+
+        // The shared secret is encoded as mpint
+        let expected = from_hex(
+            "000000204a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e\
+             161742",
+        );
+        let shared = Curve25519::compute_shared_secret(&alice, &bob_public);
+        assert_eq!(shared.unwrap(), expected);
+        let shared = Curve25519::compute_shared_secret(&bob, &alice_public);
+        assert_eq!(shared.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_invalid_public_key() {
+        let secret = Curve25519::generate_secret();
+
+        assert!(Curve25519::compute_shared_secret(&secret, &[9; 31]).is_err());
+        assert!(Curve25519::compute_shared_secret(&secret, &[9; 33]).is_err());
+
+        // Low order points give an all-zero shared secret
+        let mut one = [0; 32];
+        one[0] = 1;
+        assert!(Curve25519::compute_shared_secret(&secret, &[0; 32]).is_err());
+        assert!(Curve25519::compute_shared_secret(&secret, &one).is_err());
+    }
+}
@@ -9,13 +9,17 @@ use packet::Packet;
 
 pub enum KexResult {
     Ok(Packet),
-    Done(Packet),
+    Done(Option<Packet>),
     Error,
 }
 
 pub trait KeyExchange {
+    /// Returns the first key exchange packet if we have to send it
+    fn initiate(&mut self, conn: &mut Connection) -> Option<Packet>;
     fn process(&mut self, conn: &mut Connection, packet: Packet) -> KexResult;
     fn shared_secret<'a>(&'a self) -> Option<&'a [u8]>;
     fn exchange_hash<'a>(&'a self) -> Option<&'a [u8]>;
+    /// The host key blob the server presented, which may be a certificate
+    fn host_key<'a>(&'a self) -> Option<&'a [u8]>;
     fn hash(&self, data: &[&[u8]]) -> Vec<u8>;
 }
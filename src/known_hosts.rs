@@ -0,0 +1,329 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::io::ErrorKind::InvalidData;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use config::match_pattern_list;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use packet::ReadPacketExt;
+use public_key::{self, Certificate, CertificateType, KeyPair};
+use rustc_serialize::base64::FromBase64;
+
+/// Location of the user's known_hosts file, relative to the home directory
+pub const USER_PATH: &str = ".ssh/known_hosts";
+
+/// Location of the system wide known_hosts file
+pub const SYSTEM_PATH: &str = "/etc/ssh/ssh_known_hosts";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+struct Entry {
+    marker: Option<Marker>,
+    /// Comma separated host patterns, or a hashed host name
+    hosts: String,
+    blob: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKeyStatus {
+    /// The key, or the CA that signed its certificate, is known for the host
+    Known,
+    /// The host is known with another key of the same type
+    Changed,
+    /// No key of this type is known for the host
+    Unknown,
+    /// The key, or the CA that signed its certificate, is revoked
+    Revoked,
+}
+
+/// The entries of known_hosts files, see sshd(8)
+#[derive(Default)]
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    pub fn new() -> KnownHosts {
+        KnownHosts::default()
+    }
+
+    /// Reads the entries of a known_hosts file. Like in OpenSSH, a missing
+    /// file has no entries and invalid lines are skipped.
+    pub fn read(&mut self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path)
+        {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            if let Err(err) = self.parse_line(&line?) {
+                debug!("{}:{}: {}", path.display(), number + 1, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the entry of a line: an optional marker, the host patterns and
+    /// the public key
+    pub fn parse_line(&mut self, line: &str) -> io::Result<()> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let mut fields = line.splitn(2, char::is_whitespace);
+        let mut hosts = fields.next().unwrap_or("");
+        let mut rest = fields.next().unwrap_or("").trim_start();
+
+        let marker = match hosts
+        {
+            "@cert-authority" => Some(Marker::CertAuthority),
+            "@revoked" => Some(Marker::Revoked),
+            _ if hosts.starts_with('@') => {
+                return Err(io::Error::new(InvalidData, "unknown marker"))
+            }
+            _ => None,
+        };
+
+        if marker.is_some() {
+            let mut fields = rest.splitn(2, char::is_whitespace);
+            hosts = fields.next().unwrap_or("");
+            rest = fields.next().unwrap_or("").trim_start();
+        }
+
+        let key = public_key::parse_public_key_line(rest)?;
+        let mut blob = Vec::new();
+        key.write_public(&mut blob)?;
+
+        self.entries.push(Entry {
+            marker: marker,
+            hosts: hosts.to_string(),
+            blob: blob,
+        });
+
+        Ok(())
+    }
+
+    /// Checks a host key blob, which may be a certificate, against the
+    /// entries for a host
+    pub fn check(&self, host: &str, port: u16, blob: &[u8]) -> HostKeyStatus {
+        let name = host_name(host, port);
+        let entries: Vec<&Entry> = self.entries
+            .iter()
+            .filter(|entry| host_matches(&entry.hosts, &name))
+            .collect();
+
+        match Certificate::read(blob)
+        {
+            Ok(cert) => check_certificate(&entries, host, &cert),
+            Err(_) => check_key(&entries, blob),
+        }
+    }
+
+    /// Whether a CA is trusted to sign the host keys of a host, in which
+    /// case certificates should be preferred
+    pub fn has_cert_authority(&self, host: &str, port: u16) -> bool {
+        let name = host_name(host, port);
+        self.entries.iter().any(|entry| {
+            entry.marker == Some(Marker::CertAuthority) &&
+                host_matches(&entry.hosts, &name)
+        })
+    }
+}
+
+fn check_key(entries: &[&Entry], blob: &[u8]) -> HostKeyStatus {
+    let with_marker = |marker| {
+        entries.iter().filter(
+            move |entry| entry.marker == marker,
+        )
+    };
+
+    if with_marker(Some(Marker::Revoked)).any(|entry| entry.blob == blob) {
+        return HostKeyStatus::Revoked;
+    }
+
+    if with_marker(None).any(|entry| entry.blob == blob) {
+        return HostKeyStatus::Known;
+    }
+
+    let algorithm = key_type(blob);
+    if with_marker(None).any(|entry| key_type(&entry.blob) == algorithm) {
+        HostKeyStatus::Changed
+    }
+    else {
+        HostKeyStatus::Unknown
+    }
+}
+
+/// Checks a host certificate against the CAs of a host. Certificates that
+/// are not trusted are checked like their plain key, like in OpenSSH.
+fn check_certificate(entries: &[&Entry], host: &str, cert: &Certificate)
+    -> HostKeyStatus {
+    let (mut key_blob, mut ca_blob) = (Vec::new(), Vec::new());
+    if cert.key.write_public(&mut key_blob).is_err() ||
+        cert.signature_key.write_public(&mut ca_blob).is_err()
+    {
+        return HostKeyStatus::Unknown;
+    }
+
+    let revoked = entries.iter().any(|entry| {
+        entry.marker == Some(Marker::Revoked) &&
+            (entry.blob == key_blob || entry.blob == ca_blob)
+    });
+
+    if revoked {
+        return HostKeyStatus::Revoked;
+    }
+
+    let trusted = entries
+        .iter()
+        .filter(|entry| entry.marker == Some(Marker::CertAuthority))
+        .filter_map(|entry| read_key(&entry.blob))
+        .any(|ca| cert.is_signed_by(&[ca]));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    if trusted {
+        match cert.validate(CertificateType::Host, host, now, None)
+        {
+            Ok(()) => return HostKeyStatus::Known,
+            Err(err) => debug!("Invalid host certificate: {}", err),
+        }
+    }
+    else {
+        debug!("No trusted CA for host certificate {:?}", cert.key_id);
+    }
+
+    check_key(entries, &key_blob)
+}
+
+fn read_key(blob: &[u8]) -> Option<Box<KeyPair>> {
+    public_key::read_public_key(&mut &blob[..]).ok()
+}
+
+fn key_type(blob: &[u8]) -> Option<String> {
+    (&blob[..]).read_utf8().ok()
+}
+
+/// Matches a host name against the host field of an entry, which is either
+/// a hashed host name `|1|salt|hash` or a list of patterns
+fn host_matches(hosts: &str, name: &str) -> bool {
+    if !hosts.starts_with("|1|") {
+        return match_pattern_list(hosts, name);
+    }
+
+    let mut parts = hosts[3..].splitn(2, '|');
+    let salt = parts.next().and_then(|salt| salt.from_base64().ok());
+    let hash = parts.next().and_then(|hash| hash.from_base64().ok());
+
+    match (salt, hash)
+    {
+        (Some(salt), Some(hash)) => hash_host_name(&salt, name) == hash,
+        _ => false,
+    }
+}
+
+/// Hashes a host name as with HashKnownHosts in OpenSSH
+fn hash_host_name(salt: &[u8], name: &str) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha1::new(), salt);
+    hmac.input(name.as_bytes());
+    hmac.result().code().to_vec()
+}
+
+/// The name of a host in known_hosts files, with the port if it is not the
+/// default port
+pub fn host_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    }
+    else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Appends a host key to a known_hosts file, which is created if needed
+pub fn add_host_key(path: &Path, host: &str, port: u16, key: &KeyPair)
+    -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let line = public_key::format_public_key_line(key, "")?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", host_name(host, port), line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAID1iLjT2nUpeOK\
+                            AH4fMbKElDDHoNo2WqOZYIcTH733/2";
+    const OTHER_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMTctE+qWAgI+\
+                             W4HrqmNEBLO2dHDMUAPxOiQy26RsMt4";
+
+    fn blob(line: &str) -> Vec<u8> {
+        let key = public_key::parse_public_key_line(line).unwrap();
+        let mut blob = Vec::new();
+        key.write_public(&mut blob).unwrap();
+        blob
+    }
+
+    #[test]
+    fn test_check() {
+        let mut known_hosts = KnownHosts::new();
+        for line in [
+            format!("*.example.com,!evil.example.com {}", HOST_KEY),
+            format!("@revoked old.example.com {}", HOST_KEY),
+            format!("other.example.org {}", OTHER_KEY),
+            "@unknown-marker * ssh-ed25519 AAAA".to_string(),
+        ].iter()
+        {
+            let _ = known_hosts.parse_line(line);
+        }
+
+        let check = |host| known_hosts.check(host, 22, &blob(HOST_KEY));
+        assert_eq!(check("www.example.com"), HostKeyStatus::Known);
+        assert_eq!(check("evil.example.com"), HostKeyStatus::Unknown);
+        assert_eq!(check("old.example.com"), HostKeyStatus::Revoked);
+        assert_eq!(check("other.example.org"), HostKeyStatus::Changed);
+        assert_eq!(check("example.org"), HostKeyStatus::Unknown);
+        assert!(!known_hosts.has_cert_authority("www.example.com", 22));
+    }
+
+    #[test]
+    fn test_hashed_host() {
+        // Hashed with ssh-keygen -H
+        let mut known_hosts = KnownHosts::new();
+        let hosts = "|1|sdeNk/dn7fV1y/w8nJf170my9gg=|\
+                     vahfSZjVBz+FAHZ0/KrQPYh/mFE=";
+        known_hosts
+            .parse_line(&format!("{} {}", hosts, HOST_KEY))
+            .unwrap();
+
+        let blob = blob(HOST_KEY);
+        assert_eq!(
+            known_hosts.check("127.0.0.1", 2222, &blob),
+            HostKeyStatus::Known
+        );
+        assert_eq!(
+            known_hosts.check("127.0.0.1", 22, &blob),
+            HostKeyStatus::Unknown
+        );
+    }
+}
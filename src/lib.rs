@@ -1,11 +1,15 @@
 extern crate byteorder;
 extern crate rand;
+extern crate rustc_serialize;
 extern crate crypto;
+extern crate flate2;
 extern crate num_bigint;
 #[macro_use]
 extern crate log;
 #[cfg(target_os = "redox")]
 extern crate syscall;
+#[cfg(target_os = "redox")]
+extern crate redox_termios;
 #[cfg(not(target_os = "redox"))]
 extern crate libc;
 
@@ -17,10 +21,14 @@ mod connection;
 mod key_exchange;
 mod encryption;
 mod mac;
+mod compression;
 mod channel;
 
+pub mod config;
+pub mod known_hosts;
 pub mod public_key;
 pub mod server;
+pub mod client;
 
 #[cfg(target_os = "redox")]
 #[path = "sys/redox.rs"]
@@ -30,4 +38,9 @@ pub mod sys;
 #[path = "sys/unix.rs"]
 pub mod sys;
 
-pub use self::server::{Server, ServerConfig};
+pub use self::algorithm::{EncryptionAlgorithm, KeyExchangeAlgorithm,
+                          MacAlgorithm};
+pub use self::client::{Client, ClientConfig};
+pub use self::connection::RekeyLimit;
+pub use self::error::{ConnectionError, ConnectionResult};
+pub use self::server::{Listener, Server, ServerConfig};
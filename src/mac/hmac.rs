@@ -1,22 +1,21 @@
+use crypto::digest::Digest;
 use crypto::hmac::Hmac as rcHmac;
 use crypto::mac::Mac;
-use crypto::sha2::Sha256;
 use mac::MacAlgorithm;
 
-pub struct Hmac {
-    hmac: Box<rcHmac<Sha256>>,
+pub struct Hmac<D: Digest> {
+    hmac: Box<rcHmac<D>>,
 }
 
-impl Hmac {
-    pub fn new(key: &[u8]) -> Hmac {
-        let digest = Sha256::new();
+impl<D: Digest> Hmac<D> {
+    pub fn new(digest: D, key: &[u8]) -> Hmac<D> {
         Hmac { hmac: Box::new(rcHmac::new(digest, key)) }
     }
 }
 
-impl MacAlgorithm for Hmac {
+impl<D: Digest> MacAlgorithm for Hmac<D> {
     fn size(&self) -> usize {
-        32
+        self.hmac.output_bytes()
     }
 
     fn sign(&mut self, data: &[u8], seq: u32, buf: &mut [u8]) {
@@ -1,6 +1,8 @@
 mod hmac;
+mod umac;
 
 pub use self::hmac::Hmac;
+pub use self::umac::Umac;
 
 pub trait MacAlgorithm {
     fn size(&self) -> usize;
@@ -6,6 +6,7 @@ pub enum MessageType {
     Debug,
     ServiceRequest,
     ServiceAccept,
+    ExtInfo,
     KexInit,
     NewKeys,
     KeyExchange(u8),
@@ -31,6 +32,46 @@ pub enum MessageType {
     Unknown,
 }
 
+impl MessageType {
+    /// Whether the message may be sent during a key exchange,
+    /// see RFC 4253, section 7.1
+    pub fn is_kex_allowed(&self) -> bool {
+        use self::MessageType::*;
+        match *self
+        {
+            Disconnect | Ignore | Unimplemented | Debug | KexInit |
+            NewKeys | KeyExchange(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the message may be received during the initial key exchange
+    /// with strict key exchange, which also forbids IGNORE and DEBUG
+    pub fn is_strict_kex_allowed(&self) -> bool {
+        use self::MessageType::*;
+        match *self
+        {
+            Disconnect | KexInit | NewKeys | KeyExchange(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the message belongs to the connection protocol, which is only
+    /// available after user authentication, see RFC 4254
+    pub fn is_connection(&self) -> bool {
+        use self::MessageType::*;
+        match *self
+        {
+            GlobalRequest | RequestSuccess | RequestFailure | ChannelOpen |
+            ChannelOpenConfirmation | ChannelOpenFailure |
+            ChannelWindowAdjust | ChannelData | ChannelExtendedData |
+            ChannelEOF | ChannelClose | ChannelRequest | ChannelSuccess |
+            ChannelFailure => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<u8> for MessageType {
     fn from(id: u8) -> Self {
         use self::MessageType::*;
@@ -42,6 +83,7 @@ impl From<u8> for MessageType {
             4 => Debug,
             5 => ServiceRequest,
             6 => ServiceAccept,
+            7 => ExtInfo,
             20 => KexInit,
             21 => NewKeys,
             30..=49 => KeyExchange(id),
@@ -80,6 +122,7 @@ impl Into<u8> for MessageType {
             Debug => 4,
             ServiceRequest => 5,
             ServiceAccept => 6,
+            ExtInfo => 7,
             KexInit => 20,
             NewKeys => 21,
             KeyExchange(id) => id,
@@ -7,6 +7,9 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use message::MessageType;
 use num_bigint::BigInt;
 
+/// Largest packet we accept, including padding
+pub const MAX_PACKET_LEN: usize = 256 * 1024;
+
 pub enum Packet {
     Raw(Vec<u8>, usize),
     Payload(Vec<u8>),
@@ -29,7 +32,12 @@ impl Packet {
         let packet_len = stream.read_uint32()? as usize;
         trace!("Reading incoming packet ({} bytes)", packet_len);
 
-        // TODO: Prevent packets that are too large
+        if packet_len > MAX_PACKET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "packet too large",
+            ));
+        }
 
         let mut raw = Vec::with_capacity(packet_len + 4);
         raw.write_uint32(packet_len as u32)?;
@@ -37,17 +45,36 @@ impl Packet {
         let count = stream.take(packet_len as u64).read_to_end(&mut raw)?;
 
         if count == packet_len {
-            let padding_len = raw[4] as usize;
-            let payload_len = packet_len - padding_len - 1;
             // TODO: Verify packet size (mod 8)
-            Ok(Packet::Raw(raw, payload_len))
+            Packet::from_raw(raw)
         }
         else {
             Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken stream"))
         }
     }
 
+    /// Creates a packet from decrypted data that starts with the length
+    pub fn from_raw(raw: Vec<u8>) -> Result<Packet> {
+        let packet_len = raw.len().saturating_sub(4);
+        let padding_len = *raw.get(4).unwrap_or(&0) as usize;
+
+        if packet_len < padding_len + 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid padding length",
+            ));
+        }
+
+        let payload_len = packet_len - padding_len - 1;
+        Ok(Packet::Raw(raw, payload_len))
+    }
+
     pub fn write_to<W: io::Write>(&self, stream: &mut W) -> Result<()> {
+        self.write_aligned(stream, true)
+    }
+
+    fn write_aligned<W: io::Write>(&self, stream: &mut W, align_length: bool)
+        -> Result<()> {
         match self
         {
             &Packet::Raw(ref data, _) => {
@@ -55,7 +82,7 @@ impl Packet {
                 stream.flush()
             }
             &Packet::Payload(ref payload) => {
-                let padding_len = self.padding_len();
+                let padding_len = self.padding_len(align_length);
                 let packet_len = payload.len() + padding_len + 1;
 
                 stream.write_u32::<BigEndian>(packet_len as u32)?;
@@ -84,13 +111,15 @@ impl Packet {
         }
     }
 
-    pub fn to_raw(self) -> Result<Packet> {
+    /// Adds length and padding to the payload. The length field is left
+    /// out of the alignment when it is not encrypted together with the rest.
+    pub fn to_raw(self, align_length: bool) -> Result<Packet> {
         match self
         {
             Packet::Raw(_, _) => Ok(self),
             Packet::Payload(ref payload) => {
                 let mut buf = Vec::with_capacity(payload.len());
-                self.write_to(&mut buf)?;
+                self.write_aligned(&mut buf, align_length)?;
                 Ok(Packet::Raw(buf, payload.len()))
             }
         }
@@ -116,11 +145,12 @@ impl Packet {
         }
     }
 
-    pub fn padding_len(&self) -> usize {
+    pub fn padding_len(&self, align_length: bool) -> usize {
         let align = 32;
+        let unpadded_len = self.payload_len() + if align_length { 5 } else { 1 };
 
         // Calculate the padding to reach a multiple of 8 bytes
-        let padding_len = align - ((self.payload_len() + 5) % align);
+        let padding_len = align - (unpadded_len % align);
 
         // The padding has to be at least 4 bytes long
         if padding_len < 4 {
@@ -166,6 +196,10 @@ pub trait ReadPacketExt: ReadBytesExt {
         Ok(self.read_u32::<BigEndian>()?)
     }
 
+    fn read_uint64(&mut self) -> Result<u64> {
+        Ok(self.read_u64::<BigEndian>()?)
+    }
+
     fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
         let mut buffer = Vec::with_capacity(len);
         self.take(len as u64).read_to_end(&mut buffer)?;
@@ -0,0 +1,76 @@
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::InvalidData;
+
+use crypto::digest::Digest;
+use num_bigint::{BigInt, BigUint, Sign};
+use packet::{ReadPacketExt, WritePacketExt};
+use rand::Rng;
+
+pub fn digest<D: Digest>(mut digest: D, data: &[u8]) -> Vec<u8> {
+    let mut hash = vec![0; digest.output_bytes()];
+    digest.input(data);
+    digest.result(&mut hash);
+    hash
+}
+
+pub fn read_biguint(mut r: &mut Read) -> io::Result<BigUint> {
+    r.read_mpint()?.to_biguint().ok_or(
+        io::Error::new(InvalidData, "negative mpint"),
+    )
+}
+
+pub fn write_biguint(w: &mut Write, value: &BigUint) -> io::Result<()> {
+    w.write_mpint(BigInt::from_biguint(Sign::Plus, value.clone()))
+}
+
+/// Encodes a number as big endian bytes of a fixed length
+pub fn to_bytes(value: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut result = vec![0; len.saturating_sub(bytes.len())];
+    result.extend_from_slice(&bytes);
+    result
+}
+
+pub fn random_bits(bits: usize) -> BigUint {
+    let mut bytes = vec![0; (bits + 7) / 8];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    // Clear the excess bits of the most significant byte
+    let excess = bytes.len() * 8 - bits;
+    bytes[0] &= 0xff >> excess;
+
+    BigUint::from_bytes_be(&bytes)
+}
+
+/// Picks a random number in the range [2, limit)
+pub fn random_below(limit: &BigUint) -> BigUint {
+    loop {
+        let value = random_bits(limit.bits() as usize);
+        if &value < limit && value.bits() > 1 {
+            return value;
+        }
+    }
+}
+
+/// Computes the inverse of a modulo m with the extended Euclidean algorithm
+pub fn mod_inverse(a: &BigUint, m: &BigUint) -> Option<BigUint> {
+    let m = BigInt::from_biguint(Sign::Plus, m.clone());
+    let (mut old_r, mut r) = (BigInt::from_biguint(Sign::Plus, a.clone()), m.clone());
+    let (mut old_s, mut s) = (BigInt::from(1), BigInt::from(0));
+
+    while r.bits() > 0 {
+        let quotient = &old_r / &r;
+        let next_r = &old_r - &quotient * &r;
+        old_r = r;
+        r = next_r;
+        let next_s = &old_s - &quotient * &s;
+        old_s = s;
+        s = next_s;
+    }
+
+    if old_r != BigInt::from(1) {
+        return None;
+    }
+
+    ((old_s % &m + &m) % &m).to_biguint()
+}
@@ -0,0 +1,242 @@
+use std::io::{self, Cursor, Write};
+use std::io::ErrorKind::InvalidData;
+use std::net::IpAddr;
+
+use config::address_matches;
+use packet::{ReadPacketExt, WritePacketExt};
+use public_key::{KeyPair, read_public_key, read_public_key_data};
+
+const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CertificateType {
+    User,
+    Host,
+}
+
+/// An OpenSSH certificate, a public key signed by a certificate authority
+/// together with restrictions on its use, see PROTOCOL.certkeys in OpenSSH
+pub struct Certificate {
+    pub key: Box<KeyPair>,
+    pub serial: u64,
+    pub cert_type: CertificateType,
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    pub critical_options: Vec<(String, String)>,
+    pub extensions: Vec<String>,
+    pub signature_key: Box<KeyPair>,
+    blob: Vec<u8>,
+    signed_len: usize,
+    signature: Vec<u8>,
+}
+
+impl Certificate {
+    /// Parses a certificate blob as it is sent on the wire
+    pub fn read(blob: &[u8]) -> io::Result<Certificate> {
+        let mut r = Cursor::new(blob);
+        let algorithm = r.read_utf8()?;
+        let key_algorithm = certified_algorithm(&algorithm).ok_or(
+            io::Error::new(InvalidData, "not a certificate"),
+        )?;
+
+        let _nonce = r.read_string()?;
+        let key = read_public_key_data(&key_algorithm, &mut r)?;
+        let serial = r.read_uint64()?;
+
+        let cert_type = match r.read_uint32()?
+        {
+            1 => CertificateType::User,
+            2 => CertificateType::Host,
+            _ => {
+                return Err(io::Error::new(InvalidData, "invalid cert type"));
+            }
+        };
+
+        let key_id = r.read_utf8()?;
+        let principals = read_strings(&r.read_string()?)?;
+        let valid_after = r.read_uint64()?;
+        let valid_before = r.read_uint64()?;
+        let critical_options = read_options(&r.read_string()?)?;
+        let extensions = read_options(&r.read_string()?)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let _reserved = r.read_string()?;
+        let signature_key =
+            read_public_key(&mut Cursor::new(r.read_string()?))?;
+
+        let signed_len = r.position() as usize;
+        let signature = r.read_string()?;
+
+        Ok(Certificate {
+            key: key,
+            serial: serial,
+            cert_type: cert_type,
+            key_id: key_id,
+            principals: principals,
+            valid_after: valid_after,
+            valid_before: valid_before,
+            critical_options: critical_options,
+            extensions: extensions,
+            signature_key: signature_key,
+            blob: blob.to_vec(),
+            signed_len: signed_len,
+            signature: signature,
+        })
+    }
+
+    /// Writes the certificate blob
+    pub fn write(&self, w: &mut Write) -> io::Result<()> {
+        w.write_raw_bytes(&self.blob)
+    }
+
+    /// Checks that the certificate was signed by one of the given CAs. Like
+    /// OpenSSH, SHA-1 RSA signatures are not accepted from CAs.
+    pub fn is_signed_by(&self, authorities: &[Box<KeyPair>]) -> bool {
+        let mut ca_blob = Vec::new();
+        if self.signature_key.write_public(&mut ca_blob).is_err() {
+            return false;
+        }
+
+        let trusted = authorities.iter().any(|ca| {
+            let mut blob = Vec::new();
+            ca.write_public(&mut blob).is_ok() && blob == ca_blob
+        });
+
+        let algorithm = Cursor::new(&self.signature).read_utf8();
+
+        trusted && algorithm.ok().map_or(false, |a| a != "ssh-rsa") &&
+            self.signature_key.verify(
+                &self.blob[..self.signed_len],
+                &self.signature,
+            ) == Ok(true)
+    }
+
+    /// Checks the type, validity period, principals and critical options
+    /// of the certificate for a connection from the given address
+    pub fn validate(
+        &self,
+        cert_type: CertificateType,
+        principal: &str,
+        now: u64,
+        address: Option<IpAddr>,
+    ) -> io::Result<()> {
+        let error = |reason| Err(io::Error::new(InvalidData, reason));
+
+        if self.cert_type != cert_type {
+            return error("wrong certificate type");
+        }
+
+        if now < self.valid_after || now >= self.valid_before {
+            return error("certificate is not valid at this time");
+        }
+
+        // A certificate without principals would be valid for everyone
+        if !self.principals.iter().any(|p| p == principal) {
+            return error("principal not listed in certificate");
+        }
+
+        for &(ref name, ref value) in self.critical_options.iter() {
+            match &**name
+            {
+                "force-command" => (),
+                "source-address" => {
+                    let allowed = address.map_or(false, |address| {
+                        value.split(',').any(|n| address_matches(address, n))
+                    });
+
+                    if !allowed {
+                        return error("source address not allowed");
+                    }
+                }
+                _ => return error("unsupported critical option"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The command that has to be run instead of the one requested
+    pub fn force_command(&self) -> Option<&str> {
+        self.critical_options
+            .iter()
+            .find(|&&(ref name, _)| name == "force-command")
+            .map(|&(_, ref value)| &**value)
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|e| e == name)
+    }
+}
+
+/// Returns whether an algorithm name is a certificate algorithm
+pub fn is_certificate_algorithm(algorithm: &str) -> bool {
+    algorithm.ends_with(CERT_SUFFIX)
+}
+
+/// Returns the algorithm of the certified key for a certificate algorithm,
+/// e.g. "rsa-sha2-256" for "rsa-sha2-256-cert-v01@openssh.com"
+pub fn certified_algorithm(algorithm: &str) -> Option<String> {
+    if !is_certificate_algorithm(algorithm) {
+        return None;
+    }
+
+    let name = &algorithm[..algorithm.len() - CERT_SUFFIX.len()];
+    if name.starts_with("sk-") {
+        Some(format!("{}@openssh.com", name))
+    }
+    else {
+        Some(name.to_string())
+    }
+}
+
+/// Returns the certificate algorithm for the algorithm of a key, e.g.
+/// "rsa-sha2-256-cert-v01@openssh.com" for "rsa-sha2-256"
+pub fn certificate_algorithm(algorithm: &str) -> String {
+    let suffix = "@openssh.com";
+    let name = if algorithm.ends_with(suffix) {
+        &algorithm[..algorithm.len() - suffix.len()]
+    }
+    else {
+        algorithm
+    };
+
+    format!("{}{}", name, CERT_SUFFIX)
+}
+
+/// Reads a buffer of consecutive strings
+fn read_strings(data: &[u8]) -> io::Result<Vec<String>> {
+    let mut r = Cursor::new(data);
+    let mut strings = Vec::new();
+
+    while (r.position() as usize) < data.len() {
+        strings.push(r.read_utf8()?);
+    }
+
+    Ok(strings)
+}
+
+/// Reads critical options or extensions: pairs of a name and a string
+/// with the value, which may be empty
+fn read_options(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut r = Cursor::new(data);
+    let mut options = Vec::new();
+
+    while (r.position() as usize) < data.len() {
+        let name = r.read_utf8()?;
+        let data = r.read_string()?;
+
+        let value = if data.is_empty() {
+            String::new()
+        }
+        else {
+            Cursor::new(data).read_utf8()?
+        };
+
+        options.push((name, value));
+    }
+
+    Ok(options)
+}
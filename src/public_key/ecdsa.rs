@@ -0,0 +1,540 @@
+use std::io::{self, Read, Write};
+use std::io::Cursor;
+use std::io::ErrorKind::InvalidData;
+
+use crypto::sha2::{Sha256, Sha384, Sha512};
+use num_bigint::BigUint;
+use packet::{ReadPacketExt, WritePacketExt};
+use public_key::{CryptoSystem, KeyPair};
+use public_key::key_file;
+use public_key::bignum::{digest, mod_inverse, random_below, read_biguint,
+                         to_bytes, write_biguint};
+
+pub static ECDSA_NISTP256: CryptoSystem = CryptoSystem {
+    id: "ecdsa-sha2-nistp256",
+    name: "ecdsa",
+    signature_algorithms: &["ecdsa-sha2-nistp256"],
+    generate_key_pair: generate_nistp256,
+    import: key_file::import,
+    read_public: read_nistp256,
+    read_private: EcdsaKeyPair::read_private,
+};
+
+pub static ECDSA_NISTP384: CryptoSystem = CryptoSystem {
+    id: "ecdsa-sha2-nistp384",
+    name: "ecdsa",
+    signature_algorithms: &["ecdsa-sha2-nistp384"],
+    generate_key_pair: generate_nistp384,
+    import: key_file::import,
+    read_public: read_nistp384,
+    read_private: EcdsaKeyPair::read_private,
+};
+
+pub static ECDSA_NISTP521: CryptoSystem = CryptoSystem {
+    id: "ecdsa-sha2-nistp521",
+    name: "ecdsa",
+    signature_algorithms: &["ecdsa-sha2-nistp521"],
+    generate_key_pair: generate_nistp521,
+    import: key_file::import,
+    read_public: read_nistp521,
+    read_private: EcdsaKeyPair::read_private,
+};
+
+/// Domain parameters of a NIST prime curve y^2 = x^3 - 3x + b, see FIPS
+/// 186-4, appendix D.1.2
+struct Curve {
+    name: &'static str,
+    algorithm: &'static str,
+    system: &'static CryptoSystem,
+    hash: fn(&[u8]) -> Vec<u8>,
+    p: &'static str,
+    b: &'static str,
+    n: &'static str,
+    gx: &'static str,
+    gy: &'static str,
+}
+
+static NISTP256: Curve = Curve {
+    name: "nistp256",
+    algorithm: "ecdsa-sha2-nistp256",
+    system: &ECDSA_NISTP256,
+    hash: sha256,
+    p: "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+    b: "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b",
+    n: "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+    gx: "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+    gy: "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+};
+
+static NISTP384: Curve = Curve {
+    name: "nistp384",
+    algorithm: "ecdsa-sha2-nistp384",
+    system: &ECDSA_NISTP384,
+    hash: sha384,
+    p: "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe\
+        ffffffff0000000000000000ffffffff",
+    b: "b3312fa7e23ee7e4988e056be3f82d19181d9c6efe8141120314088f5013875a\
+        c656398d8a2ed19d2a85c8edd3ec2aef",
+    n: "ffffffffffffffffffffffffffffffffffffffffffffffffc7634d81f4372ddf\
+        581a0db248b0a77aecec196accc52973",
+    gx: "aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a38\
+         5502f25dbf55296c3a545e3872760ab7",
+    gy: "3617de4a96262c6f5d9e98bf9292dc29f8f41dbd289a147ce9da3113b5f0b8c0\
+         0a60b1ce1d7e819d7a431d7c90ea0e5f",
+};
+
+static NISTP521: Curve = Curve {
+    name: "nistp521",
+    algorithm: "ecdsa-sha2-nistp521",
+    system: &ECDSA_NISTP521,
+    hash: sha512,
+    p: "01ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\
+        ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\
+        ffff",
+    b: "0051953eb9618e1c9a1f929a21a0b68540eea2da725b99b315f3b8b489918ef1\
+        09e156193951ec7e937b1652c0bd3bb1bf073573df883d2c34f1ef451fd46b50\
+        3f00",
+    n: "01ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\
+        fffa51868783bf2f966b7fcc0148f709a5d03bb5c9b8899c47aebb6fb71e9138\
+        6409",
+    gx: "00c6858e06b70404e9cd9e3ecb662395b4429c648139053fb521f828af606b4d\
+         3dbaa14b5e77efe75928fe1dc127a2ffa8de3348b3c1856a429bf97e7e31c2e5\
+         bd66",
+    gy: "011839296a789a3bc0045c8a5fb42c7d1bd998f54449579b446817afbd17273e\
+         662c97ee72995ef42640c550b9013fad0761353c7086a272c24088be94769fd1\
+         6650",
+};
+
+static CURVES: &[&Curve] = &[&NISTP256, &NISTP384, &NISTP521];
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    digest(Sha256::new(), data)
+}
+
+fn sha384(data: &[u8]) -> Vec<u8> {
+    digest(Sha384::new(), data)
+}
+
+fn sha512(data: &[u8]) -> Vec<u8> {
+    digest(Sha512::new(), data)
+}
+
+fn generate_nistp256(_: Option<u32>) -> Box<KeyPair> {
+    EcdsaKeyPair::generate(&NISTP256)
+}
+
+fn generate_nistp384(_: Option<u32>) -> Box<KeyPair> {
+    EcdsaKeyPair::generate(&NISTP384)
+}
+
+fn generate_nistp521(_: Option<u32>) -> Box<KeyPair> {
+    EcdsaKeyPair::generate(&NISTP521)
+}
+
+fn read_nistp256(r: &mut Read) -> io::Result<Box<KeyPair>> {
+    EcdsaKeyPair::read_public(&NISTP256, r)
+}
+
+fn read_nistp384(r: &mut Read) -> io::Result<Box<KeyPair>> {
+    EcdsaKeyPair::read_public(&NISTP384, r)
+}
+
+fn read_nistp521(r: &mut Read) -> io::Result<Box<KeyPair>> {
+    EcdsaKeyPair::read_public(&NISTP521, r)
+}
+
+#[derive(Clone, PartialEq)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+}
+
+/// A point in jacobian coordinates, which avoids an inversion per addition.
+/// The point at infinity has z = 0.
+#[derive(Clone)]
+struct Jacobian {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+/// Curve parameters parsed for computations
+struct Params {
+    p: BigUint,
+    b: BigUint,
+    n: BigUint,
+    g: Point,
+    len: usize,
+}
+
+impl Curve {
+    fn params(&self) -> Params {
+        let parse =
+            |hex: &str| BigUint::parse_bytes(hex.as_bytes(), 16).unwrap();
+        let p = parse(self.p);
+
+        Params {
+            len: (p.bits() as usize + 7) / 8,
+            p: p,
+            b: parse(self.b),
+            n: parse(self.n),
+            g: Point {
+                x: parse(self.gx),
+                y: parse(self.gy),
+            },
+        }
+    }
+
+    fn by_algorithm(algorithm: &str) -> Option<&'static Curve> {
+        CURVES.iter().find(|c| c.algorithm == algorithm).cloned()
+    }
+}
+
+impl Params {
+    fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + b) % &self.p
+    }
+
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a * b) % &self.p
+    }
+
+    fn infinity(&self) -> Jacobian {
+        Jacobian {
+            x: BigUint::from(1u32),
+            y: BigUint::from(1u32),
+            z: BigUint::from(0u32),
+        }
+    }
+
+    fn is_infinity(&self, point: &Jacobian) -> bool {
+        point.z.bits() == 0
+    }
+
+    /// Point doubling with a = -3, "dbl-2001-b" from the Explicit-Formulas
+    /// Database
+    fn double(&self, point: &Jacobian) -> Jacobian {
+        if self.is_infinity(point) || point.y.bits() == 0 {
+            return self.infinity();
+        }
+
+        let delta = self.mul(&point.z, &point.z);
+        let gamma = self.mul(&point.y, &point.y);
+        let beta = self.mul(&point.x, &gamma);
+        let alpha = self.mul(
+            &(BigUint::from(3u32) * self.sub(&point.x, &delta)),
+            &self.add(&point.x, &delta),
+        );
+
+        let beta4 = (&beta << 2) % &self.p;
+        let x = self.sub(&self.mul(&alpha, &alpha), &self.add(&beta4, &beta4));
+
+        let yz = self.add(&point.y, &point.z);
+        let z = self.sub(&self.sub(&self.mul(&yz, &yz), &gamma), &delta);
+
+        let gamma_sq8 = (self.mul(&gamma, &gamma) << 3) % &self.p;
+        let y = self.sub(&self.mul(&alpha, &self.sub(&beta4, &x)), &gamma_sq8);
+
+        Jacobian { x: x, y: y, z: z }
+    }
+
+    /// General point addition, "add-1998-cmo-2" from the Explicit-Formulas
+    /// Database
+    fn add_points(&self, a: &Jacobian, b: &Jacobian) -> Jacobian {
+        if self.is_infinity(a) {
+            return b.clone();
+        }
+        if self.is_infinity(b) {
+            return a.clone();
+        }
+
+        let z1z1 = self.mul(&a.z, &a.z);
+        let z2z2 = self.mul(&b.z, &b.z);
+        let u1 = self.mul(&a.x, &z2z2);
+        let u2 = self.mul(&b.x, &z1z1);
+        let s1 = self.mul(&self.mul(&a.y, &b.z), &z2z2);
+        let s2 = self.mul(&self.mul(&b.y, &a.z), &z1z1);
+
+        if u1 == u2 {
+            return if s1 == s2 { self.double(a) } else { self.infinity() };
+        }
+
+        let h = self.sub(&u2, &u1);
+        let r = self.sub(&s2, &s1);
+        let hh = self.mul(&h, &h);
+        let hhh = self.mul(&hh, &h);
+        let v = self.mul(&u1, &hh);
+
+        let x = self.sub(
+            &self.sub(&self.mul(&r, &r), &hhh),
+            &self.add(&v, &v),
+        );
+        let y = self.sub(
+            &self.mul(&r, &self.sub(&v, &x)),
+            &self.mul(&s1, &hhh),
+        );
+        let z = self.mul(&self.mul(&a.z, &b.z), &h);
+
+        Jacobian { x: x, y: y, z: z }
+    }
+
+    /// Computes k * point with a Montgomery ladder over all bits of the
+    /// group order, which adds and doubles once per bit whatever its value.
+    /// This is not constant time: the `BigUint` arithmetic and the branch on
+    /// each bit still take time that depends on k.
+    fn multiply(&self, k: &BigUint, point: &Point) -> Jacobian {
+        let mut r0 = self.infinity();
+        let mut r1 = Jacobian {
+            x: point.x.clone(),
+            y: point.y.clone(),
+            z: BigUint::from(1u32),
+        };
+
+        for i in (0..self.n.bits()).rev() {
+            if k.bit(i) {
+                r0 = self.add_points(&r0, &r1);
+                r1 = self.double(&r1);
+            }
+            else {
+                r1 = self.add_points(&r0, &r1);
+                r0 = self.double(&r0);
+            }
+        }
+
+        r0
+    }
+
+    fn to_affine(&self, point: &Jacobian) -> Option<Point> {
+        if self.is_infinity(point) {
+            return None;
+        }
+
+        let z_inv = point.z.modpow(&(&self.p - 2u32), &self.p);
+        let z_inv2 = self.mul(&z_inv, &z_inv);
+
+        Some(Point {
+            x: self.mul(&point.x, &z_inv2),
+            y: self.mul(&point.y, &self.mul(&z_inv2, &z_inv)),
+        })
+    }
+
+    fn is_on_curve(&self, point: &Point) -> bool {
+        if point.x >= self.p || point.y >= self.p {
+            return false;
+        }
+
+        let x3 = self.mul(&self.mul(&point.x, &point.x), &point.x);
+        let ax = self.mul(&BigUint::from(3u32), &point.x);
+        let rhs = self.add(&self.sub(&x3, &ax), &self.b);
+
+        self.mul(&point.y, &point.y) == rhs
+    }
+
+    /// Encodes a point in uncompressed form, see SEC 1, section 2.3.3
+    fn encode_point(&self, point: &Point) -> Vec<u8> {
+        let mut result = vec![0x04];
+        result.extend(to_bytes(&point.x, self.len));
+        result.extend(to_bytes(&point.y, self.len));
+        result
+    }
+
+    fn decode_point(&self, data: &[u8]) -> Option<Point> {
+        if data.len() != 1 + 2 * self.len || data[0] != 0x04 {
+            return None;
+        }
+
+        let point = Point {
+            x: BigUint::from_bytes_be(&data[1..1 + self.len]),
+            y: BigUint::from_bytes_be(&data[1 + self.len..]),
+        };
+
+        if self.is_on_curve(&point) { Some(point) } else { None }
+    }
+
+    /// Converts a hash to an integer, truncated to the bit length of the
+    /// group order
+    fn hash_to_int(&self, hash: &[u8]) -> BigUint {
+        let value = BigUint::from_bytes_be(hash);
+        let bits = hash.len() as u64 * 8;
+
+        if bits > self.n.bits() {
+            value >> (bits - self.n.bits())
+        }
+        else {
+            value
+        }
+    }
+}
+
+struct EcdsaKeyPair {
+    curve: &'static Curve,
+    public: Point,
+    private: Option<BigUint>,
+}
+
+impl EcdsaKeyPair {
+    fn generate(curve: &'static Curve) -> Box<KeyPair> {
+        let params = curve.params();
+
+        loop {
+            let d = random_below(&params.n);
+
+            let public = params.to_affine(&params.multiply(&d, &params.g));
+
+            if let Some(public) = public {
+                return Box::new(EcdsaKeyPair {
+                    curve: curve,
+                    public: public,
+                    private: Some(d),
+                });
+            }
+        }
+    }
+
+    fn read_private(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
+        let curve = Curve::by_algorithm(&r.read_utf8()?).ok_or(
+            io::Error::new(InvalidData, "not a ECDSA key"),
+        )?;
+
+        let mut key = Self::read_point(curve, r)?;
+        let d = read_biguint(&mut r)?;
+
+        // The private key has to match the public key
+        let params = curve.params();
+        if d.bits() == 0 || d >= params.n ||
+            params.to_affine(&params.multiply(&d, &params.g)).as_ref() !=
+                Some(&key.public)
+        {
+            return Err(io::Error::new(InvalidData, "invalid ECDSA key"));
+        }
+
+        key.private = Some(d);
+        Ok(Box::new(key))
+    }
+
+    fn read_public(curve: &'static Curve, r: &mut Read)
+        -> io::Result<Box<KeyPair>> {
+        Ok(Box::new(Self::read_point(curve, r)?))
+    }
+
+    /// Reads the curve name and the public point
+    fn read_point(curve: &'static Curve, mut r: &mut Read)
+        -> io::Result<EcdsaKeyPair> {
+        if r.read_utf8()? != curve.name {
+            return Err(io::Error::new(InvalidData, "ECDSA curve mismatch"));
+        }
+
+        let public = curve.params().decode_point(&r.read_string()?).ok_or(
+            io::Error::new(InvalidData, "invalid ECDSA public key"),
+        )?;
+
+        Ok(EcdsaKeyPair {
+            curve: curve,
+            public: public,
+            private: None,
+        })
+    }
+
+    fn write_point(&self, w: &mut Write) -> io::Result<()> {
+        w.write_string(self.curve.algorithm)?;
+        w.write_string(self.curve.name)?;
+        w.write_bytes(&self.curve.params().encode_point(&self.public))
+    }
+}
+
+impl KeyPair for EcdsaKeyPair {
+    fn system(&self) -> &'static CryptoSystem {
+        self.curve.system
+    }
+
+    fn has_private(&self) -> bool {
+        self.private.is_some()
+    }
+
+    fn bits(&self) -> u32 {
+        self.curve.params().p.bits() as u32
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, ()> {
+        let mut reader = Cursor::new(signature);
+        if reader.read_utf8().or(Err(()))? != self.curve.algorithm {
+            return Err(());
+        }
+
+        // The signature blob contains r and s as mpints, see RFC 5656, 3.1.2
+        let mut blob = Cursor::new(reader.read_string().or(Err(()))?);
+        let r = read_biguint(&mut blob).or(Err(()))?;
+        let s = read_biguint(&mut blob).or(Err(()))?;
+
+        let params = self.curve.params();
+        if r.bits() == 0 || s.bits() == 0 || r >= params.n || s >= params.n {
+            return Ok(false);
+        }
+
+        let e = params.hash_to_int(&(self.curve.hash)(data));
+        let w = mod_inverse(&s, &params.n).ok_or(())?;
+        let u1 = (e * &w) % &params.n;
+        let u2 = (&r * &w) % &params.n;
+
+        let sum = params.add_points(
+            &params.multiply(&u1, &params.g),
+            &params.multiply(&u2, &self.public),
+        );
+
+        match params.to_affine(&sum)
+        {
+            Some(point) => Ok(point.x % &params.n == r),
+            None => Ok(false),
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let d = self.private.as_ref().ok_or(())?;
+        let params = self.curve.params();
+        let e = params.hash_to_int(&(self.curve.hash)(data));
+
+        let (r, s) = loop {
+            let k = random_below(&params.n);
+            let point = params.to_affine(&params.multiply(&k, &params.g));
+            let point = match point
+            {
+                Some(point) => point,
+                None => continue,
+            };
+
+            let r = point.x % &params.n;
+            let k_inv = mod_inverse(&k, &params.n).ok_or(())?;
+            let s = (k_inv * (&e + &r * d)) % &params.n;
+
+            if r.bits() > 0 && s.bits() > 0 {
+                break (r, s);
+            }
+        };
+
+        let mut blob = Vec::new();
+        write_biguint(&mut blob, &r).or(Err(()))?;
+        write_biguint(&mut blob, &s).or(Err(()))?;
+
+        let mut result = Vec::new();
+        result.write_string(self.curve.algorithm).or(Err(()))?;
+        result.write_bytes(&blob).or(Err(()))?;
+        Ok(result)
+    }
+
+    fn write_public(&self, w: &mut Write) -> io::Result<()> {
+        self.write_point(w)
+    }
+
+    fn write_private(&self, w: &mut Write) -> io::Result<()> {
+        let d = self.private.as_ref().ok_or(
+            io::Error::new(InvalidData, "not a private key"),
+        )?;
+
+        self.write_point(w)?;
+        write_biguint(w, d)
+    }
+}
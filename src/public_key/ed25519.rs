@@ -3,13 +3,17 @@ use std::io::ErrorKind::InvalidData;
 
 use crypto::ed25519;
 use public_key::{CryptoSystem, KeyPair};
+use public_key::key_file;
 use rand::Rng;
 
 pub static ED25519: CryptoSystem = CryptoSystem {
-    id: "ed25519",
+    id: "ssh-ed25519",
+    name: "ed25519",
+    signature_algorithms: &["ssh-ed25519"],
     generate_key_pair: Ed25519KeyPair::generate,
-    import: Ed25519KeyPair::import,
+    import: key_file::import,
     read_public: Ed25519KeyPair::read_public,
+    read_private: Ed25519KeyPair::read_private,
 };
 
 struct Ed25519KeyPair {
@@ -30,7 +34,7 @@ impl Ed25519KeyPair {
         })
     }
 
-    fn import(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
+    fn read_private(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
         use packet::ReadPacketExt;
 
         if r.read_utf8()? != "ssh-ed25519" {
@@ -83,6 +87,10 @@ impl KeyPair for Ed25519KeyPair {
         self.private.is_some()
     }
 
+    fn bits(&self) -> u32 {
+        256
+    }
+
     fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, ()> {
         use packet::ReadPacketExt;
         use std::io::Cursor;
@@ -92,6 +100,10 @@ impl KeyPair for Ed25519KeyPair {
 
         if id == b"ssh-ed25519" {
             if let Ok(sig) = reader.read_string() {
+                // ed25519::verify panics on signatures of another length
+                if sig.len() != 64 {
+                    return Ok(false);
+                }
                 return Ok(ed25519::verify(data, &self.public, sig.as_slice()));
             }
         }
@@ -118,13 +130,14 @@ impl KeyPair for Ed25519KeyPair {
         w.write_bytes(&self.public)
     }
 
-    fn export(&self, w: &mut Write) -> io::Result<()> {
+    fn write_private(&self, w: &mut Write) -> io::Result<()> {
         use packet::WritePacketExt;
+        let private_key = self.private.ok_or(
+            io::Error::new(InvalidData, "not a private key"),
+        )?;
+
         w.write_string("ssh-ed25519")?;
         w.write_bytes(&self.public)?;
-        if let Some(private_key) = self.private {
-            w.write_bytes(&private_key)?;
-        }
-        Ok(())
+        w.write_bytes(&private_key)
     }
 }
@@ -0,0 +1,110 @@
+use crypto::md5::Md5;
+use crypto::sha2::Sha256;
+use public_key::bignum::digest;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+
+/// The hash of a key fingerprint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FingerprintHash {
+    Sha256,
+    Md5,
+}
+
+impl FingerprintHash {
+    fn name(&self) -> &'static str {
+        match self
+        {
+            &FingerprintHash::Sha256 => "SHA256",
+            &FingerprintHash::Md5 => "MD5",
+        }
+    }
+
+    fn digest(&self, blob: &[u8]) -> Vec<u8> {
+        match self
+        {
+            &FingerprintHash::Sha256 => digest(Sha256::new(), blob),
+            &FingerprintHash::Md5 => digest(Md5::new(), blob),
+        }
+    }
+}
+
+// Size of the randomart field, see OpenSSH sshkey.c
+const FIELD_WIDTH: usize = 17;
+const FIELD_HEIGHT: usize = 9;
+const SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+/// Formats the fingerprint of a public key blob like OpenSSH, as unpadded
+/// base64 for SHA256 and as colon separated hex for MD5
+pub fn fingerprint(blob: &[u8], hash: FingerprintHash) -> String {
+    let digest = hash.digest(blob);
+
+    let encoded = match hash
+    {
+        FingerprintHash::Sha256 => {
+            digest.to_base64(STANDARD).trim_right_matches('=').to_string()
+        }
+        FingerprintHash::Md5 => {
+            let hex: Vec<String> =
+                digest.iter().map(|b| format!("{:02x}", b)).collect();
+            hex.join(":")
+        }
+    };
+
+    format!("{}:{}", hash.name(), encoded)
+}
+
+/// Draws the "drunken bishop" randomart of a public key blob, see "The
+/// drunken bishop: An analysis of the OpenSSH fingerprint visualization
+/// algorithm". The bishop starts in the center and moves diagonally, two
+/// bits of the digest at a time.
+pub fn randomart(blob: &[u8], hash: FingerprintHash, title: &str) -> String {
+    let mut field = [[0usize; FIELD_WIDTH]; FIELD_HEIGHT];
+    let last = SYMBOLS.len() - 1;
+    let (mut x, mut y) = (FIELD_WIDTH / 2, FIELD_HEIGHT / 2);
+
+    for byte in hash.digest(blob) {
+        let mut input = byte;
+        for _ in 0..4 {
+            x = if input & 0x1 != 0 {
+                (x + 1).min(FIELD_WIDTH - 1)
+            }
+            else {
+                x.saturating_sub(1)
+            };
+            y = if input & 0x2 != 0 {
+                (y + 1).min(FIELD_HEIGHT - 1)
+            }
+            else {
+                y.saturating_sub(1)
+            };
+
+            if field[y][x] < last - 2 {
+                field[y][x] += 1;
+            }
+            input >>= 2;
+        }
+    }
+
+    // Mark the start and the end of the walk
+    field[FIELD_HEIGHT / 2][FIELD_WIDTH / 2] = last - 1;
+    field[y][x] = last;
+
+    let mut art = frame(&format!("[{}]", title));
+    for row in field.iter() {
+        art.push('|');
+        art.extend(row.iter().map(|&count| SYMBOLS[count] as char));
+        art.push_str("|\n");
+    }
+    art.push_str(&frame(&format!("[{}]", hash.name())));
+    art.pop();
+    art
+}
+
+/// A horizontal border with a centered label
+fn frame(label: &str) -> String {
+    let label: String = label.chars().take(FIELD_WIDTH).collect();
+    let left = (FIELD_WIDTH - label.len()) / 2;
+    let right = FIELD_WIDTH - label.len() - left;
+
+    format!("+{}{}{}+\n", "-".repeat(left), label, "-".repeat(right))
+}
@@ -1,27 +1,231 @@
 use std::io::{self, Read, Write};
+use std::io::ErrorKind::InvalidData;
 
-// mod rsa;
+mod bignum;
+mod certificate;
+mod ecdsa;
 mod ed25519;
+mod fingerprint;
+mod key_file;
+mod rsa;
+mod sk;
 
-// pub use self::rsa::RSA;
-
+pub use self::certificate::{Certificate, CertificateType,
+                            certificate_algorithm, certified_algorithm,
+                            is_certificate_algorithm};
+pub use self::ecdsa::{ECDSA_NISTP256, ECDSA_NISTP384, ECDSA_NISTP521};
 pub use self::ed25519::ED25519;
+pub use self::fingerprint::FingerprintHash;
+pub use self::key_file::{export_private_key, is_encrypted_private_key,
+                         read_private_key};
+pub use self::rsa::{RSA, RSA_MAX_BITS, RSA_MIN_BITS};
+pub use self::sk::{SK_USER_PRESENCE, SK_USER_VERIFICATION, SkSignatureInfo,
+                   sk_signature_info};
 
 pub trait KeyPair: Sync + Send {
     fn system(&self) -> &'static CryptoSystem;
 
     fn has_private(&self) -> bool;
 
+    /// The key size in bits, as shown by ssh-keygen -l
+    fn bits(&self) -> u32;
+
     fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, ()>;
     fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ()>;
 
+    /// Signs with one of the system's signature algorithms. Key types with a
+    /// single signature algorithm only need to implement `sign`.
+    fn sign_with(&self, data: &[u8], algorithm: &str) -> Result<Vec<u8>, ()> {
+        if self.system().signature_algorithms.contains(&algorithm) {
+            self.sign(data)
+        }
+        else {
+            Err(())
+        }
+    }
+
     fn write_public(&self, w: &mut Write) -> io::Result<()>;
-    fn export(&self, w: &mut Write) -> io::Result<()>;
+
+    /// Writes the key as it is stored in the private section of an OpenSSH
+    /// key file: the algorithm name followed by the key components
+    fn write_private(&self, w: &mut Write) -> io::Result<()>;
+
+    /// Writes the private key in the OpenSSH key file format
+    fn export(&self, w: &mut Write) -> io::Result<()> {
+        export_private_key(self, "", None, w)
+    }
+
+    /// The fingerprint of the public key, e.g. "SHA256:..."
+    fn fingerprint(&self, hash: FingerprintHash) -> String {
+        let mut blob = Vec::new();
+        let _ = self.write_public(&mut blob);
+        fingerprint::fingerprint(&blob, hash)
+    }
+
+    /// The randomart picture of the public key, as shown by ssh-keygen -lv
+    fn randomart(&self, hash: FingerprintHash) -> String {
+        let mut blob = Vec::new();
+        let _ = self.write_public(&mut blob);
+        let title = self.system().name.to_uppercase();
+        fingerprint::randomart(&blob, hash, &title)
+    }
 }
 
 pub struct CryptoSystem {
+    /// The key type as it is sent on the wire, e.g. "ssh-ed25519"
     pub id: &'static str,
+    /// The name of the key type in key file names and for ssh-keygen -t,
+    /// e.g. "ecdsa" for all ECDSA curves
+    pub name: &'static str,
+    /// Names of the signature algorithms, ordered by preference
+    pub signature_algorithms: &'static [&'static str],
     pub generate_key_pair: fn(bits: Option<u32>) -> Box<KeyPair>,
+    /// Imports a private key in the OpenSSH key file format
     pub import: fn(r: &mut Read) -> io::Result<Box<KeyPair>>,
     pub read_public: fn(r: &mut Read) -> io::Result<Box<KeyPair>>,
+    /// Reads a key written by `KeyPair::write_private`
+    pub read_private: fn(r: &mut Read) -> io::Result<Box<KeyPair>>,
+}
+
+/// The crypto systems that can generate keys, in order of preference
+pub static CRYPTO_SYSTEMS: &[&CryptoSystem] = &[
+    &ED25519,
+    &ECDSA_NISTP256,
+    &ECDSA_NISTP384,
+    &ECDSA_NISTP521,
+    &RSA,
+];
+
+/// All known crypto systems, including security keys which can only verify
+static REGISTRY: &[&CryptoSystem] = &[
+    &ED25519,
+    &ECDSA_NISTP256,
+    &ECDSA_NISTP384,
+    &ECDSA_NISTP521,
+    &RSA,
+    &sk::SK_ED25519,
+    &sk::SK_ECDSA_NISTP256,
+];
+
+/// Finds the crypto system of a key type as it is sent on the wire, e.g.
+/// "ssh-rsa"
+pub fn system_by_id(id: &str) -> Option<&'static CryptoSystem> {
+    REGISTRY.iter().cloned().find(|system| system.id == id)
+}
+
+/// Finds the crypto system of a public key algorithm, which may also be a
+/// signature algorithm like "rsa-sha2-256" or a certificate algorithm
+pub fn system_by_algorithm(algorithm: &str)
+    -> Option<&'static CryptoSystem> {
+    let algorithm =
+        certified_algorithm(algorithm).unwrap_or_else(|| algorithm.to_string());
+
+    REGISTRY.iter().cloned().find(|system| {
+        system.id == algorithm ||
+            system.signature_algorithms.contains(&&*algorithm)
+    })
+}
+
+/// All signature algorithms that keys can be verified with, followed by
+/// the algorithms of certificates for them
+pub fn signature_algorithms() -> Vec<String> {
+    let algorithms: Vec<String> = REGISTRY
+        .iter()
+        .flat_map(|system| system.signature_algorithms.iter())
+        .map(|algorithm| algorithm.to_string())
+        .collect();
+
+    let certificates: Vec<String> = algorithms
+        .iter()
+        .map(|algorithm| certificate_algorithm(algorithm))
+        .collect();
+
+    algorithms.into_iter().chain(certificates).collect()
+}
+
+/// Finds the crypto system of a key type name as used in key file names,
+/// e.g. "ecdsa" in "ssh_host_ecdsa_key". Names with several systems, like
+/// ECDSA, are told apart by the key size and default to the first one. A
+/// size that matches none of them is an error.
+pub fn system_by_name(name: &str, bits: Option<u32>)
+    -> Option<&'static CryptoSystem> {
+    let systems: Vec<&'static CryptoSystem> = REGISTRY
+        .iter()
+        .cloned()
+        .filter(|system| system.name == name)
+        .collect();
+
+    match bits
+    {
+        Some(bits) if systems.len() > 1 => {
+            let bits = bits.to_string();
+            systems.into_iter().find(|system| system.id.ends_with(&*bits))
+        }
+        _ => systems.first().cloned(),
+    }
+}
+
+/// Reads a public key blob as it is sent on the wire, e.g. the server host
+/// key in a key exchange reply.
+pub fn read_public_key(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
+    use packet::ReadPacketExt;
+
+    let algorithm = r.read_utf8()?;
+    read_public_key_data(&algorithm, r)
+}
+
+/// Reads the part of a public key blob after the algorithm name
+fn read_public_key_data(algorithm: &str, r: &mut Read)
+    -> io::Result<Box<KeyPair>> {
+    match system_by_id(algorithm)
+    {
+        Some(system) => (system.read_public)(r),
+        None => {
+            Err(io::Error::new(InvalidData, "unsupported public key type"))
+        }
+    }
+}
+
+/// Parses a public key in the OpenSSH line format, e.g. a line of an
+/// authorized_keys file: the algorithm, the base64 encoded key blob and an
+/// optional comment.
+pub fn parse_public_key_line(line: &str) -> io::Result<Box<KeyPair>> {
+    let blob = decode_key_line(line)?;
+    read_public_key(&mut &blob[..])
+}
+
+/// Formats a public key in the OpenSSH line format, as in a *.pub file
+pub fn format_public_key_line(key: &KeyPair, comment: &str)
+    -> io::Result<String> {
+    use packet::ReadPacketExt;
+    use rustc_serialize::base64::{STANDARD, ToBase64};
+
+    let mut blob = Vec::new();
+    key.write_public(&mut blob)?;
+
+    let algorithm = (&blob[..]).read_utf8()?;
+    let line = format!("{} {}", algorithm, blob.to_base64(STANDARD));
+
+    if comment.is_empty() {
+        Ok(line)
+    }
+    else {
+        Ok(format!("{} {}", line, comment))
+    }
+}
+
+/// Parses a certificate in the format of an OpenSSH *-cert.pub file
+pub fn parse_certificate_line(line: &str) -> io::Result<Certificate> {
+    let blob = decode_key_line(line)?;
+    Certificate::read(&blob)
+}
+
+/// Decodes the base64 blob, the second field of a public key line
+fn decode_key_line(line: &str) -> io::Result<Vec<u8>> {
+    use rustc_serialize::base64::FromBase64;
+
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|data| data.from_base64().ok())
+        .ok_or(io::Error::new(InvalidData, "invalid public key line"))
 }
@@ -1,70 +1,342 @@
-use key::{Key, PublicKey, PrivateKey, KeyPair, CryptoSystem};
-use std::io::{Read, Write, Result};
+use std::io::{self, Read, Write};
+use std::io::Cursor;
+use std::io::ErrorKind::InvalidData;
+
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
+use crypto::util::fixed_time_eq;
+use num_bigint::BigUint;
+use packet::{ReadPacketExt, WritePacketExt};
+use public_key::{CryptoSystem, KeyPair};
+use public_key::key_file;
+use public_key::bignum::{digest, mod_inverse, random_bits, random_below,
+                         read_biguint, to_bytes, write_biguint};
 
 pub static RSA: CryptoSystem = CryptoSystem {
-    id: "rsa",
-    generate_key_pair: generate_key_pair,
+    id: "ssh-rsa",
+    name: "rsa",
+    signature_algorithms: &["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+    generate_key_pair: RsaKeyPair::generate,
+    import: key_file::import,
+    read_public: RsaKeyPair::read_public,
+    read_private: RsaKeyPair::read_private,
 };
 
-pub fn generate_key_pair(size: u32) -> KeyPair {
-    let public = Box::new(RsaPublicKey::new());
-    let private = Box::new(RsaPrivateKey::new());
-    (public, private)
+/// Smallest modulus we accept, like OpenSSH
+pub const RSA_MIN_BITS: u32 = 1024;
+/// Largest modulus ssh-keygen generates, like OpenSSH
+pub const RSA_MAX_BITS: u32 = 16384;
+const DEFAULT_BITS: u32 = 3072;
+const PUBLIC_EXPONENT: u32 = 65537;
+
+// DER encoded DigestInfo prefixes for EMSA-PKCS1-v1_5, see RFC 8017, 9.2
+static SHA1_PREFIX: &[u8] = &[
+    0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05,
+    0x00, 0x04, 0x14,
+];
+static SHA256_PREFIX: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03,
+    0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+];
+static SHA512_PREFIX: &[u8] = &[
+    0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03,
+    0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40,
+];
+
+struct RsaPrivateKey {
+    d: BigUint,
+    p: BigUint,
+    q: BigUint,
+    iqmp: BigUint,
+}
+
+struct RsaKeyPair {
+    n: BigUint,
+    e: BigUint,
+    private: Option<RsaPrivateKey>,
 }
 
-pub struct RsaPublicKey {}
+impl RsaKeyPair {
+    fn generate(bits: Option<u32>) -> Box<KeyPair> {
+        let bits = bits.unwrap_or(DEFAULT_BITS).max(RSA_MIN_BITS) as usize;
+        let e = BigUint::from(PUBLIC_EXPONENT);
+        let one = BigUint::from(1u32);
+
+        loop {
+            let p = generate_prime(bits - bits / 2, &e);
+            let q = generate_prime(bits / 2, &e);
+
+            if p == q {
+                continue;
+            }
+
+            let n = &p * &q;
+            if n.bits() as usize != bits {
+                continue;
+            }
 
-impl RsaPublicKey {
-    pub fn new() -> RsaPublicKey {
-        RsaPublicKey {}
+            let phi = (&p - &one) * (&q - &one);
+            let d = match mod_inverse(&e, &phi)
+            {
+                Some(d) => d,
+                None => continue,
+            };
+            let iqmp = match mod_inverse(&q, &p)
+            {
+                Some(iqmp) => iqmp,
+                None => continue,
+            };
+
+            return Box::new(RsaKeyPair {
+                n: n,
+                e: e,
+                private: Some(RsaPrivateKey {
+                    d: d,
+                    p: p,
+                    q: q,
+                    iqmp: iqmp,
+                }),
+            });
+        }
+    }
+
+    fn read_private(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
+        if r.read_utf8()? != "ssh-rsa" {
+            return Err(io::Error::new(InvalidData, "not a RSA key"));
+        }
+
+        let n = read_biguint(&mut r)?;
+        let e = read_biguint(&mut r)?;
+        let d = read_biguint(&mut r)?;
+        let iqmp = read_biguint(&mut r)?;
+        let p = read_biguint(&mut r)?;
+        let q = read_biguint(&mut r)?;
+
+        if n.bits() < RSA_MIN_BITS as u64 || &p * &q != n {
+            return Err(io::Error::new(InvalidData, "invalid RSA key"));
+        }
+
+        Ok(Box::new(RsaKeyPair {
+            n: n,
+            e: e,
+            private: Some(RsaPrivateKey {
+                d: d,
+                p: p,
+                q: q,
+                iqmp: iqmp,
+            }),
+        }))
+    }
+
+    fn read_public(mut r: &mut Read) -> io::Result<Box<KeyPair>> {
+        let e = read_biguint(&mut r)?;
+        let n = read_biguint(&mut r)?;
+
+        if n.bits() < RSA_MIN_BITS as u64 || e.bits() < 2 {
+            return Err(io::Error::new(InvalidData, "invalid RSA key"));
+        }
+
+        Ok(Box::new(RsaKeyPair {
+            n: n,
+            e: e,
+            private: None,
+        }))
+    }
+
+    /// Length of the modulus in bytes
+    fn len(&self) -> usize {
+        (self.n.bits() as usize + 7) / 8
+    }
+
+    /// Creates the EMSA-PKCS1-v1_5 encoded message for a signature algorithm
+    fn encode(&self, algorithm: &str, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let (prefix, hash) = match algorithm
+        {
+            "ssh-rsa" => (SHA1_PREFIX, digest(Sha1::new(), data)),
+            "rsa-sha2-256" => (SHA256_PREFIX, digest(Sha256::new(), data)),
+            "rsa-sha2-512" => (SHA512_PREFIX, digest(Sha512::new(), data)),
+            _ => return Err(()),
+        };
+
+        let len = self.len();
+        let padding_len = len
+            .checked_sub(prefix.len() + hash.len() + 3)
+            .ok_or(())?;
+
+        let mut encoded = Vec::with_capacity(len);
+        encoded.push(0x00);
+        encoded.push(0x01);
+        encoded.extend(::std::iter::repeat(0xff).take(padding_len));
+        encoded.push(0x00);
+        encoded.extend_from_slice(prefix);
+        encoded.extend_from_slice(&hash);
+        Ok(encoded)
+    }
+
+    /// Computes m^d mod n using the CRT with a blinded message, so that the
+    /// timing does not depend on the message
+    fn private_operation(&self, private: &RsaPrivateKey, m: &BigUint) -> BigUint {
+        let one = BigUint::from(1u32);
+
+        let (r, r_inv) = loop {
+            let r = random_below(&self.n);
+            if let Some(r_inv) = mod_inverse(&r, &self.n) {
+                break (r, r_inv);
+            }
+        };
+
+        let blinded = (m * r.modpow(&self.e, &self.n)) % &self.n;
+
+        let dp = &private.d % (&private.p - &one);
+        let dq = &private.d % (&private.q - &one);
+        let m1 = blinded.modpow(&dp, &private.p);
+        let m2 = blinded.modpow(&dq, &private.q);
+
+        // h = iqmp * (m1 - m2) mod p
+        let diff = (&m1 + &private.p - (&m2 % &private.p)) % &private.p;
+        let h = (&private.iqmp * diff) % &private.p;
+        let s = m2 + h * &private.q;
+
+        (s * r_inv) % &self.n
     }
 }
 
-impl Key for RsaPublicKey {
+impl KeyPair for RsaKeyPair {
     fn system(&self) -> &'static CryptoSystem {
         &RSA
     }
 
-    fn read(&self, r: &mut Read) -> Result<Box<Self>> {
-        Err(::std::io::Error::new(::std::io::ErrorKind::Other, ""))
+    fn has_private(&self) -> bool {
+        self.private.is_some()
     }
 
-    fn write(&self, w: &mut Write) -> Result<()> {
-        Ok(())
+    fn bits(&self) -> u32 {
+        self.n.bits() as u32
     }
-}
 
-impl PublicKey for RsaPublicKey {
-    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
-        Vec::new()
+    fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, ()> {
+        let mut reader = Cursor::new(signature);
+        let algorithm = reader.read_utf8().or(Err(()))?;
+        let sig = reader.read_string().or(Err(()))?;
+
+        let expected = self.encode(&algorithm, data)?;
+
+        if sig.len() > self.len() {
+            return Err(());
+        }
+
+        let s = BigUint::from_bytes_be(&sig);
+        if s >= self.n {
+            return Ok(false);
+        }
+
+        let m = to_bytes(&s.modpow(&self.e, &self.n), self.len());
+        Ok(fixed_time_eq(&m, &expected))
     }
-}
 
-pub struct RsaPrivateKey {
-}
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.sign_with(data, "rsa-sha2-512")
+    }
+
+    fn sign_with(&self, data: &[u8], algorithm: &str) -> Result<Vec<u8>, ()> {
+        let private = self.private.as_ref().ok_or(())?;
+        let encoded = self.encode(algorithm, data)?;
+
+        let m = BigUint::from_bytes_be(&encoded);
+        let s = self.private_operation(private, &m);
+
+        // Guard against faults in the CRT computation leaking the key
+        if s.modpow(&self.e, &self.n) != m {
+            return Err(());
+        }
+
+        let mut result = Vec::new();
+        result.write_string(algorithm).or(Err(()))?;
+        result.write_bytes(&to_bytes(&s, self.len())).or(Err(()))?;
+        Ok(result)
+    }
+
+    fn write_public(&self, w: &mut Write) -> io::Result<()> {
+        w.write_string("ssh-rsa")?;
+        write_biguint(w, &self.e)?;
+        write_biguint(w, &self.n)
+    }
+
+    fn write_private(&self, w: &mut Write) -> io::Result<()> {
+        let private = self.private.as_ref().ok_or(
+            io::Error::new(InvalidData, "not a private key"),
+        )?;
 
-impl RsaPrivateKey {
-    pub fn new() -> RsaPrivateKey {
-        RsaPrivateKey { }
+        w.write_string("ssh-rsa")?;
+        write_biguint(w, &self.n)?;
+        write_biguint(w, &self.e)?;
+        write_biguint(w, &private.d)?;
+        write_biguint(w, &private.iqmp)?;
+        write_biguint(w, &private.p)?;
+        write_biguint(w, &private.q)
     }
 }
 
-impl PrivateKey for RsaPrivateKey {
-    fn sign(&self, data: &[u8]) -> Vec<u8> {
-        Vec::new()
+/// Generates a prime of exactly `bits` bits with the two most significant
+/// bits set, so that the product of two such primes has twice the bits
+fn generate_prime(bits: usize, e: &BigUint) -> BigUint {
+    let one = BigUint::from(1u32);
+    let top = (BigUint::from(3u32) << (bits - 2)) | &one;
+
+    loop {
+        let candidate = random_bits(bits) | &top;
+
+        if is_probable_prime(&candidate) &&
+            mod_inverse(e, &(&candidate - &one)).is_some()
+        {
+            return candidate;
+        }
     }
 }
 
-impl Key for RsaPrivateKey {
-    fn system(&self) -> &'static CryptoSystem {
-        &RSA
+static SMALL_PRIMES: &[u32] = &[
+    3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71,
+    73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151,
+    157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229, 233,
+    239, 241, 251,
+];
+
+/// Miller-Rabin primality test with random bases
+fn is_probable_prime(n: &BigUint) -> bool {
+    let zero = BigUint::from(0u32);
+    let one = BigUint::from(1u32);
+
+    for p in SMALL_PRIMES {
+        if n % BigUint::from(*p) == zero {
+            return n == &BigUint::from(*p);
+        }
     }
 
-    fn read(&self, r: &mut Read) -> Result<Box<Self>> {
-        Err(::std::io::Error::new(::std::io::ErrorKind::Other, ""))
+    let n_minus_one = n - &one;
+    let mut d = n_minus_one.clone();
+    let mut s = 0;
+    while &d % 2u32 == zero {
+        d >>= 1;
+        s += 1;
     }
 
-    fn write(&self, w: &mut Write) -> Result<()> {
-        Ok(())
+    'witness: for _ in 0..40 {
+        let a = random_below(&n_minus_one);
+        let mut x = a.modpow(&d, n);
+
+        if x == one || x == n_minus_one {
+            continue;
+        }
+
+        for _ in 1..s {
+            x = (&x * &x) % n;
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+
+        return false;
     }
+
+    true
 }
@@ -0,0 +1,175 @@
+use std::io::{self, Cursor, Read, Write};
+use std::io::ErrorKind::InvalidData;
+
+use crypto::sha2::Sha256;
+use packet::{ReadPacketExt, WritePacketExt};
+use public_key::{CryptoSystem, ECDSA_NISTP256, ED25519, KeyPair};
+use public_key::bignum::digest;
+
+// Security keys are only verified. Creating keys or signatures needs the
+// hardware token, so these systems are not exported from `public_key`.
+pub static SK_ED25519: CryptoSystem = CryptoSystem {
+    id: "sk-ssh-ed25519@openssh.com",
+    name: "ed25519-sk",
+    signature_algorithms: &["sk-ssh-ed25519@openssh.com"],
+    generate_key_pair: generate,
+    import: import,
+    read_public: read_sk_ed25519,
+    read_private: import,
+};
+
+pub static SK_ECDSA_NISTP256: CryptoSystem = CryptoSystem {
+    id: "sk-ecdsa-sha2-nistp256@openssh.com",
+    name: "ecdsa-sk",
+    signature_algorithms: &["sk-ecdsa-sha2-nistp256@openssh.com"],
+    generate_key_pair: generate,
+    import: import,
+    read_public: read_sk_ecdsa_nistp256,
+    read_private: import,
+};
+
+/// The user touched the security key
+pub const SK_USER_PRESENCE: u8 = 0x01;
+/// The user was verified by the security key, e.g. with a PIN
+pub const SK_USER_VERIFICATION: u8 = 0x04;
+
+/// Authenticator data of a security key signature
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SkSignatureInfo {
+    pub flags: u8,
+    pub counter: u32,
+}
+
+/// Reads the flags and counter of a security key signature, see
+/// PROTOCOL.u2f in OpenSSH. Returns None for other signatures.
+pub fn sk_signature_info(signature: &[u8]) -> Option<SkSignatureInfo> {
+    let mut reader = Cursor::new(signature);
+    let algorithm = reader.read_utf8().ok()?;
+
+    if !algorithm.starts_with("sk-") {
+        return None;
+    }
+
+    let _ = reader.read_string().ok()?;
+    let flags = reader.read_bytes(1).ok()?;
+    let counter = reader.read_uint32().ok()?;
+
+    if flags.len() != 1 {
+        return None;
+    }
+
+    Some(SkSignatureInfo {
+        flags: flags[0],
+        counter: counter,
+    })
+}
+
+fn generate(_: Option<u32>) -> Box<KeyPair> {
+    panic!("security keys can only be generated by the hardware token");
+}
+
+fn import(_: &mut Read) -> io::Result<Box<KeyPair>> {
+    Err(io::Error::new(InvalidData, "security keys cannot be imported"))
+}
+
+fn read_sk_ed25519(r: &mut Read) -> io::Result<Box<KeyPair>> {
+    SkKeyPair::read_public(&SK_ED25519, &ED25519, "ssh-ed25519", r)
+}
+
+fn read_sk_ecdsa_nistp256(r: &mut Read) -> io::Result<Box<KeyPair>> {
+    SkKeyPair::read_public(
+        &SK_ECDSA_NISTP256,
+        &ECDSA_NISTP256,
+        "ecdsa-sha2-nistp256",
+        r,
+    )
+}
+
+/// A FIDO/U2F security key. The authenticator signs with a regular ed25519
+/// or ECDSA key, but over a hash of the application and the message.
+struct SkKeyPair {
+    system: &'static CryptoSystem,
+    key: Box<KeyPair>,
+    key_algorithm: &'static str,
+    application: String,
+}
+
+impl SkKeyPair {
+    fn read_public(
+        system: &'static CryptoSystem,
+        key_system: &'static CryptoSystem,
+        key_algorithm: &'static str,
+        mut r: &mut Read,
+    ) -> io::Result<Box<KeyPair>> {
+        let key = (key_system.read_public)(r)?;
+        let application = r.read_utf8()?;
+
+        Ok(Box::new(SkKeyPair {
+            system: system,
+            key: key,
+            key_algorithm: key_algorithm,
+            application: application,
+        }))
+    }
+
+    fn algorithm(&self) -> &'static str {
+        self.system.signature_algorithms[0]
+    }
+}
+
+impl KeyPair for SkKeyPair {
+    fn system(&self) -> &'static CryptoSystem {
+        self.system
+    }
+
+    fn has_private(&self) -> bool {
+        false
+    }
+
+    fn bits(&self) -> u32 {
+        self.key.bits()
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, ()> {
+        let mut reader = Cursor::new(signature);
+        if reader.read_utf8().or(Err(()))? != self.algorithm() {
+            return Err(());
+        }
+
+        let sig = reader.read_string().or(Err(()))?;
+        let info = sk_signature_info(signature).ok_or(())?;
+
+        // The authenticator signs the application and message hashes
+        // together with its flags and counter
+        let mut signed = digest(Sha256::new(), self.application.as_bytes());
+        signed.push(info.flags);
+        signed.write_uint32(info.counter).or(Err(()))?;
+        signed.extend(digest(Sha256::new(), data));
+
+        let mut key_signature = Vec::new();
+        key_signature.write_string(self.key_algorithm).or(Err(()))?;
+        key_signature.write_bytes(&sig).or(Err(()))?;
+
+        self.key.verify(&signed, &key_signature)
+    }
+
+    fn sign(&self, _: &[u8]) -> Result<Vec<u8>, ()> {
+        Err(())
+    }
+
+    fn write_public(&self, w: &mut Write) -> io::Result<()> {
+        // Replace the algorithm name of the wrapped key
+        let mut key = Vec::new();
+        self.key.write_public(&mut key)?;
+        let mut reader = Cursor::new(key);
+        let _ = reader.read_string()?;
+
+        w.write_string(self.algorithm())?;
+        io::copy(&mut reader, w)?;
+        w.write_string(&self.application)
+    }
+
+    fn write_private(&self, _: &mut Write) -> io::Result<()> {
+        Err(io::Error::new(InvalidData, "security keys cannot be exported"))
+    }
+}
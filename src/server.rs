@@ -1,15 +1,162 @@
+use std::collections::HashMap;
 use std::io;
-use std::net::TcpListener;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 
-use connection::{Connection, ConnectionType};
-use public_key::KeyPair;
+use rand::{self, Rng};
+
+use algorithm::{self, EncryptionAlgorithm, KeyExchangeAlgorithm, MacAlgorithm,
+                PublicKeyAlgorithm};
+use config::sshd::{Match, MaxStartups, Settings};
+use connection::{Connection, ConnectionType, RekeyLimit};
+use public_key::{Certificate, KeyPair};
+use sys;
+
+/// An address that the server accepts connections on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    /// Only accept IPv6 connections on an IPv6 address. Otherwise the socket
+    /// is dual-stack, so `[::]` also accepts IPv4 connections.
+    pub v6_only: bool,
+}
+
+impl Listener {
+    pub fn new(address: SocketAddr) -> Listener {
+        Listener {
+            address: address,
+            v6_only: false,
+        }
+    }
+}
 
 pub struct ServerConfig {
-    pub host: String,
-    pub port: u16,
-    pub key: Box<KeyPair>,
+    /// Addresses to accept connections on, like with the default of
+    /// OpenSSH, on the IPv4 and IPv6 wildcard addresses
+    pub listeners: Vec<Listener>,
+    /// Host keys, at most one of each key type
+    pub host_keys: Vec<Box<KeyPair>>,
+    /// Certificates of host keys that are presented to clients
+    pub host_certificates: Vec<Certificate>,
+    pub rekey_limit: RekeyLimit,
+    /// Time a client has to authenticate
+    pub login_grace_time: Option<Duration>,
+    /// Limits of unauthenticated connections, beyond which new connections
+    /// are dropped
+    pub max_startups: MaxStartups,
+    pub per_source_max_startups: Option<usize>,
+    pub kex_algorithms: Vec<KeyExchangeAlgorithm>,
+    /// Ciphers that are offered, which may include CBC mode for old clients
+    pub ciphers: Vec<EncryptionAlgorithm>,
+    pub macs: Vec<MacAlgorithm>,
+    /// Public keys that are accepted for publickey authentication
+    pub authorized_keys: Vec<Box<KeyPair>>,
+    /// Certificate authorities whose user certificates are accepted
+    pub trusted_user_ca_keys: Vec<Box<KeyPair>>,
+    /// Require security keys to confirm that the user touched them
+    pub sk_touch_required: bool,
+    /// Require security keys to verify the user, e.g. with a PIN
+    pub sk_verify_required: bool,
+    /// Subsystem names and the commands that implement them
+    pub subsystems: Vec<(String, String)>,
+    /// Options that Match blocks can override per connection
+    pub settings: Settings,
+    pub matches: Vec<Match>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listeners: vec![
+                Listener::new("0.0.0.0:22".parse().unwrap()),
+                Listener {
+                    address: "[::]:22".parse().unwrap(),
+                    v6_only: true,
+                },
+            ],
+            host_keys: Vec::new(),
+            host_certificates: Vec::new(),
+            rekey_limit: RekeyLimit::default(),
+            login_grace_time: Some(Duration::from_secs(120)),
+            max_startups: MaxStartups::default(),
+            per_source_max_startups: None,
+            kex_algorithms: algorithm::KEY_EXCHANGE.to_vec(),
+            ciphers: algorithm::ENCRYPTION.to_vec(),
+            macs: algorithm::MAC.to_vec(),
+            authorized_keys: Vec::new(),
+            trusted_user_ca_keys: Vec::new(),
+            sk_touch_required: true,
+            sk_verify_required: false,
+            subsystems: Vec::new(),
+            settings: Settings::default(),
+            matches: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The host key that signs for a negotiated host key algorithm. For
+    /// certificate algorithms, this is the certified key.
+    pub fn host_key(&self, algorithm: PublicKeyAlgorithm) -> Option<&KeyPair> {
+        let id = algorithm.system().id;
+        self.host_keys
+            .iter()
+            .find(|key| key.system().id == id)
+            .map(|key| &**key)
+    }
+
+    /// The host certificate for a negotiated certificate algorithm
+    pub fn host_certificate(&self, algorithm: PublicKeyAlgorithm)
+        -> Option<&Certificate> {
+        if algorithm.certified().is_none() {
+            return None;
+        }
+
+        let id = algorithm.system().id;
+        self.host_certificates.iter().find(
+            |cert| cert.key.system().id == id,
+        )
+    }
+}
+
+/// Numbers of unauthenticated connections by source address
+type Startups = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Counts a connection as unauthenticated until it is dropped
+pub struct Startup {
+    startups: Startups,
+    address: IpAddr,
+}
+
+impl Startup {
+    fn new(startups: &Startups, address: IpAddr) -> Startup {
+        *startups.lock().unwrap().entry(address).or_insert(0) += 1;
+        Startup {
+            startups: startups.clone(),
+            address: address,
+        }
+    }
+}
+
+impl Drop for Startup {
+    fn drop(&mut self) {
+        let mut startups = self.startups.lock().unwrap();
+        let remove = match startups.get_mut(&self.address)
+        {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            startups.remove(&self.address);
+        }
+    }
 }
 
 pub struct Server {
@@ -21,19 +168,68 @@ impl Server {
         Server { config: Arc::new(config) }
     }
 
+    /// Accepts connections on all listeners. Addresses that can't be bound
+    /// are skipped, unless none of them can be bound.
     pub fn run(&self) -> io::Result<()> {
-        let listener =
-            TcpListener::bind((&*self.config.host, self.config.port))?;
+        let mut sockets = Vec::new();
+        for listener in self.config.listeners.iter() {
+            match sys::bind(&listener.address, listener.v6_only)
+            {
+                Ok(socket) => {
+                    info!("Listening on {}", listener.address);
+                    sockets.push(socket);
+                }
+                Err(err) => {
+                    error!("Failed to bind {}: {}", listener.address, err)
+                }
+            }
+        }
+
+        if sockets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "cannot bind any address",
+            ));
+        }
+
+        // The sockets are accepted on in the background, so that the
+        // connections are all handled by the loop below
+        let (tx, rx) = mpsc::channel();
+        for socket in sockets {
+            let tx = tx.clone();
+            thread::spawn(move || while tx.send(socket.accept()).is_ok() {});
+        }
+
+        let startups = Startups::default();
+
+        for result in rx.iter() {
+            let (mut stream, addr) = match result
+            {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
 
-        loop {
-            let (mut stream, addr) = listener.accept()?;
+            let addr = unmap_address(addr);
+            let local_addr = stream.local_addr().ok().map(unmap_address);
             let config = self.config.clone();
 
             debug!("Incoming connection from {}", addr);
 
+            // Dropping the stream closes the connection
+            if self.drops(&startups, addr.ip()) {
+                continue;
+            }
+            let startup = Startup::new(&startups, addr.ip());
+
             thread::spawn(move || {
                 let mut connection =
                     Connection::new(ConnectionType::Server(config));
+                connection.peer_addr = Some(addr.ip());
+                connection.local_addr = local_addr;
+                connection.startup = Some(startup);
 
                 let result = connection.run(&mut stream);
 
@@ -45,4 +241,56 @@ impl Server {
 
         Ok(())
     }
+
+    /// Whether to drop a new connection because of the unauthenticated
+    /// connections, like MaxStartups and PerSourceMaxStartups in OpenSSH
+    fn drops(&self, startups: &Startups, address: IpAddr) -> bool {
+        let startups = startups.lock().unwrap();
+
+        let from_address = startups.get(&address).cloned().unwrap_or(0);
+        if self.config.per_source_max_startups.map_or(
+            false,
+            |max| from_address >= max,
+        )
+        {
+            info!("Dropping connection from {}: PerSourceMaxStartups", address);
+            return true;
+        }
+
+        let total = startups.values().sum();
+        let rate = self.config.max_startups.drop_rate(total);
+        if rate > 0 && rand::thread_rng().gen_range(0, 100) < rate {
+            info!(
+                "Dropping connection from {} with {} unauthenticated \
+                 connections: MaxStartups",
+                address,
+                total
+            );
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address, as accepted by dual-stack sockets,
+/// into the IPv4 address, so that it matches IPv4 address patterns
+fn unmap_address(address: SocketAddr) -> SocketAddr {
+    let ip = match address
+    {
+        SocketAddr::V6(ref v6) => v6.ip().segments(),
+        SocketAddr::V4(_) => return address,
+    };
+
+    if ip[..5] != [0; 5] || ip[5] != 0xffff {
+        return address;
+    }
+
+    let ip = Ipv4Addr::new(
+        (ip[6] >> 8) as u8,
+        ip[6] as u8,
+        (ip[7] >> 8) as u8,
+        ip[7] as u8,
+    );
+    SocketAddr::new(IpAddr::V4(ip), address.port())
 }
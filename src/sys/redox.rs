@@ -1,4 +1,5 @@
-use std::io::Result;
+use std::io::{Error, Result};
+use std::net::{SocketAddr, TcpListener};
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
@@ -13,6 +14,49 @@ pub fn fork() -> usize {
 
 pub fn set_winsize(fd: RawFd, row: u16, col: u16, xpixel: u16, ypixel: u16) {}
 
+/// Turns the echo of a terminal on or off, e.g. to read a passphrase
+pub fn set_echo(fd: RawFd, echo: bool) -> Result<()> {
+    use redox_termios::{ECHO, Termios};
+    use syscall;
+
+    // The attributes are read and written through a termios handle of the
+    // terminal
+    let handle = cvt(syscall::dup(fd as usize, b"termios"))?;
+
+    let mut termios = Termios::default();
+    let result = cvt(syscall::read(handle, &mut termios)).and_then(|_| {
+        if echo {
+            termios.c_lflag |= ECHO;
+        }
+        else {
+            termios.c_lflag &= !ECHO;
+        }
+        cvt(syscall::write(handle, &termios))
+    });
+
+    let _ = syscall::close(handle);
+    result.map(|_| ())
+}
+
+/// Converts the result of a system call into an I/O result
+fn cvt(result: ::syscall::Result<usize>) -> Result<usize> {
+    result.map_err(|err| Error::from_raw_os_error(err.errno))
+}
+
+/// Binds a listening socket. Redox has no socket option to choose between
+/// IPv6 only and dual-stack sockets, so an IPv6 socket that should also
+/// accept IPv4 connections is only bound with a warning.
+pub fn bind(address: &SocketAddr, v6_only: bool) -> Result<TcpListener> {
+    if address.is_ipv6() && !v6_only {
+        warn!(
+            "Dual-stack sockets are not supported, {} may not accept IPv4 \
+             connections",
+            address
+        );
+    }
+    TcpListener::bind(address)
+}
+
 pub fn getpty() -> (RawFd, PathBuf) {
     use syscall;
 
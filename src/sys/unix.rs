@@ -1,4 +1,5 @@
 use std::io::Result;
+use std::net::{SocketAddr, TcpListener};
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
@@ -69,3 +70,87 @@ pub fn getpty() -> (RawFd, PathBuf) {
     };
     (master_fd, tty_path)
 }
+
+/// Turns the echo of a terminal on or off, e.g. to read a passphrase
+pub fn set_echo(fd: RawFd, echo: bool) -> Result<()> {
+    use std::io::Error;
+
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        if echo {
+            termios.c_lflag |= libc::ECHO;
+        }
+        else {
+            termios.c_lflag &= !libc::ECHO;
+        }
+
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Binds a listening socket. An IPv6 socket also accepts IPv4 connections
+/// unless `v6_only` is set.
+pub fn bind(address: &SocketAddr, v6_only: bool) -> Result<TcpListener> {
+    use std::io::Error;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let v6_address = match *address
+    {
+        SocketAddr::V4(_) => return TcpListener::bind(address),
+        SocketAddr::V6(ref address) => address,
+    };
+
+    unsafe {
+        let fd = libc::socket(libc::AF_INET6, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // Closes the socket on errors
+        let listener = TcpListener::from_raw_fd(fd);
+
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6_only as i32)?;
+
+        let mut addr: libc::sockaddr_in6 = mem::zeroed();
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = v6_address.port().to_be();
+        addr.sin6_addr.s6_addr = v6_address.ip().octets();
+        addr.sin6_scope_id = v6_address.scope_id();
+
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        ) < 0 || libc::listen(fd, 128) < 0
+        {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(listener)
+    }
+}
+
+unsafe fn set_option(fd: RawFd, level: i32, name: i32, value: i32)
+    -> Result<()> {
+    if libc::setsockopt(
+        fd,
+        level,
+        name,
+        &value as *const i32 as *const libc::c_void,
+        std::mem::size_of::<i32>() as libc::socklen_t,
+    ) < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}